@@ -0,0 +1,502 @@
+// Audio helpers: lightweight header parsing for stored and returned audio
+
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::AudioInfo;
+
+// OpenAI returns raw pcm as 24kHz, 16-bit, mono (Azure pcm is requested to match)
+pub const OPENAI_PCM_SAMPLE_RATE: u32 = 24_000;
+pub const OPENAI_PCM_CHANNELS: u16 = 1;
+pub const OPENAI_PCM_BITS_PER_SAMPLE: u16 = 16;
+
+// Output rates TTS pcm can be resampled to; all at or below the provider's native rate
+pub const SUPPORTED_PCM_SAMPLE_RATES: [u32; 4] = [8_000, 16_000, 22_050, OPENAI_PCM_SAMPLE_RATE];
+
+// Bit depths pcm can be written to wav at. Providers only produce 16-bit samples, so 24-bit
+// output is the same audio widened for pipelines that expect that container.
+pub const SUPPORTED_WAV_BIT_DEPTHS: [u16; 2] = [OPENAI_PCM_BITS_PER_SAMPLE, 24];
+
+// Wrap 16-bit mono pcm at `sample_rate` in a canonical 44-byte WAV header, widening the
+// samples first when `bits_per_sample` is 24
+pub fn wrap_pcm_as_wav(pcm: &[u8], sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
+    let widened;
+    let pcm = if bits_per_sample == 24 {
+        widened = widen_pcm16_to_24(pcm);
+        &widened
+    } else {
+        pcm
+    };
+    let block_align = OPENAI_PCM_CHANNELS * (bits_per_sample / 8);
+    let byte_rate = sample_rate * block_align as u32;
+    let data_len = pcm.len() as u32;
+
+    let mut wav = Vec::with_capacity(44 + pcm.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&OPENAI_PCM_CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&byte_rate.to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&bits_per_sample.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    wav.extend_from_slice(pcm);
+    wav
+}
+
+// 16-bit little-endian samples as 24-bit, with the added low byte zeroed
+fn widen_pcm16_to_24(pcm: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(pcm.len() / 2 * 3);
+    for sample in pcm.chunks_exact(2) {
+        out.extend_from_slice(&[0, sample[0], sample[1]]);
+    }
+    out
+}
+
+// Resample 16-bit mono little-endian pcm by linear interpolation. Good enough for speech
+// headed to telephony rates; no anti-aliasing filter is applied when downsampling.
+pub fn resample_pcm16(pcm: &[u8], from_rate: u32, to_rate: u32) -> Vec<u8> {
+    let samples: Vec<i16> = pcm
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    if from_rate == to_rate || samples.is_empty() {
+        return pcm.to_vec();
+    }
+
+    let out_len = (samples.len() as u64 * to_rate as u64 / from_rate as u64) as usize;
+    let step = from_rate as f64 / to_rate as f64;
+    let mut out = Vec::with_capacity(out_len * 2);
+    for i in 0..out_len {
+        let pos = i as f64 * step;
+        let index = pos as usize;
+        let frac = pos - index as f64;
+        let a = samples[index] as f64;
+        let b = samples.get(index + 1).copied().unwrap_or(samples[index]) as f64;
+        let sample = (a + (b - a) * frac).round() as i16;
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    out
+}
+
+// Duration and details of headerless 16-bit mono pcm at a known rate
+pub fn pcm_duration_ms(bytes: &[u8], sample_rate: u32) -> u64 {
+    let bytes_per_sec =
+        sample_rate as u64 * OPENAI_PCM_CHANNELS as u64 * (OPENAI_PCM_BITS_PER_SAMPLE as u64 / 8);
+    bytes.len() as u64 * 1000 / bytes_per_sec
+}
+
+pub fn pcm_audio_info(sample_rate: u32) -> AudioInfo {
+    AudioInfo {
+        sample_rate: Some(sample_rate),
+        channels: Some(OPENAI_PCM_CHANNELS),
+        bitrate_kbps: Some(
+            sample_rate * OPENAI_PCM_CHANNELS as u32 * OPENAI_PCM_BITS_PER_SAMPLE as u32 / 1000,
+        ),
+        codec: "pcm".to_string(),
+    }
+}
+
+// Best-effort playback duration in milliseconds; None when the format can't be parsed cheaply
+pub fn duration_ms(bytes: &[u8], format: &str) -> Option<u64> {
+    match format {
+        "wav" => wav_duration_ms(bytes),
+        "pcm" => Some(pcm_duration_ms(bytes, OPENAI_PCM_SAMPLE_RATE)),
+        "mp3" => mp3_duration_ms(bytes),
+        "flac" => {
+            let info = parse_flac_streaminfo(bytes)?;
+            if info.sample_rate == 0 {
+                return None;
+            }
+            Some(info.total_samples * 1000 / info.sample_rate as u64)
+        }
+        _ => None,
+    }
+}
+
+// Sample rate, channels, bitrate and codec read from the container/frame headers
+pub fn audio_info(bytes: &[u8], format: &str) -> Option<AudioInfo> {
+    match format {
+        "wav" => {
+            let header = parse_wav_header(bytes)?;
+            Some(AudioInfo {
+                sample_rate: Some(header.sample_rate),
+                channels: Some(header.channels),
+                bitrate_kbps: Some(header.byte_rate * 8 / 1000),
+                codec: "pcm".to_string(),
+            })
+        }
+        "pcm" => Some(pcm_audio_info(OPENAI_PCM_SAMPLE_RATE)),
+        "mp3" => {
+            let header = parse_mp3_header(bytes)?;
+            Some(AudioInfo {
+                sample_rate: Some(header.sample_rate),
+                channels: Some(header.channels),
+                bitrate_kbps: Some(header.bitrate_kbps),
+                codec: "mp3".to_string(),
+            })
+        }
+        "flac" => {
+            let info = parse_flac_streaminfo(bytes)?;
+            Some(AudioInfo {
+                sample_rate: Some(info.sample_rate),
+                channels: Some(info.channels),
+                bitrate_kbps: None,
+                codec: "flac".to_string(),
+            })
+        }
+        "ogg" | "opus" => parse_ogg_info(bytes),
+        _ => None,
+    }
+}
+
+// Detect the container from magic bytes; None when unrecognized
+pub fn sniff_format(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        // EBML (Matroska/WebM); browsers record audio as webm
+        return Some("webm");
+    }
+    if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WAVE" {
+        return Some("wav");
+    }
+    if bytes.starts_with(b"OggS") {
+        return Some("ogg");
+    }
+    if bytes.starts_with(b"fLaC") {
+        return Some("flac");
+    }
+    // ISO base media (MP4/M4A): a size field, then an "ftyp" box
+    if bytes.len() >= 8 && &bytes[4..8] == b"ftyp" {
+        return Some("m4a");
+    }
+    if bytes.starts_with(b"ID3") || parse_mp3_header(&bytes[..bytes.len().min(4)]).is_some() {
+        return Some("mp3");
+    }
+    None
+}
+
+// Name recorded in pair metadata for zlib-compressed audio files
+pub const ZLIB_COMPRESSION: &str = "zlib";
+
+// Only uncompressed formats shrink meaningfully; encoded audio is left as-is
+pub fn is_compressible(format: &str) -> bool {
+    matches!(format, "wav" | "pcm")
+}
+
+pub fn compress(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(bytes)
+        .map_err(|e| format!("Failed to compress audio: {}", e))?;
+    encoder
+        .finish()
+        .map_err(|e| format!("Failed to compress audio: {}", e))
+}
+
+pub fn decompress(bytes: &[u8], compression: &str) -> Result<Vec<u8>, String> {
+    match compression {
+        ZLIB_COMPRESSION => {
+            let mut out = Vec::new();
+            ZlibDecoder::new(bytes)
+                .read_to_end(&mut out)
+                .map_err(|e| format!("Failed to decompress audio: {}", e))?;
+            Ok(out)
+        }
+        other => Err(format!("Unknown audio compression: {}", other)),
+    }
+}
+
+fn wav_duration_ms(bytes: &[u8]) -> Option<u64> {
+    let header = parse_wav_header(bytes)?;
+    if header.byte_rate == 0 {
+        return None;
+    }
+    Some(header.data_len as u64 * 1000 / header.byte_rate as u64)
+}
+
+pub struct WavHeader {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub byte_rate: u32,
+    pub bits_per_sample: u16,
+    pub data_start: usize, // Offset of the sample data, just past the data chunk header
+    pub data_len: usize,
+}
+
+// Walk the RIFF chunks for `fmt ` and `data`
+pub fn parse_wav_header(bytes: &[u8]) -> Option<WavHeader> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return None;
+    }
+
+    let mut fmt: Option<(u16, u32, u32, u16)> = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let size = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().ok()?) as usize;
+        let body = pos + 8;
+
+        if id == b"fmt " && body + 16 <= bytes.len() {
+            let channels = u16::from_le_bytes(bytes[body + 2..body + 4].try_into().ok()?);
+            let sample_rate = u32::from_le_bytes(bytes[body + 4..body + 8].try_into().ok()?);
+            let byte_rate = u32::from_le_bytes(bytes[body + 8..body + 12].try_into().ok()?);
+            let bits_per_sample = u16::from_le_bytes(bytes[body + 14..body + 16].try_into().ok()?);
+            fmt = Some((channels, sample_rate, byte_rate, bits_per_sample));
+        } else if id == b"data" {
+            let (channels, sample_rate, byte_rate, bits_per_sample) = fmt?;
+            // Streamed wavs may carry a placeholder size; clamp to what we actually have
+            return Some(WavHeader {
+                sample_rate,
+                channels,
+                byte_rate,
+                bits_per_sample,
+                data_start: body,
+                data_len: size.min(bytes.len() - body),
+            });
+        }
+
+        // Chunks are padded to an even length
+        pos = body + size + (size & 1);
+    }
+
+    None
+}
+
+// Silence trimming works on 10ms frames; a frame is quiet when its RMS is below the
+// threshold (about -36 dBFS), and this much quiet audio is kept at each end
+const SILENCE_FRAME_MS: usize = 10;
+const SILENCE_RMS_THRESHOLD: f64 = 500.0;
+const SILENCE_PADDING_FRAMES: usize = 10;
+
+// Cut leading and trailing silence from 16-bit pcm wav. None when the audio isn't a wav
+// this can read; audio that is silent throughout is returned unchanged.
+pub fn trim_wav_silence(bytes: &[u8]) -> Option<Vec<u8>> {
+    let header = parse_wav_header(bytes)?;
+    if header.bits_per_sample != 16 || header.channels == 0 {
+        return None;
+    }
+    let data = &bytes[header.data_start..header.data_start + header.data_len];
+    let frame_bytes =
+        header.sample_rate as usize * SILENCE_FRAME_MS / 1000 * header.channels as usize * 2;
+    if frame_bytes == 0 {
+        return None;
+    }
+
+    let loud = |frame: &[u8]| {
+        let samples = frame.chunks_exact(2);
+        let count = samples.len().max(1) as f64;
+        let sum_squares: f64 = samples
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f64)
+            .map(|s| s * s)
+            .sum();
+        (sum_squares / count).sqrt() >= SILENCE_RMS_THRESHOLD
+    };
+    let frames: Vec<&[u8]> = data.chunks(frame_bytes).collect();
+    let Some(first) = frames.iter().position(|f| loud(f)) else {
+        return Some(bytes.to_vec());
+    };
+    let last = frames.iter().rposition(|f| loud(f)).unwrap_or(first);
+
+    let start = first.saturating_sub(SILENCE_PADDING_FRAMES) * frame_bytes;
+    let end = ((last + 1 + SILENCE_PADDING_FRAMES) * frame_bytes).min(data.len());
+    let trimmed = &data[start..end];
+
+    // Keep the original header chunks, fixing up the RIFF and data sizes; anything after
+    // the data chunk is dropped
+    let mut wav = Vec::with_capacity(header.data_start + trimmed.len());
+    wav.extend_from_slice(&bytes[..header.data_start]);
+    wav.extend_from_slice(trimmed);
+    let riff_len = (wav.len() - 8) as u32;
+    wav[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    wav[header.data_start - 4..header.data_start]
+        .copy_from_slice(&(trimmed.len() as u32).to_le_bytes());
+    Some(wav)
+}
+
+pub struct Mp3FrameHeader {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bitrate_kbps: u32,
+}
+
+// Skip an ID3v2 tag if present and parse the first MPEG audio frame header
+pub fn parse_mp3_header(bytes: &[u8]) -> Option<Mp3FrameHeader> {
+    let mut pos = 0;
+    if bytes.len() >= 10 && &bytes[0..3] == b"ID3" {
+        let size = bytes[6..10]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7f));
+        pos = 10 + size;
+    }
+
+    while pos + 4 <= bytes.len() {
+        if bytes[pos] == 0xff && bytes[pos + 1] & 0xe0 == 0xe0 {
+            if let Some(header) = decode_mp3_frame_header(&bytes[pos..pos + 4]) {
+                return Some(header);
+            }
+        }
+        pos += 1;
+    }
+
+    None
+}
+
+fn decode_mp3_frame_header(h: &[u8]) -> Option<Mp3FrameHeader> {
+    let version = (h[1] >> 3) & 0x03; // 3 = MPEG1, 2 = MPEG2, 0 = MPEG2.5
+    let layer = (h[1] >> 1) & 0x03; // 1 = Layer III
+    let bitrate_index = (h[2] >> 4) as usize;
+    let sample_rate_index = ((h[2] >> 2) & 0x03) as usize;
+    let channel_mode = h[3] >> 6; // 3 = mono
+
+    if version == 1
+        || layer != 1
+        || bitrate_index == 0
+        || bitrate_index == 15
+        || sample_rate_index == 3
+    {
+        return None;
+    }
+
+    const MPEG1_L3_BITRATES: [u32; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const MPEG2_L3_BITRATES: [u32; 15] =
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 32_000];
+
+    let base_rate = SAMPLE_RATES[sample_rate_index];
+    let (bitrate_kbps, sample_rate) = match version {
+        3 => (MPEG1_L3_BITRATES[bitrate_index], base_rate),
+        2 => (MPEG2_L3_BITRATES[bitrate_index], base_rate / 2),
+        _ => (MPEG2_L3_BITRATES[bitrate_index], base_rate / 4),
+    };
+
+    Some(Mp3FrameHeader {
+        sample_rate,
+        channels: if channel_mode == 3 { 1 } else { 2 },
+        bitrate_kbps,
+    })
+}
+
+// Assumes constant bitrate, which holds for provider-generated mp3
+fn mp3_duration_ms(bytes: &[u8]) -> Option<u64> {
+    let header = parse_mp3_header(bytes)?;
+    Some(bytes.len() as u64 * 8 / header.bitrate_kbps as u64)
+}
+
+pub struct FlacStreamInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub total_samples: u64,
+}
+
+// STREAMINFO is always the first metadata block after the "fLaC" marker
+pub fn parse_flac_streaminfo(bytes: &[u8]) -> Option<FlacStreamInfo> {
+    if bytes.len() < 4 + 4 + 18 || &bytes[0..4] != b"fLaC" || bytes[4] & 0x7f != 0 {
+        return None;
+    }
+
+    // Skip min/max block size (4 bytes) and min/max frame size (6 bytes)
+    let info = &bytes[8 + 10..8 + 18];
+    let sample_rate = ((info[0] as u32) << 12) | ((info[1] as u32) << 4) | ((info[2] as u32) >> 4);
+    let channels = ((info[2] >> 1) & 0x07) as u16 + 1;
+    let total_samples =
+        (((info[3] & 0x0f) as u64) << 32) | u32::from_be_bytes(info[4..8].try_into().ok()?) as u64;
+
+    Some(FlacStreamInfo {
+        sample_rate,
+        channels,
+        total_samples,
+    })
+}
+
+// Read the identification header from the first Ogg page (Opus or Vorbis)
+fn parse_ogg_info(bytes: &[u8]) -> Option<AudioInfo> {
+    if bytes.len() < 27 || &bytes[0..4] != b"OggS" {
+        return None;
+    }
+
+    let segments = bytes[26] as usize;
+    let payload = bytes.get(27 + segments..)?;
+
+    if payload.len() >= 19 && &payload[0..8] == b"OpusHead" {
+        // Opus always decodes at 48kHz; the header carries the original input rate
+        return Some(AudioInfo {
+            sample_rate: Some(u32::from_le_bytes(payload[12..16].try_into().ok()?)),
+            channels: Some(payload[9] as u16),
+            bitrate_kbps: None,
+            codec: "opus".to_string(),
+        });
+    }
+
+    if payload.len() >= 30 && &payload[0..7] == b"\x01vorbis" {
+        let nominal_bitrate = i32::from_le_bytes(payload[20..24].try_into().ok()?);
+        return Some(AudioInfo {
+            sample_rate: Some(u32::from_le_bytes(payload[12..16].try_into().ok()?)),
+            channels: Some(payload[11] as u16),
+            bitrate_kbps: (nominal_bitrate > 0).then_some(nominal_bitrate as u32 / 1000),
+            codec: "vorbis".to_string(),
+        });
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One second of a repeating ramp, which compresses well like real silence-padded speech
+    fn sample_wav() -> Vec<u8> {
+        let pcm: Vec<u8> = (0..OPENAI_PCM_SAMPLE_RATE)
+            .flat_map(|i| (((i % 64) as i16 - 32) * 256).to_le_bytes())
+            .collect();
+        wrap_pcm_as_wav(&pcm, OPENAI_PCM_SAMPLE_RATE, OPENAI_PCM_BITS_PER_SAMPLE)
+    }
+
+    #[test]
+    fn compressed_wav_is_smaller_and_round_trips() {
+        let wav = sample_wav();
+        let compressed = compress(&wav).unwrap();
+        assert!(compressed.len() < wav.len());
+        assert_eq!(decompress(&compressed, ZLIB_COMPRESSION).unwrap(), wav);
+    }
+
+    #[test]
+    fn parses_a_canonical_wav_header() {
+        // 44.1kHz, 16-bit stereo: 44-byte header followed by two frames of samples
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&44u32.to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&44_100u32.to_le_bytes());
+        wav.extend_from_slice(&176_400u32.to_le_bytes());
+        wav.extend_from_slice(&4u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&8u32.to_le_bytes());
+        assert_eq!(wav.len(), 44);
+        wav.extend_from_slice(&[0; 8]);
+
+        let header = parse_wav_header(&wav).unwrap();
+        assert_eq!(header.sample_rate, 44_100);
+        assert_eq!(header.channels, 2);
+        assert_eq!(header.bits_per_sample, 16);
+        assert_eq!(header.data_start, 44);
+        assert_eq!(header.data_len, 8);
+
+        let info = audio_info(&wav, "wav").unwrap();
+        assert_eq!(info.sample_rate, Some(44_100));
+        assert_eq!(info.channels, Some(2));
+        assert_eq!(info.bitrate_kbps, Some(1411));
+    }
+}
@@ -0,0 +1,90 @@
+// AWS helpers: Signature Version 4 request signing for the REST APIs we call directly
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::AwsCredentials;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Headers (Authorization, X-Amz-Date, ...) that sign a JSON POST to `https://{host}{path}`.
+// The Host header itself is left to the HTTP client, but is covered by the signature.
+pub fn sigv4_json_post_headers(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    host: &str,
+    path: &str,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> HashMap<String, String> {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date_stamp = now.format("%Y%m%d").to_string();
+    let payload_hash = hex::encode(Sha256::digest(body));
+
+    // Canonical headers must be lowercase and sorted by name
+    let mut signed = vec![
+        ("content-type", "application/json".to_string()),
+        ("host", host.to_string()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        signed.push(("x-amz-security-token", token.clone()));
+    }
+    let canonical_headers: String = signed
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = signed
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "POST\n{}\n\n{}\n{}\n{}",
+        path, canonical_headers, signed_headers, payload_hash
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date_stamp, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let k_date = hmac_sha256(
+        format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+        date_stamp.as_bytes(),
+    );
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    let k_signing = hmac_sha256(&k_service, b"aws4_request");
+    let signature = hex::encode(hmac_sha256(&k_signing, string_to_sign.as_bytes()));
+
+    let mut headers = HashMap::from([
+        ("Content-Type".to_string(), "application/json".to_string()),
+        ("X-Amz-Date".to_string(), amz_date),
+        (
+            "Authorization".to_string(),
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key_id, scope, signed_headers, signature
+            ),
+        ),
+    ]);
+    if let Some(token) = &credentials.session_token {
+        headers.insert("X-Amz-Security-Token".to_string(), token.clone());
+    }
+    headers
+}
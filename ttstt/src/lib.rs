@@ -3,21 +3,37 @@
 
 use hyperprocess_macro::*;
 use hyperware_process_lib::{
+    get_blob,
     homepage::add_to_homepage,
-    hyperapp::SaveOptions,
+    http::{client::send_request_await_response, Method},
+    hyperapp::{add_response_header, get_path, sleep, spawn, SaveOptions},
     our,
     vfs::{
         create_drive,
-        directory::directory_async::open_dir_async,
-        file::file_async::{create_file_async, open_file_async},
+        directory::directory_async::{open_dir_async, remove_dir_async},
+        file::file_async::{create_file_async, open_file_async, remove_file_async},
+        parse_response, vfs_request, DirEntry, FileType, SeekFrom, VfsAction, VfsResponse,
     },
 };
 
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Poll;
 use uuid::Uuid;
 
+mod audio;
+mod aws;
+mod text;
+
 // Import OpenAI clients
 use hyperware_openai_stt::{client::TranscriptionClient, types::Model as OpenAISttModel};
 use hyperware_openai_tts::{
@@ -30,17 +46,179 @@ use hyperware_openai_tts::{
 pub enum Provider {
     #[serde(rename = "OpenAI")]
     OpenAI,
-    // Future: ElevenLabs, PlayAI, Groq
+    PlayAI,
+    Azure,    // Azure Cognitive Services Speech (region-scoped, see `ProviderConfig::region`)
+    AwsPolly, // Amazon Polly; region-scoped and signed with `ProviderConfig::aws_credentials`
+    Local,    // On-node STT service, used directly or as a fallback (see `local-stt` feature)
+    Webhook,  // Any HTTP backend speaking a small JSON protocol (see `handle_webhook_tts`)
+              // Future: ElevenLabs, Groq
+}
+
+impl Provider {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Provider::OpenAI => "OpenAI",
+            Provider::PlayAI => "PlayAI",
+            Provider::Azure => "Azure",
+            Provider::AwsPolly => "AwsPolly",
+            Provider::Local => "Local",
+            Provider::Webhook => "Webhook",
+        }
+    }
+
+    fn supports_tts(&self) -> bool {
+        match self {
+            Provider::OpenAI => true,
+            Provider::PlayAI => true,
+            Provider::Azure => true,
+            Provider::AwsPolly => true,
+            Provider::Local => false,
+            Provider::Webhook => true,
+        }
+    }
+
+    // Accepted TTS speed multipliers, or None for providers without TTS or whose range
+    // isn't known
+    fn speed_range(&self) -> Option<(f32, f32)> {
+        match self {
+            Provider::OpenAI => Some((0.25, 4.0)),
+            Provider::PlayAI => Some((0.1, 5.0)),
+            Provider::Azure => Some((0.5, 2.0)), // SSML prosody rate as a relative multiplier
+            Provider::AwsPolly => Some((0.2, 2.0)), // SSML prosody rate, 20% to 200%
+            Provider::Local | Provider::Webhook => None,
+        }
+    }
+
+    // Longest TTS input the provider documents accepting, or None where it publishes no
+    // character limit
+    fn max_tts_chars(&self) -> Option<u32> {
+        match self {
+            Provider::OpenAI => Some(4096),
+            Provider::AwsPolly => Some(3000), // Billed characters; SSML tags don't count
+            Provider::PlayAI | Provider::Azure | Provider::Local | Provider::Webhook => None,
+        }
+    }
+
+    // Static capability table behind get_provider_capabilities; new providers add an arm.
+    // Formats are the `format` values the provider's TTS handler accepts.
+    fn capabilities(&self) -> ProviderCapabilities {
+        let (tts_formats, ssml, translation): (&[&str], bool, bool) = match self {
+            Provider::OpenAI => (&["mp3", "opus", "aac", "flac", "wav", "pcm"], false, true),
+            Provider::PlayAI => (&["mp3", "wav", "ogg", "flac"], false, false),
+            Provider::Azure => (&["mp3", "wav", "pcm", "ogg", "webm"], true, false),
+            Provider::AwsPolly => (&["mp3", "ogg"], true, false),
+            Provider::Local => (&[], false, false),
+            // Formats are whatever the backend accepts
+            Provider::Webhook => (&[], false, false),
+        };
+        ProviderCapabilities {
+            provider: self.clone(),
+            tts: self.supports_tts(),
+            stt: self.supports_stt(),
+            tts_formats: tts_formats.iter().map(|f| f.to_string()).collect(),
+            ssml,
+            // No provider call streams yet, and none return word timestamps
+            streaming: false,
+            timestamps: false,
+            translation,
+            speed_range: self.speed_range(),
+            max_tts_chars: self.max_tts_chars(),
+        }
+    }
+
+    fn supports_stt(&self) -> bool {
+        match self {
+            Provider::OpenAI => true,
+            Provider::PlayAI => false,
+            Provider::Azure => true,
+            Provider::AwsPolly => false,
+            Provider::Local => true,
+            Provider::Webhook => true,
+        }
+    }
+}
+
+// Internal errors with structure worth keeping until they're rendered for the caller
+#[derive(Debug, Clone, PartialEq)]
+enum TtsttError {
+    // Every attempted provider, in order; only the local-stt fallback retries today
+    #[cfg_attr(not(feature = "local-stt"), allow(dead_code))]
+    AllProvidersFailed(Vec<(Provider, String)>),
+}
+
+impl std::fmt::Display for TtsttError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TtsttError::AllProvidersFailed(errors) => {
+                write!(f, "All providers failed")?;
+                for (i, (provider, error)) in errors.iter().enumerate() {
+                    let sep = if i == 0 { ": " } else { "; " };
+                    write!(f, "{}{}: {}", sep, provider.as_str(), error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Endpoints return String errors
+impl From<TtsttError> for String {
+    fn from(error: TtsttError) -> Self {
+        error.to_string()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ProviderConfig {
     provider: Provider,
+    #[serde(default = "default_profile_name")]
+    profile_name: String, // Distinguishes several accounts for one provider, e.g. "dev" and "prod"
     api_key: String,
     is_default_tts: bool,
     is_default_stt: bool,
     default_voice: Option<String>,
     default_speed: Option<f32>,
+    #[serde(default)]
+    user_id: Option<String>, // Account/user id for providers that require one (PlayAI)
+    #[serde(default)]
+    region: Option<String>, // Service region for region-scoped providers (Azure), e.g. "eastus"
+    #[serde(default)]
+    base_url: Option<String>, // OpenAI-compatible API root incl. version, e.g. "http://localhost:8080/v1"
+    #[serde(default)]
+    aws_credentials: Option<AwsCredentials>, // Required for AwsPolly, which doesn't use `api_key`
+    #[serde(default)]
+    default_tts_model: Option<String>, // Used when a request names no model
+    #[serde(default)]
+    default_stt_model: Option<String>,
+    // Webhook only: JSON fields holding the response's base64 audio (default "audio_data")
+    // and transcript (default "text"); dots reach nested fields, e.g. "result.text"
+    #[serde(default)]
+    response_audio_field: Option<String>,
+    #[serde(default)]
+    response_text_field: Option<String>,
+}
+
+// What a provider can do, so clients can hide options it doesn't support
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProviderCapabilities {
+    provider: Provider,
+    tts: bool,
+    stt: bool,
+    tts_formats: Vec<String>,
+    ssml: bool, // TTS text starting with <speak> is passed through as SSML
+    streaming: bool,
+    timestamps: bool,
+    translation: bool, // STT `translate` option
+    speed_range: Option<(f32, f32)>,
+    max_tts_chars: Option<u32>,
+}
+
+// IAM access key pair used to sign AWS requests
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>, // Only for temporary (STS) credentials
 }
 
 // TTS Types
@@ -52,14 +230,124 @@ pub struct TtsReq {
     model: Option<String>,
     format: Option<String>,
     speed: Option<f32>,
-    api_key: Option<String>, // For request authentication
+    api_key: Option<String>,                 // For request authentication
+    metadata: Option<Vec<(String, String)>>, // Client context merged into the stored pair
+    expand_numbers: Option<bool>, // Spell out numbers, currency and dates before synthesis
+    normalize: Option<bool>,      // expand_numbers plus punctuation and whitespace cleanup
+    locale: Option<String>,       // Expansion locale hint, e.g. "en-US" (default) or "en-GB"
+    callback_url: Option<String>, // POSTed a CompletionCallback once the pair has been saved
+    raw_pcm: Option<bool>,        // Keep headerless pcm instead of wrapping it as wav
+    web_compatible: Option<bool>, // Return OpenAI opus as "ogg" so browsers can play it
+    bit_depth: Option<u16>,       // wav bit depth when pcm is wrapped, 16 (default) or 24
+    encoding: Option<String>,     // TtsRes::audio_data as "base64" (default) or "hex"
+    sample_rate: Option<u32>,     // pcm output rate in Hz, one of audio::SUPPORTED_PCM_SAMPLE_RATES
+    store_only: Option<bool>, // Save the pair and return only its id and format; fails if not saved
+    idempotency_key: Option<String>, // Retries with the same key return the stored result
+    pair_id: Option<String>,  // Store under this id instead of a random one, e.g. in tests
+    profile: Option<String>,  // Provider profile to use; defaults as described on `resolve_profile`
+    include_raw: Option<bool>, // Attach provider response details to TtsRes::raw
+    segments: Option<Vec<TtsSegment>>, // Synthesize these in turn instead of `text`, then concatenate
+}
+
+// Part of a segmented TTS request, e.g. one language of bilingual content. Unset fields
+// fall back to the request's voice and locale.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TtsSegment {
+    text: String,
+    voice: Option<String>,
+    language: Option<String>, // Expansion locale for this segment
+}
+
+const TTS_SEGMENTS_METADATA_KEY: &str = "segments";
+
+// Re-synthesize a stored pair's text; unset overrides fall back to the usual TTS defaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegenerateFromPairReq {
+    id: String,
+    provider: Option<Provider>,
+    voice: Option<String>,
+    model: Option<String>,
+    api_key: Option<String>,
+}
+
+// Transcribe a stored pair's audio again, e.g. with a better model; unset overrides fall
+// back to the usual STT defaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetranscribePairReq {
+    id: String,
+    provider: Option<Provider>,
+    model: Option<String>,
+    language: Option<String>,
+    api_key: Option<String>,
+}
+
+// Dry-run pricing for a TTS request; no provider call is made
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CostEstimate {
+    characters: u32,
+    estimated_usd: f32,
+    model: String,
+}
+
+// App-level TTS defaults, applied after request values and provider defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TtsDefaults {
+    voice: Option<String>,
+    model: Option<String>,
+    format: Option<String>,
+    speed: Option<f32>,
+    // Operator-approved speed band, applied on top of each provider's own range
+    min_speed: Option<f32>,
+    max_speed: Option<f32>,
+}
+
+impl TtsDefaults {
+    // Keep a speed inside the configured band, clamping unless `strict`
+    fn check_speed_band(&self, speed: f32, strict: bool) -> Result<f32, String> {
+        let min = self.min_speed.unwrap_or(f32::MIN);
+        let max = self.max_speed.unwrap_or(f32::MAX);
+        if strict && !(min..=max).contains(&speed) {
+            return Err(format!(
+                "speed {} is outside the allowed range {} to {}",
+                speed,
+                self.min_speed.map_or("-".to_string(), |s| s.to_string()),
+                self.max_speed.map_or("-".to_string(), |s| s.to_string())
+            ));
+        }
+        Ok(speed.clamp(min, max))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetTtsDefaultsReq {
+    api_key: Option<String>,
+    defaults: TtsDefaults,
+    strict_speed: Option<bool>, // Reject out-of-range speeds instead of clamping; unchanged when None
+}
+
+// Provider-neutral voice name, e.g. "female-warm", mapped to a provider's own voice
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct VoiceAlias {
+    alias: String,
+    provider: Provider,
+    voice: String,
+}
+
+// Replaces the whole alias table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetVoiceAliasesReq {
+    api_key: Option<String>,
+    aliases: Vec<VoiceAlias>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TtsRes {
-    audio_data: String, // Base64 encoded
+    audio_data: String, // Base64 encoded; empty for store_only requests
     format: String,
     provider: Provider,
+    audio_info: Option<AudioInfo>,
+    raw: Option<Vec<(String, String)>>, // Provider response details, when include_raw was set
+    pair_id: Option<String>, // Stored pair, which may be an earlier identical one; None if not saved
 }
 
 // STT Types
@@ -69,13 +357,172 @@ pub struct SttReq {
     provider: Option<Provider>,
     model: Option<String>,
     language: Option<String>,
-    api_key: Option<String>, // For request authentication
+    api_key: Option<String>,                 // For request authentication
+    metadata: Option<Vec<(String, String)>>, // Client context merged into the stored pair
+    include_raw: Option<bool>,               // Attach provider response details to SttRes::raw
+    profile: Option<String>,                 // Provider profile to use, as for TtsReq
+    translate: Option<bool>,                 // Translate to English (OpenAI whisper-1 only)
+    idempotency_key: Option<String>,         // As for TtsReq
+    pair_id: Option<String>,                 // As for TtsReq
+    format: Option<String>, // Container, e.g. "m4a"; sniffed from the audio when unset
+    encoding: Option<String>, // How audio_data is encoded, "base64" (default) or "hex"
+    trim_silence: Option<bool>, // Cut leading/trailing silence before upload; wav only
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SttRes {
     text: String,
     provider: Provider,
+    audio_info: Option<AudioInfo>, // Details of the submitted audio
+    raw: Option<Vec<(String, String)>>, // Provider response details, when include_raw was set
+    detected_language: Option<String>, // Source language, where the provider reports it
+}
+
+// Several STT requests in one call; each item keeps its own provider/model/language
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SttBatchReq {
+    api_key: Option<String>, // Applies to every item, replacing any item-level key
+    items: Vec<SttReq>,
+    options: Option<BatchOptions>, // Defaults to BatchOptions::default()
+}
+
+// Throughput against rate-limit safety for a batch. Concurrency only overlaps OpenAI
+// calls, whose client awaits the response; Azure, Webhook and Local calls block until they
+// return, so those items run one after another whatever max_concurrency says. Batches are
+// STT only: there is no TTS batch endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchOptions {
+    max_concurrency: u32, // Provider calls in flight at once; still capped by max_concurrent_calls
+    preserve_order: bool, // Return results in input order rather than as they complete
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            max_concurrency: DEFAULT_STT_BATCH_CONCURRENCY,
+            preserve_order: true,
+        }
+    }
+}
+
+// Outcome of one batch item; exactly one of response and error is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SttBatchItem {
+    index: u32, // Position of the item in the request
+    response: Option<SttRes>,
+    error: Option<String>,
+}
+
+impl SttBatchItem {
+    fn new(index: usize, result: Result<SttRes, String>) -> Self {
+        match result {
+            Ok(response) => SttBatchItem {
+                index: index as u32,
+                response: Some(response),
+                error: None,
+            },
+            Err(e) => SttBatchItem {
+                index: index as u32,
+                response: None,
+                error: Some(e),
+            },
+        }
+    }
+}
+
+// An STT request checked and ready for its provider call, as split out of stt() so
+// stt_batch can run several calls at once
+struct PreparedStt {
+    request_id: String,
+    request: SttReq,
+    provider: Provider,
+    audio_bytes: Vec<u8>,
+    trimmed_duration_ms: Option<u64>, // Set when trim_silence cut the audio
+}
+
+enum SttPrep {
+    Answered(SttRes), // Replayed from an earlier pair with the same idempotency key
+    Ready(PreparedStt),
+}
+
+// Transcribe audio and speak the transcript back, e.g. to change the voice of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SttThenTtsReq {
+    api_key: Option<String>,
+    audio_data: String, // Base64 encoded
+    language: Option<String>,
+    stt_provider: Option<Provider>, // Each falls back to the default provider for its side
+    tts_provider: Option<Provider>,
+    voice: Option<String>,
+    model: Option<String>, // TTS model
+    format: Option<String>,
+}
+
+// Both results; each names the pair it was stored as in the other's metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SttThenTtsRes {
+    transcript: SttRes,
+    speech: TtsRes,
+    stt_pair_id: String,
+    tts_pair_id: Option<String>, // As speech.pair_id; None if the speech wasn't saved
+}
+
+// Metadata keys cross-linking the two pairs stored by stt_then_tts
+const TTS_PAIR_METADATA_KEY: &str = "tts_pair_id";
+const STT_PAIR_METADATA_KEY: &str = "stt_pair_id";
+
+// Link to add to the transcript's pair once TTS has run: the pair tts() actually stored,
+// which may be an earlier identical one. None if TTS failed or saved nothing.
+fn tts_pair_link(speech: &Result<TtsRes, String>) -> Option<(String, String)> {
+    let pair_id = speech.as_ref().ok()?.pair_id.clone()?;
+    Some((TTS_PAIR_METADATA_KEY.to_string(), pair_id))
+}
+
+// One chunk of a progressive STT upload. Chunks are appended in order; settings are only
+// read from the chunk that starts the session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SttStreamReq {
+    api_key: Option<String>,
+    session_id: Option<String>, // None starts a new session
+    audio_data: String,         // Base64 encoded chunk; may be empty
+    flush: bool,                // Transcribe everything received so far as an interim result
+    is_final: bool,             // Transcribe and store the whole recording, ending the session
+    provider: Option<Provider>,
+    model: Option<String>,
+    language: Option<String>,
+    profile: Option<String>,
+    metadata: Option<Vec<(String, String)>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SttStreamRes {
+    session_id: String,
+    received_bytes: u64,
+    text: Option<String>, // Interim transcript on flush, final transcript on is_final
+    provider: Option<Provider>,
+    is_final: bool,
+}
+
+// Buffered audio for an in-progress stt_stream session
+#[derive(Debug, Clone)]
+struct SttStream {
+    api_key: Option<String>,
+    audio: Vec<u8>,
+    provider: Option<Provider>,
+    model: Option<String>,
+    language: Option<String>,
+    profile: Option<String>,
+    metadata: Option<Vec<(String, String)>>,
+    last_chunk_at: chrono::DateTime<Utc>,
+}
+
+// Audio details parsed from container/frame headers where possible
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AudioInfo {
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    bitrate_kbps: Option<u32>,
+    codec: String,
 }
 
 // Storage Types
@@ -95,6 +542,8 @@ pub struct AudioTextPair {
     timestamp: String,
     request_type: RequestType,
     metadata: Vec<(String, String)>, // Using Vec instead of HashMap for WIT compatibility
+    duration_ms: Option<u64>,        // Parsed from the audio where the format allows
+    audio_info: Option<AudioInfo>,
 }
 
 // API Key Management
@@ -110,6 +559,24 @@ pub struct ApiKey {
     role: ApiKeyRole,
     created_at: String,
     name: String,
+    #[serde(default)]
+    rate_limit_per_min: Option<u32>, // tts/stt requests allowed per minute; None is unlimited
+    #[serde(default)]
+    last_used_at: Option<String>, // Last successful validation; None if never used
+}
+
+// One-time grant to fetch a pair's audio without an API key
+#[derive(Debug, Clone)]
+struct AudioToken {
+    pair_id: String,
+    expires_at: chrono::DateTime<Utc>,
+}
+
+// Token bucket for one API key; holds up to a minute's allowance and refills continuously
+#[derive(Debug, Clone)]
+struct RateBucket {
+    tokens: f64,
+    last_refill: chrono::DateTime<Utc>,
 }
 
 // Request/Response types for endpoints
@@ -133,13 +600,15 @@ pub struct AddProviderReq {
 pub struct RemoveProviderReq {
     api_key: Option<String>,
     provider: Provider,
+    profile_name: Option<String>, // None removes every profile of the provider
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SetDefaultProviderReq {
     api_key: Option<String>,
     provider: Provider,
-    provider_type: String, // "tts" or "stt"
+    provider_type: String,        // "tts" or "stt"
+    profile_name: Option<String>, // Defaults to the provider's DEFAULT_PROFILE_NAME profile
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -147,6 +616,7 @@ pub struct GenerateApiKeyReq {
     api_key: Option<String>,
     name: String,
     role: ApiKeyRole,
+    rate_limit_per_min: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -162,6 +632,52 @@ pub struct RevokeApiKeyReq {
     key_to_revoke: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokeApiKeyByNameReq {
+    api_key: Option<String>,
+    name: String,
+}
+
+// Change a key's role in place, keeping its value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetApiKeyRoleReq {
+    api_key: Option<String>,
+    key_to_modify: String,
+    role: ApiKeyRole,
+}
+
+// Audit log: one JSON line per admin action, appended to AUDIT_DRIVE. Keys are
+// identified by name only, never by value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    timestamp: String,
+    actor: String,  // Name of the key that made the call
+    action: String, // Endpoint name, e.g. "revoke_api_key"
+    target: String, // Provider, key name or pair id acted on
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAuditLogReq {
+    api_key: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+// Newest entries first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditLogPage {
+    entries: Vec<AuditEntry>,
+    total: u32,
+    has_more: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetRateLimitReq {
+    api_key: Option<String>,
+    key: String,
+    rate_limit_per_min: Option<u32>, // None removes the limit
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListApiKeysReq {
     api_key: Option<String>,
@@ -173,765 +689,7599 @@ pub struct ApiKeyInfo {
     role: ApiKeyRole,
     created_at: String,
     key_preview: String,
+    rate_limit_per_min: Option<u32>,
+    last_used_at: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProviderInfo {
     provider: Provider,
+    profile_name: String,
+    is_default_tts: bool,
+    is_default_stt: bool,
+    default_voice: Option<String>,
+    default_speed: Option<f32>,
+    default_tts_model: Option<String>,
+    default_stt_model: Option<String>,
+    total_spend: f64, // Estimated USD across TTS and STT since the last reset_spend
+    healthy: Option<bool>, // From the init preflight; None if it didn't run or can't check
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetProvidersAdminReq {
+    api_key: Option<String>,
+}
+
+// Full provider config for admins; the provider's api_key is masked to its last 4 chars
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderAdminInfo {
+    provider: Provider,
+    profile_name: String,
+    api_key_masked: String,
     is_default_tts: bool,
     is_default_stt: bool,
     default_voice: Option<String>,
     default_speed: Option<f32>,
+    default_tts_model: Option<String>,
+    default_stt_model: Option<String>,
+    user_id: Option<String>,
+    region: Option<String>,
+    base_url: Option<String>,
+    aws_access_key_id_masked: Option<String>,
+    total_spend: f64,
+    healthy: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetSpendReq {
+    api_key: Option<String>,
+    provider: Option<Provider>, // None resets every provider
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetHistoryReq {
     limit: Option<u32>,
     offset: Option<u32>,
+    include_audio: Option<bool>, // Defaults to false; fetch audio via get_audio_text_pair
+    sort_by: Option<String>,     // "timestamp" (default), "text_length", "duration", "provider"
+    order: Option<String>,       // "asc" or "desc" (default)
+    cursor: Option<String>,      // next_cursor from a previous page; replaces offset
+    sort: Option<SortOrder>,     // Typed alternative to `order`
+    exclude_test: Option<bool>,  // Leave out pairs made by test_tts/test_stt
+    min_duration_ms: Option<u64>, // Duration bounds, inclusive; not supported with `cursor`
+    max_duration_ms: Option<u64>,
+    include_unknown_duration: Option<bool>, // With bounds set, keep pairs lacking a duration
 }
 
+// Count of stored pairs, optionally narrowed; all filters must match
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct GetAudioTextPairReq {
-    id: String,
+pub struct CountHistoryReq {
+    provider: Option<Provider>,
+    request_type: Option<RequestType>,
+    exclude_test: Option<bool>,
 }
 
+// Incremental sync: pairs created strictly after `timestamp` (RFC3339), oldest first
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct GetAdminKeyRes {
-    admin_key: String,
-    message: String,
+pub struct GetHistorySinceReq {
+    timestamp: String,
+    limit: Option<u32>, // Defaults to 100; pass the last pair's timestamp to continue
+    include_audio: Option<bool>,
+    exclude_test: Option<bool>,
 }
 
-// App State
-#[derive(Default, Serialize, Deserialize)]
-pub struct TtsttState {
-    // Provider configurations
-    providers: Vec<ProviderConfig>,
-
-    // TTSTT API keys
-    api_keys: Vec<ApiKey>,
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub enum SortOrder {
+    #[default]
+    NewestFirst,
+    OldestFirst,
+}
 
-    // Settings
-    default_tts_provider: Option<Provider>,
-    default_stt_provider: Option<Provider>,
+// Paged history with enough context for clients to stop paging deterministically
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pairs: Vec<AudioTextPair>,
+    total: u32,                  // Pairs available across all pages
+    has_more: bool,              // False once offset + page length reaches total
+    next_cursor: Option<String>, // Opaque; pass back as `cursor` (timestamp sort only)
+}
 
-    // Admin key (generated on first init)
-    admin_key: String,
+// Trash: deleted pairs move to TRASH_DRIVE until restored or the trash is emptied
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteAudioTextPairReq {
+    api_key: Option<String>,
+    id: String,
+}
 
-    // Storage path for audio-text pairs
-    storage_initialized: bool,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreAudioTextPairReq {
+    api_key: Option<String>,
+    id: String,
 }
 
-// Helper methods (outside of hyperprocess impl block)
-impl TtsttState {
-    // Helper: Validate API key and check permissions
-    fn validate_api_key(&self, api_key: Option<String>, require_admin: bool) -> Result<(), String> {
-        let key = api_key.ok_or("API key required")?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmptyTrashReq {
+    api_key: Option<String>,
+}
 
-        let api_key_entry = self
-            .api_keys
-            .iter()
-            .find(|k| k.key == key)
-            .ok_or("Invalid API key")?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupeHistoryReq {
+    api_key: Option<String>,
+}
 
-        if require_admin && !matches!(api_key_entry.role, ApiKeyRole::Admin) {
-            return Err("Admin permission required".to_string());
-        }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAudioTextPairReq {
+    id: String,
+}
 
-        Ok(())
-    }
+// Token for fetching a pair's audio from /audio/<token>, e.g. as an <audio src>
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAudioTokenReq {
+    id: String,
+}
 
-    // Helper: Get provider config
-    fn get_provider_config(&self, provider: &Provider) -> Result<&ProviderConfig, String> {
-        self.providers
-            .iter()
-            .find(|p| p.provider == *provider)
-            .ok_or_else(|| format!("Provider {:?} not configured", provider))
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioTokenRes {
+    token: String,
+    expires_at: String, // RFC 3339; the token also stops working once used
+}
 
-    // OpenAI TTS implementation
-    async fn handle_openai_tts(&self, request: TtsReq) -> Result<TtsRes, String> {
-        let config = self.get_provider_config(&Provider::OpenAI)?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAudioRangeReq {
+    id: String,
+    start: u64,  // Byte offset into the audio
+    length: u64, // At most MAX_AUDIO_RANGE_BYTES
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioRangeRes {
+    audio_data: String, // Base64 encoded; shorter than requested at the end of the audio
+    format: String,
+    start: u64,
+    total_bytes: u64, // Size of the whole audio, for building Content-Range
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdatePairMetadataReq {
+    api_key: Option<String>,
+    id: String,
+    metadata: Vec<(String, String)>,
+    replace: Option<bool>, // true replaces the whole array; default merges by key
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportHistoryReq {
+    api_key: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+    compress: Option<bool>, // Gzip the archive; defaults to true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportHistoryReq {
+    api_key: Option<String>,
+    archive: String,          // As produced by export_history
+    compressed: Option<bool>, // ExportRes::compressed; None detects gzip from the archive itself
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportRes {
+    imported: u32,
+    skipped: u32, // Already present or invalid
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportRes {
+    archive: String, // Base64-encoded JSON lines, one ExportEntry per pair, gzipped if `compressed`
+    count: u32,
+    has_more: bool,
+    compressed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAdminKeyRes {
+    admin_key: String,
+    message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotateAdminKeyReq {
+    api_key: Option<String>, // Must be the current initial admin key
+    name: Option<String>,    // Keeps the existing name when None
+}
+
+// Transcript post-processing webhook
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TranscriptWebhook {
+    url: String,
+    timeout_secs: Option<u64>,
+}
+
+// Body POSTed to a request's callback_url after its pair is saved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompletionCallback {
+    id: String,
+    provider: Provider,
+    format: String,
+    duration_ms: Option<u64>,
+    stored: bool, // False when saving the pair failed; the audio was still returned
+}
+
+// Local (on-node) STT service, e.g. a whisper server on the same machine
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LocalSttConfig {
+    url: String,
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetLocalSttReq {
+    api_key: Option<String>,
+    config: Option<LocalSttConfig>, // None removes the local provider
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetTranscriptWebhookReq {
+    api_key: Option<String>,
+    webhook: Option<TranscriptWebhook>, // None disables post-processing
+}
+
+// On-disk contents of a pair's metadata.json (audio bytes are stored alongside)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredPairMetadata {
+    id: String,
+    text: String,
+    audio_format: String,
+    provider: Provider,
+    timestamp: String,
+    request_type: RequestType,
+    metadata: Vec<(String, String)>,
+    #[serde(default)]
+    duration_ms: Option<u64>,
+    #[serde(default)]
+    audio_info: Option<AudioInfo>,
+    // Set when the audio file holds compressed bytes (e.g. "zlib")
+    #[serde(default)]
+    compression: Option<String>,
+    // Hex SHA-256 of the original (uncompressed) audio; None for pairs saved before checksums
+    #[serde(default)]
+    audio_sha256: Option<String>,
+    // Set while the audio file is still being written after the metadata
+    #[serde(default)]
+    pending: bool,
+}
+
+impl StoredPairMetadata {
+    fn from_pair(pair: &AudioTextPair) -> Self {
+        Self {
+            id: pair.id.clone(),
+            text: pair.text.clone(),
+            audio_format: pair.audio_format.clone(),
+            provider: pair.provider.clone(),
+            timestamp: pair.timestamp.clone(),
+            request_type: pair.request_type.clone(),
+            metadata: pair.metadata.clone(),
+            duration_ms: pair.duration_ms,
+            audio_info: pair.audio_info.clone(),
+            compression: None,
+            audio_sha256: None,
+            pending: false,
+        }
+    }
+
+    fn into_pair(self, audio_data: String) -> AudioTextPair {
+        AudioTextPair {
+            id: self.id,
+            text: self.text,
+            audio_data,
+            audio_format: self.audio_format,
+            provider: self.provider,
+            timestamp: self.timestamp,
+            request_type: self.request_type,
+            metadata: self.metadata,
+            duration_ms: self.duration_ms,
+            audio_info: self.audio_info,
+        }
+    }
+}
+
+// One line of an exported history archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExportEntry {
+    metadata: StoredPairMetadata,
+    audio_data: String, // Base64 encoded
+}
+
+// Which stored pairs a history listing includes, checked against metadata alone
+struct HistoryFilter {
+    exclude_test: bool,
+    min_duration_ms: Option<u64>,
+    max_duration_ms: Option<u64>,
+    include_unknown_duration: bool,
+}
+
+impl HistoryFilter {
+    fn from_request(request: &GetHistoryReq) -> Result<Self, String> {
+        if let (Some(min), Some(max)) = (request.min_duration_ms, request.max_duration_ms) {
+            if min > max {
+                return Err("min_duration_ms must not exceed max_duration_ms".to_string());
+            }
+        }
+        Ok(Self {
+            exclude_test: request.exclude_test.unwrap_or(false),
+            min_duration_ms: request.min_duration_ms,
+            max_duration_ms: request.max_duration_ms,
+            include_unknown_duration: request.include_unknown_duration.unwrap_or(false),
+        })
+    }
+
+    fn filters_duration(&self) -> bool {
+        self.min_duration_ms.is_some() || self.max_duration_ms.is_some()
+    }
+
+    // Pairs without a duration (formats we can't parse, or saved before durations were
+    // recorded) are an error under duration bounds unless include_unknown_duration is set
+    fn matches(&self, metadata: &StoredPairMetadata) -> Result<bool, String> {
+        if self.exclude_test && is_test_pair(&metadata.metadata) {
+            return Ok(false);
+        }
+        if !self.filters_duration() {
+            return Ok(true);
+        }
+        let Some(duration_ms) = metadata.duration_ms else {
+            if self.include_unknown_duration {
+                return Ok(true);
+            }
+            return Err(format!(
+                "Pair {} has no recorded duration; set include_unknown_duration to include such pairs",
+                metadata.id
+            ));
+        };
+        Ok(self.min_duration_ms.is_none_or(|min| duration_ms >= min)
+            && self.max_duration_ms.is_none_or(|max| duration_ms <= max))
+    }
+}
+
+// Field used to order history listings
+#[derive(Debug, Clone, Copy)]
+enum HistorySortKey {
+    Timestamp,
+    TextLength,
+    Duration,
+    Provider,
+}
+
+impl HistorySortKey {
+    fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("timestamp") => Ok(Self::Timestamp),
+            Some("text_length") => Ok(Self::TextLength),
+            Some("duration") => Ok(Self::Duration),
+            Some("provider") => Ok(Self::Provider),
+            Some(other) => Err(format!(
+                "Invalid sort_by '{}': must be 'timestamp', 'text_length', 'duration' or 'provider'",
+                other
+            )),
+        }
+    }
+
+    // Ascending comparison; pairs without a duration sort before those with one
+    fn compare(&self, a: &StoredPairMetadata, b: &StoredPairMetadata) -> std::cmp::Ordering {
+        match self {
+            Self::Timestamp => a.timestamp.cmp(&b.timestamp),
+            Self::TextLength => a.text.chars().count().cmp(&b.text.chars().count()),
+            Self::Duration => a.duration_ms.cmp(&b.duration_ms),
+            Self::Provider => a.provider.as_str().cmp(b.provider.as_str()),
+        }
+    }
+}
+
+// Whether pairs remain past an offset page. Uses the page bounds, not the number of pairs
+// returned, since pairs with unreadable audio are skipped.
+fn page_has_more(offset: usize, limit: usize, total: usize) -> bool {
+    offset.saturating_add(limit) < total
+}
+
+// History cursors are the last-seen (timestamp, id), base64-encoded to stay opaque
+fn encode_history_cursor(timestamp: &str, id: &str) -> String {
+    BASE64.encode(format!("{}\n{}", timestamp, id))
+}
+
+fn decode_history_cursor(cursor: &str) -> Result<(String, String), String> {
+    BASE64
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|text| {
+            text.split_once('\n')
+                .map(|(timestamp, id)| (timestamp.to_string(), id.to_string()))
+        })
+        .ok_or_else(|| "Invalid cursor".to_string())
+}
+
+// File extension used for a stored pair's audio file
+fn audio_extension(format: &str) -> &'static str {
+    match format {
+        "webm" => "webm",
+        "mp3" => "mp3",
+        "wav" => "wav",
+        "ogg" => "ogg",
+        "flac" => "flac",
+        "aac" => "aac",
+        "m4a" => "m4a",
+        "opus" => "opus",
+        "pcm" => "pcm",
+        _ => "audio",
+    }
+}
+
+// How audio_data travels in requests and responses; stored audio is always raw bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AudioEncoding {
+    Base64,
+    Hex,
+}
+
+impl AudioEncoding {
+    fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("base64") => Ok(Self::Base64),
+            Some("hex") => Ok(Self::Hex),
+            Some(other) => Err(format!(
+                "Invalid encoding '{}': must be 'base64' or 'hex'",
+                other
+            )),
+        }
+    }
+
+    // Response audio, given the base64 used internally
+    fn encode(self, base64_audio: String) -> Result<String, String> {
+        match self {
+            Self::Base64 => Ok(base64_audio),
+            Self::Hex => BASE64
+                .decode(&base64_audio)
+                .map(hex::encode)
+                .map_err(|e| format!("Failed to decode audio data: {}", e)),
+        }
+    }
+
+    // Bytes that encoded request audio decodes to, worked out without decoding it
+    fn decoded_len(self, audio: &str) -> u64 {
+        match self {
+            Self::Base64 => audio.len() as u64 / 4 * 3,
+            Self::Hex => audio.trim().len() as u64 / 2,
+        }
+    }
+
+    // Request audio as the base64 used internally
+    fn to_base64(self, audio: String) -> Result<String, String> {
+        match self {
+            Self::Base64 => Ok(audio),
+            Self::Hex => hex::decode(audio.trim())
+                .map(|bytes| BASE64.encode(bytes))
+                .map_err(|e| format!("Failed to decode hex audio data: {}", e)),
+        }
+    }
+}
+
+// The request one segment is synthesized from
+fn segment_request(request: &TtsReq, segment: &TtsSegment) -> TtsReq {
+    TtsReq {
+        text: segment.text.clone(),
+        voice: segment.voice.clone().or_else(|| request.voice.clone()),
+        locale: segment.language.clone().or_else(|| request.locale.clone()),
+        segments: None,
+        ..request.clone()
+    }
+}
+
+// The text a TTS request asks to have normalized before synthesis, or None to send it as is
+fn spoken_text(request: &TtsReq) -> Option<String> {
+    if request.text.trim_start().starts_with("<speak") {
+        return None;
+    }
+    let locale = request.locale.as_deref().unwrap_or("en-US");
+    if request.normalize == Some(true) {
+        Some(text::normalize(&request.text, locale))
+    } else if request.expand_numbers == Some(true) {
+        Some(text::expand_numbers(&request.text, locale))
+    } else {
+        None
+    }
+}
+
+// Format to report for OpenAI output requested as `format`. OpenAI frames opus in an Ogg
+// container, which browsers only play when it's labelled ogg, so web_compatible relabels
+// it; the pair is then stored with an .ogg extension. Bare opus packets have lost their
+// boundaries and can't be wrapped after the fact.
+fn openai_output_format(
+    format: &str,
+    audio: &[u8],
+    web_compatible: bool,
+) -> Result<String, String> {
+    if format != "opus" || !web_compatible {
+        return Ok(format.to_string());
+    }
+    if audio::sniff_format(audio) != Some("ogg") {
+        return Err(
+            "OpenAI returned opus without an Ogg container; retry without web_compatible"
+                .to_string(),
+        );
+    }
+    Ok("ogg".to_string())
+}
+
+// Extension used for all formats before per-format extensions were added
+const LEGACY_AUDIO_EXTENSION: &str = "audio";
+
+// Drive pairs are stored in unless set_storage_drive picks another
+const DEFAULT_STORAGE_DRIVE: &str = "audio_pairs";
+
+// Drive holding soft-deleted pairs, shared by every storage drive; history never lists it.
+// Restored pairs go to the current storage drive.
+const TRASH_DRIVE: &str = "audio_pairs_trash";
+
+// Drive holding the append-only audit log
+const AUDIT_DRIVE: &str = "audit";
+const AUDIT_LOG_FILE: &str = "log.jsonl";
+
+// Leading bytes of every gzip stream, used to recognize compressed history archives
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Largest slice get_audio_range returns in one call
+const MAX_AUDIO_RANGE_BYTES: u64 = 8 * 1024 * 1024;
+
+// How long an unused audio token stays valid
+const AUDIO_TOKEN_TTL_MINUTES: i64 = 5;
+
+// Suffix for in-flight writes; leftovers are removed on init
+const TMP_FILE_SUFFIX: &str = ".tmp";
+
+// Storage limits
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub enum EvictionPolicy {
+    #[default]
+    Fifo, // Oldest created first
+    Lru, // Least recently accessed first
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetStorageLimitsReq {
+    api_key: Option<String>,
+    max_total_bytes: Option<u64>, // None removes the ceiling
+    eviction_policy: Option<EvictionPolicy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetRetentionReq {
+    api_key: Option<String>,
+    retention_days: Option<u32>, // None disables pruning
+}
+
+// Log verbosity; each level includes the ones above it
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug, // Adds full provider error details
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetLogLevelReq {
+    api_key: Option<String>,
+    level: LogLevel,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetPreflightOnInitReq {
+    api_key: Option<String>,
+    enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetMaxTextCharsReq {
+    api_key: Option<String>,
+    max_text_chars: Option<u32>, // None restores each provider's own limit
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetMaxAudioBytesReq {
+    api_key: Option<String>,
+    max_audio_bytes: Option<u64>, // None restores the 25MB default
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetAudioCompressionReq {
+    api_key: Option<String>,
+    enabled: bool, // Compress wav/pcm audio losslessly before it is written to VFS
+}
+
+// Usage counters, one per provider and operation
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UsageCounter {
+    provider: Provider,
+    request_type: RequestType,
+    requests: u64,
+    units: u64, // Characters synthesized for TTS, audio bytes submitted for STT
+    #[serde(default)]
+    spend_usd: f64, // Estimated from the same pricing tables as estimate_tts_cost
+}
+
+// In-flight provider calls, for tuning concurrency limits
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConcurrencyStats {
+    provider: Provider,
+    current_concurrent: u32,
+    peak_concurrent: u32, // Highest in-flight count seen since the counters were created
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageStats {
+    counters: Vec<UsageCounter>,
+    concurrency: Vec<ConcurrencyStats>,
+    max_concurrent_calls: Option<u32>, // Limit across all providers; None is unlimited
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetMaxConcurrentCallsReq {
+    api_key: Option<String>,
+    max_concurrent_calls: Option<u32>, // None removes the limit
+}
+
+// Which backend a client is talking to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppInfo {
+    node: String,
+    package_id: String,
+    version: String,
+    wit_world: String,
+    provider_count: u32, // Configured provider profiles
+}
+
+// Must match the `wit_world` given to hyperprocess below
+const WIT_WORLD: &str = "ttstt-dot-os-v0";
+
+// Health of the drive backing history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageStatus {
+    available: bool,
+    error: Option<String>, // Why storage was last found unusable
+    pair_count: u32,
+    total_bytes: u64,
+    drive: String,
+    max_total_bytes: Option<u64>, // Capacity ceiling from set_storage_limits
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetStorageDriveReq {
+    api_key: Option<String>,
+    drive: Option<String>, // None restores DEFAULT_STORAGE_DRIVE
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyHistoryReq {
+    api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyHistoryRes {
+    checked: u32,
+    unverified: u32, // Pairs saved before checksums were recorded; read but not compared
+    failures: Vec<(String, String)>, // (pair directory, error) for unreadable or corrupt pairs
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryStorageInitReq {
+    api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetUsageReq {
+    api_key: Option<String>,
+}
+
+// A processed tts/stt request, remembered so retries with the same key skip the provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IdempotencyEntry {
+    key: String,
+    api_key: Option<String>, // Keys are scoped to the caller that sent them
+    request_type: RequestType,
+    pair_id: String,
+    created_at: String,
+}
+
+// Per-pair bookkeeping kept in state so storage limits don't require a VFS walk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PairIndexEntry {
+    id: String,
+    timestamp: String,
+    size_bytes: u64,
+    last_accessed: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    content_hash: Option<String>,
+    #[serde(default)]
+    is_test: bool,
+    #[serde(default)]
+    metadata_keys: Vec<String>, // Distinct, for list_metadata_keys
+    #[serde(default)]
+    provider: Option<Provider>, // Only None in indexes older than version 5
+    #[serde(default)]
+    request_type: Option<RequestType>,
+}
+
+// Bump when PairIndexEntry gains fields derived from stored metadata, forcing a rebuild
+const PAIR_INDEX_VERSION: u32 = 5;
+
+// Metadata marking pairs created by the test endpoints, which history can exclude
+const SOURCE_METADATA_KEY: &str = "source";
+const TEST_SOURCE: &str = "test";
+
+fn is_test_pair(metadata: &[(String, String)]) -> bool {
+    metadata
+        .iter()
+        .any(|(k, v)| k == SOURCE_METADATA_KEY && v == TEST_SOURCE)
+}
+
+// Metadata key whose values are treated as tags
+const TAG_METADATA_KEY: &str = "tag";
+
+// Metadata key holding a TTS pair's content hash, used to skip saving duplicates
+const CONTENT_HASH_METADATA_KEY: &str = "content_hash";
+
+fn content_hash_from_metadata(metadata: &[(String, String)]) -> Option<String> {
+    metadata
+        .iter()
+        .find(|(k, _)| k == CONTENT_HASH_METADATA_KEY)
+        .map(|(_, v)| v.clone())
+}
+
+// Everything that shapes a TTS pair's audio bytes
+struct ContentHashInput<'a> {
+    provider: &'a Provider,
+    audio_format: &'a str,
+    text: &'a str, // As sent to the provider, i.e. after any expansion or normalization
+    voice: Option<&'a str>,
+    model: Option<&'a str>,
+    speed: Option<f32>,
+    profile: Option<&'a str>, // Resolved profile, which also picks the base_url
+    sample_rate: Option<u32>,
+    bit_depth: Option<u16>,
+}
+
+// Identifies TTS output by everything that shapes it. Text is whitespace-normalized; the
+// hash is FNV-1a so values persisted in metadata stay stable across builds. Fields added
+// later only take part when set, so hashes stored before them still match.
+fn content_hash(input: &ContentHashInput) -> String {
+    let text = input.text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let speed = input.speed.map(|s| s.to_string());
+    let mut fields = vec![
+        input.provider.as_str().to_string(),
+        input.audio_format.to_string(),
+        text,
+        input.voice.unwrap_or("").to_string(),
+        input.model.unwrap_or("").to_string(),
+        speed.unwrap_or_default(),
+    ];
+    if let Some(profile) = input.profile {
+        fields.push(format!("profile={}", profile));
+    }
+    if let Some(sample_rate) = input.sample_rate {
+        fields.push(format!("sample_rate={}", sample_rate));
+    }
+    if let Some(bit_depth) = input.bit_depth {
+        fields.push(format!("bit_depth={}", bit_depth));
+    }
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for field in &fields {
+        for byte in field.bytes().chain(std::iter::once(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+fn tags_from_metadata(metadata: &[(String, String)]) -> Vec<String> {
+    metadata
+        .iter()
+        .filter(|(k, _)| k == TAG_METADATA_KEY)
+        .map(|(_, v)| v.clone())
+        .collect()
+}
+
+fn metadata_keys(metadata: &[(String, String)]) -> Vec<String> {
+    let mut keys: Vec<String> = metadata.iter().map(|(k, _)| k.clone()).collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListTagsReq {
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagCount {
+    tag: String,
+    count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListMetadataKeysReq {
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataKeyCount {
+    key: String,
+    count: u32, // Pairs with at least one entry under this key
+}
+
+// App State
+#[derive(Default, Serialize, Deserialize)]
+pub struct TtsttState {
+    // Provider configurations
+    providers: Vec<ProviderConfig>,
+
+    // TTSTT API keys
+    api_keys: Vec<ApiKey>,
+
+    // Result of the last key check per (provider, profile); runtime-only, and absent
+    // until a check has run
+    #[serde(skip)]
+    provider_health: Vec<(Provider, String, bool)>,
+    #[serde(default)]
+    preflight_on_init: bool, // Check every stored key with a cheap provider call on init
+
+    // Settings
+    default_tts_provider: Option<Provider>,
+    default_stt_provider: Option<Provider>,
+    #[serde(default)]
+    tts_defaults: TtsDefaults,
+    #[serde(default)]
+    strict_speed: bool, // Out-of-range TTS speeds error instead of being clamped
+    #[serde(default)]
+    voice_aliases: Vec<VoiceAlias>,
+
+    // Admin key (generated on first init)
+    admin_key: String,
+    #[serde(default)]
+    admin_key_retrieved: bool, // Set once get_admin_key has handed the key out
+
+    // Storage path for audio-text pairs
+    storage_initialized: bool,
+    #[serde(default)]
+    storage_drive: Option<String>, // None uses DEFAULT_STORAGE_DRIVE
+    // Runtime health of that storage, probed on init, after failed saves and on retry.
+    // History reads and saves refuse to run while it is false.
+    #[serde(skip)]
+    storage_available: bool,
+    #[serde(skip)]
+    storage_error: Option<String>,
+
+    // Optional STT post-processing webhook
+    #[serde(default)]
+    transcript_webhook: Option<TranscriptWebhook>,
+
+    // Local STT service; with the `local-stt` feature it also backs up cloud STT once
+    // every cloud provider is unhealthy
+    #[serde(default)]
+    local_stt: Option<LocalSttConfig>,
+
+    // Storage ceiling across all pairs, enforced on save
+    #[serde(default)]
+    max_total_bytes: Option<u64>,
+    #[serde(default)]
+    eviction_policy: EvictionPolicy,
+
+    // Pairs older than this are pruned on init and after each save; None keeps everything
+    #[serde(default)]
+    retention_days: Option<u32>,
+
+    // TTS input ceiling in characters; None uses the provider's own limit, if it has one
+    #[serde(default)]
+    max_text_chars: Option<u32>,
+
+    // STT upload ceiling in decoded bytes; None uses DEFAULT_MAX_AUDIO_BYTES
+    #[serde(default)]
+    max_audio_bytes: Option<u64>,
+
+    // Transparently compress uncompressed audio (wav/pcm) on save
+    #[serde(default)]
+    compress_audio: bool,
+
+    // Lines below this level are dropped by `log`
+    #[serde(default)]
+    log_level: LogLevel,
+
+    // Completed requests per provider; only updated through `record_usage`
+    #[serde(default)]
+    usage: Vec<UsageCounter>,
+    #[serde(default)]
+    peak_concurrent: Vec<(Provider, u32)>,
+    // Provider calls currently awaiting a response; runtime-only so a save mid-call
+    // can't persist a count that never drains
+    #[serde(skip)]
+    in_flight: Vec<(Provider, u32)>,
+    // Provider calls allowed in flight at once across all providers; extra requests queue
+    #[serde(default)]
+    max_concurrent_calls: Option<u32>,
+    // Counters for `metrics`, reset on restart as Prometheus counters expect
+    #[serde(skip)]
+    request_counts: Vec<(RequestType, u64)>,
+    #[serde(skip)]
+    provider_errors: Vec<(Provider, RequestType, u64)>,
+
+    // Recently processed idempotency keys, expired after IDEMPOTENCY_TTL_HOURS
+    #[serde(default)]
+    idempotency_keys: Vec<IdempotencyEntry>,
+
+    // Per-key rate limit buckets; runtime-only, so every key starts with a full bucket on init
+    #[serde(skip)]
+    rate_buckets: HashMap<String, RateBucket>,
+
+    // Unredeemed audio tokens by token; runtime-only, so a restart revokes them all
+    #[serde(skip)]
+    audio_tokens: HashMap<String, AudioToken>,
+
+    // Open stt_stream sessions by id; only the final pair is ever persisted
+    #[serde(skip)]
+    stt_streams: HashMap<String, SttStream>,
+
+    // Pairs whose audio is still being written in the background; shared with the writer
+    // task, which removes its pair once the write has finished or failed
+    #[serde(skip)]
+    audio_writes: Rc<RefCell<HashSet<String>>>,
+
+    // Index of stored pairs (rebuilt from VFS on init if empty or outdated)
+    #[serde(default)]
+    pair_index: Vec<PairIndexEntry>,
+    #[serde(default)]
+    pair_index_version: u32,
+}
+
+// Assumed STT upload format when the bytes aren't recognized (browser recordings)
+const DEFAULT_STT_AUDIO_FORMAT: &str = "webm";
+
+// Containers an STT request may declare in `format`
+const STT_AUDIO_FORMATS: [&str; 7] = ["webm", "wav", "mp3", "m4a", "mp4", "ogg", "flac"];
+
+const DEFAULT_WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+// Largest decoded STT upload accepted by default (OpenAI's transcription limit)
+const DEFAULT_MAX_AUDIO_BYTES: u64 = 25 * 1024 * 1024;
+const CALLBACK_ATTEMPTS: u32 = 3;
+// Wait before the first callback retry, doubled for each retry after it
+const CALLBACK_BACKOFF_MS: u64 = 2_000;
+
+const MAX_STT_BATCH_ITEMS: usize = 20;
+// Provider calls a batch keeps in flight at once unless its options say otherwise
+const DEFAULT_STT_BATCH_CONCURRENCY: u32 = 4;
+
+// How long a request queues for a provider call slot, and how often it checks
+const QUEUE_TIMEOUT_SECS: i64 = 120;
+const QUEUE_POLL_MS: u64 = 100;
+// How long a change to a pair waits for its background audio write before giving up
+const AUDIO_WRITE_TIMEOUT_SECS: i64 = 30;
+
+// stt_stream sessions idle this long are dropped along with their audio
+const STT_STREAM_IDLE_MINUTES: i64 = 10;
+const MAX_STT_STREAMS: usize = 32;
+
+const IDEMPOTENCY_TTL_HOURS: i64 = 24;
+const MAX_IDEMPOTENCY_KEYS: usize = 1000;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+// Checked on init when no OpenAI provider is configured
+const OPENAI_API_KEY_ENV: &str = "OPENAI_API_KEY";
+
+// Kept short so an offline node isn't held up on init for long
+const PREFLIGHT_TIMEOUT_SECS: u64 = 5;
+
+// Profile name for configs that predate profiles or don't set one
+const DEFAULT_PROFILE_NAME: &str = "default";
+
+fn default_profile_name() -> String {
+    DEFAULT_PROFILE_NAME.to_string()
+}
+
+// Response headers returned as raw details when a request sets include_raw
+const OPENAI_RAW_HEADERS: [&str; 4] = [
+    "x-request-id",
+    "openai-model",
+    "openai-processing-ms",
+    "openai-version",
+];
+#[cfg(feature = "local-stt")]
+const DEFAULT_LOCAL_STT_TIMEOUT_SECS: u64 = 120;
+
+// Limits on client-supplied request metadata
+const MAX_REQUEST_METADATA_ENTRIES: usize = 32;
+const MAX_REQUEST_METADATA_KEY_LEN: usize = 64;
+const MAX_REQUEST_METADATA_VALUE_LEN: usize = 1024;
+
+const PLAYAI_TTS_STREAM_URL: &str = "https://api.play.ht/api/v2/tts/stream";
+const PLAYAI_VOICES_URL: &str = "https://api.play.ht/api/v2/voices";
+const PLAYAI_VOICE_JENNIFER: &str =
+    "s3://voice-cloning-zero-shot/775ae416-49bb-4fb6-bd45-740f205d20a1/jennifersaad/manifest.json";
+
+const AZURE_DEFAULT_VOICE: &str = "en-US-JennyNeural";
+
+const POLLY_DEFAULT_VOICE: &str = "Joanna";
+const AZURE_STT_API_VERSION: &str = "2024-11-15";
+
+// USD per million input characters. Keys are the model names `handle_openai_tts` accepts;
+// gpt-4o-mini-tts is billed per token, so its entry is OpenAI's per-character estimate.
+const OPENAI_TTS_PRICING: [(&str, f32); 3] = [
+    ("tts-1", 15.0),
+    ("tts-1-hd", 30.0),
+    ("gpt-4o-mini-tts", 12.0),
+];
+const DEFAULT_OPENAI_TTS_MODEL: &str = "gpt-4o-mini-tts";
+const AZURE_NEURAL_TTS_USD_PER_M_CHARS: f32 = 15.0;
+
+// USD per million characters, keyed by the Polly engine names `handle_polly_tts` accepts as `model`
+const POLLY_TTS_PRICING: [(&str, f32); 4] = [
+    ("standard", 4.0),
+    ("neural", 16.0),
+    ("long-form", 100.0),
+    ("generative", 30.0),
+];
+const DEFAULT_POLLY_ENGINE: &str = "neural";
+
+// USD per audio minute, keyed like the `handle_openai_stt` model arms
+const OPENAI_STT_PRICING: [(&str, f32); 3] = [
+    ("whisper-1", 0.006),
+    ("gpt-4o-transcribe", 0.006),
+    ("gpt-4o-mini-transcribe", 0.003),
+];
+const DEFAULT_OPENAI_STT_MODEL: &str = "whisper-1";
+// The only model OpenAI's translations endpoint accepts
+const OPENAI_TRANSLATION_MODEL: &str = "whisper-1";
+const OPENAI_API_BASE_URL: &str = "https://api.openai.com/v1";
+const AZURE_STT_USD_PER_MINUTE: f32 = 1.0 / 60.0;
+// Used to estimate STT minutes when the container has no parseable duration
+const STT_ASSUMED_BITRATE_KBPS: u64 = 64;
+
+// Output formats OpenAI synthesizes. Unset or unknown formats fall back to the default,
+// unless the app-level TtsDefaults::format was applied first.
+const OPENAI_TTS_FORMATS: &[&str] = &["mp3", "opus", "aac", "flac", "wav", "pcm"];
+const DEFAULT_TTS_FORMAT: &str = "mp3";
+
+// Requested format as OpenAI will produce it. Both the enum sent to OpenAI and the format
+// reported back are derived from this, so they can't disagree: an unrecognized request
+// such as "ogg" is reported, and stored with an extension, as the mp3 it produced.
+fn openai_tts_format_name(format: Option<&str>) -> &'static str {
+    OPENAI_TTS_FORMATS
+        .iter()
+        .copied()
+        .find(|name| Some(*name) == format)
+        .unwrap_or(DEFAULT_TTS_FORMAT)
+}
+
+// The enum sent to OpenAI alongside the format name it produces
+fn openai_tts_format(format: Option<&str>) -> (&'static str, OpenAIAudioFormat) {
+    let name = openai_tts_format_name(format);
+    let format = match name {
+        "opus" => OpenAIAudioFormat::Opus,
+        "aac" => OpenAIAudioFormat::Aac,
+        "flac" => OpenAIAudioFormat::Flac,
+        "wav" => OpenAIAudioFormat::Wav,
+        "pcm" => OpenAIAudioFormat::Pcm,
+        _ => OpenAIAudioFormat::Mp3,
+    };
+    (name, format)
+}
+
+// Content-Type for audio served as raw bytes
+fn audio_mime_type(format: &str) -> &'static str {
+    match format {
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" | "opus" => "audio/ogg",
+        "flac" => "audio/flac",
+        "aac" => "audio/aac",
+        "m4a" | "mp4" => "audio/mp4",
+        "webm" => "audio/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+// Canonical OpenAI model for a requested name; unknown names use the default
+fn openai_tts_model_name(model: Option<&str>) -> &'static str {
+    OPENAI_TTS_PRICING
+        .iter()
+        .map(|(name, _)| *name)
+        .find(|name| Some(*name) == model)
+        .unwrap_or(DEFAULT_OPENAI_TTS_MODEL)
+}
+
+fn openai_stt_model_name(model: Option<&str>) -> &'static str {
+    OPENAI_STT_PRICING
+        .iter()
+        .map(|(name, _)| *name)
+        .find(|name| Some(*name) == model)
+        .unwrap_or(DEFAULT_OPENAI_STT_MODEL)
+}
+
+fn polly_engine_name(model: Option<&str>) -> &'static str {
+    POLLY_TTS_PRICING
+        .iter()
+        .map(|(name, _)| *name)
+        .find(|name| Some(*name) == model)
+        .unwrap_or(DEFAULT_POLLY_ENGINE)
+}
+
+// Polly voice ids are capitalized names ("Joanna", "Matthew"); accept any casing
+fn polly_voice_id(voice: &str) -> String {
+    let mut chars = voice.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => POLLY_DEFAULT_VOICE.to_string(),
+    }
+}
+
+// Accept full Azure neural voice names as-is and map a few short names onto them
+fn azure_voice_name(voice: &str) -> &str {
+    if voice.ends_with("Neural") {
+        return voice;
+    }
+    match voice {
+        "jenny" => "en-US-JennyNeural",
+        "guy" => "en-US-GuyNeural",
+        "aria" => "en-US-AriaNeural",
+        "davis" => "en-US-DavisNeural",
+        "sonia" => "en-GB-SoniaNeural",
+        "ryan" => "en-GB-RyanNeural",
+        _ => AZURE_DEFAULT_VOICE,
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Build a multipart/form-data body; each part is (name, filename, content type, bytes)
+fn multipart_body(boundary: &str, parts: &[(&str, Option<&str>, &str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, filename, content_type, bytes) in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        match filename {
+            Some(filename) => body.extend_from_slice(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                    name, filename
+                )
+                .as_bytes(),
+            ),
+            None => body.extend_from_slice(
+                format!("Content-Disposition: form-data; name=\"{}\"\r\n", name).as_bytes(),
+            ),
+        }
+        body.extend_from_slice(format!("Content-Type: {}\r\n\r\n", content_type).as_bytes());
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+// Last-resort TTS speed. tts() resolves the request speed, then the provider's
+// default_speed, then the app-level TtsDefaults::speed; this only applies when none is set.
+const DEFAULT_TTS_SPEED: f32 = 1.0;
+
+// Keep a TTS speed inside the provider's accepted range, clamping unless `strict`
+fn check_speed(provider: &Provider, speed: f32, strict: bool) -> Result<f32, String> {
+    let Some((min, max)) = provider.speed_range() else {
+        return Ok(speed);
+    };
+
+    if speed.is_nan() || (strict && !(min..=max).contains(&speed)) {
+        return Err(format!("speed must be between {} and {}", min, max));
+    }
+
+    Ok(speed.clamp(min, max))
+}
+
+// STT audio format: as declared by the caller, else sniffed, else the browser default
+fn stt_audio_format<'a>(declared: Option<&'a str>, audio: &[u8]) -> &'a str {
+    declared
+        .or_else(|| audio::sniff_format(audio))
+        .unwrap_or(DEFAULT_STT_AUDIO_FORMAT)
+}
+
+// Upload filename for STT audio; some providers dispatch on the extension
+fn stt_upload_filename(declared: Option<&str>, audio: &[u8]) -> String {
+    format!("audio.{}", stt_audio_format(declared, audio))
+}
+
+// Show only the last 4 characters of a secret, e.g. "****abcd"
+fn mask_secret(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    let visible = chars.len().saturating_sub(4);
+    // Short secrets are masked entirely rather than mostly revealed
+    if visible < 4 {
+        return "*".repeat(chars.len());
+    }
+    let tail: String = chars[visible..].iter().collect();
+    format!("{}{}", "*".repeat(visible), tail)
+}
+
+fn validate_idempotency_key(key: &Option<String>) -> Result<(), String> {
+    match key {
+        Some(key) if key.trim().is_empty() => Err("idempotency_key must not be empty".to_string()),
+        Some(key) if key.len() > MAX_IDEMPOTENCY_KEY_LEN => Err(format!(
+            "idempotency_key exceeds {} bytes",
+            MAX_IDEMPOTENCY_KEY_LEN
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Pair ids become VFS path components, so only allow plain identifiers
+fn is_valid_pair_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Validate client-supplied metadata before it is merged into a stored pair
+fn validate_request_metadata(metadata: &Option<Vec<(String, String)>>) -> Result<(), String> {
+    let Some(entries) = metadata else {
+        return Ok(());
+    };
+
+    if entries.len() > MAX_REQUEST_METADATA_ENTRIES {
+        return Err(format!(
+            "metadata has {} entries; at most {} are allowed",
+            entries.len(),
+            MAX_REQUEST_METADATA_ENTRIES
+        ));
+    }
+
+    for (key, value) in entries {
+        if key.trim().is_empty() {
+            return Err("metadata keys must not be empty".to_string());
+        }
+        if key.len() > MAX_REQUEST_METADATA_KEY_LEN {
+            return Err(format!(
+                "metadata key '{}' exceeds {} bytes",
+                key, MAX_REQUEST_METADATA_KEY_LEN
+            ));
+        }
+        if value.len() > MAX_REQUEST_METADATA_VALUE_LEN {
+            return Err(format!(
+                "metadata value for '{}' exceeds {} bytes",
+                key, MAX_REQUEST_METADATA_VALUE_LEN
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// VFS helper: rename a file or directory in place
+fn rename_vfs_path(from: &str, to: &str) -> Result<(), String> {
+    let message = vfs_request(
+        from,
+        VfsAction::Rename {
+            new_path: to.to_string(),
+        },
+    )
+    .send_and_await_response(5)
+    .map_err(|e| format!("Failed to send rename request: {:?}", e))?
+    .map_err(|e| format!("Rename request failed: {:?}", e))?;
+
+    match parse_response(message.body()) {
+        Ok(VfsResponse::Ok) => Ok(()),
+        Ok(VfsResponse::Err(e)) => Err(format!("Failed to rename {} to {}: {:?}", from, to, e)),
+        Ok(other) => Err(format!("Unexpected rename response: {:?}", other)),
+        Err(e) => Err(format!("Failed to parse rename response: {:?}", e)),
+    }
+}
+
+// Write to a uniquely named temp file next to `path`, then rename it into place so
+// readers never observe a partially written file
+async fn write_file_atomic(path: &str, bytes: &[u8]) -> Result<(), String> {
+    let tmp_path = format!("{}.{}{}", path, Uuid::new_v4(), TMP_FILE_SUFFIX);
+    let tmp_file = create_file_async(&tmp_path, Some(5))
+        .await
+        .map_err(|e| format!("Failed to create {}: {:?}", tmp_path, e))?;
+
+    if let Err(e) = tmp_file.write(bytes).await {
+        let _ = remove_file_async(&tmp_path, Some(5)).await;
+        return Err(format!("Failed to write {}: {:?}", tmp_path, e));
+    }
+
+    rename_vfs_path(&tmp_path, path)
+}
+
+// Poll every future until all finish, returning each output with the future's position,
+// in completion order. Futures that block the thread while waiting still run one at a time.
+async fn join_unordered<F: Future>(futures: Vec<F>) -> Vec<(usize, F::Output)> {
+    let mut pending: Vec<(usize, Pin<Box<F>>)> =
+        futures.into_iter().map(Box::pin).enumerate().collect();
+    let mut done = Vec::with_capacity(pending.len());
+    std::future::poll_fn(|cx| {
+        let mut i = 0;
+        while i < pending.len() {
+            match pending[i].1.as_mut().poll(cx) {
+                Poll::Ready(output) => {
+                    let (position, _) = pending.swap_remove(i);
+                    done.push((position, output));
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        if pending.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    done
+}
+
+// Second half of save_audio_text_pair: write the audio, then clear the pair's pending
+// flag. Runs after the response has gone out, so failures can only be logged; the pair
+// keeps its pending flag, reads of it fail, and prepare_storage trashes it.
+async fn write_pending_audio(
+    id: String,
+    base_path: String,
+    audio_path: String,
+    audio: Vec<u8>,
+    audio_writes: Rc<RefCell<HashSet<String>>>,
+) {
+    let result = async {
+        write_file_atomic(&audio_path, &audio).await?;
+
+        let metadata_path = format!("{}/metadata.json", base_path);
+        let metadata_str = open_file_async(&metadata_path, false, Some(5))
+            .await
+            .map_err(|e| format!("Failed to open metadata file: {:?}", e))?
+            .read_to_string()
+            .await
+            .map_err(|e| format!("Failed to read metadata: {:?}", e))?;
+        let mut metadata: StoredPairMetadata = serde_json::from_str(&metadata_str)
+            .map_err(|e| format!("Failed to parse metadata: {}", e))?;
+        metadata.pending = false;
+        let metadata_json = serde_json::to_string(&metadata)
+            .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+        write_file_atomic(&metadata_path, metadata_json.as_bytes()).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to write audio for {}: {}", base_path, e);
+    }
+    audio_writes.borrow_mut().remove(&id);
+}
+
+// Deliver a completion callback, backing off between failed attempts. Spawned by tts(),
+// so delivery never delays or fails the request.
+async fn send_completion_callback(url: String, callback: CompletionCallback) {
+    let mut backoff_ms = CALLBACK_BACKOFF_MS;
+    for attempt in 1..=CALLBACK_ATTEMPTS {
+        if attempt > 1 {
+            if let Err(e) = sleep(backoff_ms).await {
+                eprintln!("Failed to wait before retrying callback: {:?}", e);
+            }
+            backoff_ms *= 2;
+        }
+        match post_json(&url, &callback, DEFAULT_WEBHOOK_TIMEOUT_SECS) {
+            Ok(_) => {
+                println!(
+                    "Delivered completion callback for {} (attempt {})",
+                    callback.id, attempt
+                );
+                return;
+            }
+            Err(e) => eprintln!(
+                "Completion callback for {} failed (attempt {}/{}): {}",
+                callback.id, attempt, CALLBACK_ATTEMPTS, e
+            ),
+        }
+    }
+    eprintln!(
+        "Giving up on completion callback for {} after {} attempts",
+        callback.id, CALLBACK_ATTEMPTS
+    );
+}
+
+// HTTP helper: POST a JSON body and return the response body on success
+fn post_json<T: Serialize>(url: &str, body: &T, timeout_secs: u64) -> Result<Vec<u8>, String> {
+    let body =
+        serde_json::to_vec(body).map_err(|e| format!("Failed to serialize request: {}", e))?;
+    let headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+
+    post_bytes(url, headers, body, timeout_secs)
+}
+
+// The pair an STT result is saved as. A post-processed transcript replaces the response
+// text, so the same text is stored and returned; the raw one is kept in metadata.
+fn transcript_pair(
+    id: &str,
+    audio_data: &str,
+    audio_format: &str,
+    response: &mut SttRes,
+    mut metadata: Vec<(String, String)>,
+    processed: Option<String>,
+) -> AudioTextPair {
+    if let Some(processed) = processed {
+        let raw = std::mem::replace(&mut response.text, processed);
+        metadata.push(("raw_transcript".to_string(), raw));
+    }
+
+    AudioTextPair {
+        id: id.to_string(),
+        text: response.text.clone(),
+        audio_data: audio_data.to_string(),
+        audio_format: audio_format.to_string(),
+        provider: response.provider.clone(),
+        timestamp: Utc::now().to_rfc3339(),
+        request_type: RequestType::STT,
+        metadata,
+        duration_ms: None,
+        audio_info: response.audio_info.clone(),
+    }
+}
+
+// One exchange with a transcript webhook: `post` sends the JSON request body and returns
+// the response body, from which the replacement text is read
+fn call_transcript_webhook(
+    text: &str,
+    language: Option<&str>,
+    post: impl FnOnce(Vec<u8>) -> Result<Vec<u8>, String>,
+) -> Result<String, String> {
+    #[derive(Serialize)]
+    struct WebhookReq<'a> {
+        text: &'a str,
+        language: Option<&'a str>,
+    }
+
+    #[derive(Deserialize)]
+    struct WebhookRes {
+        text: String,
+    }
+
+    let body = serde_json::to_vec(&WebhookReq { text, language })
+        .map_err(|e| format!("Failed to serialize request: {}", e))?;
+    let response = post(body)?;
+    serde_json::from_slice::<WebhookRes>(&response)
+        .map(|res| res.text)
+        .map_err(|e| format!("Failed to parse webhook response: {}", e))
+}
+
+// HTTP helper: POST a raw body with the given headers and return the response body on success
+fn post_bytes(
+    url: &str,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+    timeout_secs: u64,
+) -> Result<Vec<u8>, String> {
+    post_bytes_with_headers(url, headers, body, timeout_secs).map(|(_, body)| body)
+}
+
+// Like post_bytes, but also returns the response headers (names lowercased)
+fn post_bytes_with_headers(
+    url: &str,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+    timeout_secs: u64,
+) -> Result<(Vec<(String, String)>, Vec<u8>), String> {
+    send_with_headers(Method::POST, url, headers, body, timeout_secs)
+}
+
+// HTTP helper: GET with the given headers and return the response body on success
+fn get_bytes(
+    url: &str,
+    headers: HashMap<String, String>,
+    timeout_secs: u64,
+) -> Result<Vec<u8>, String> {
+    send_with_headers(Method::GET, url, headers, Vec::new(), timeout_secs).map(|(_, body)| body)
+}
+
+fn send_with_headers(
+    method: Method,
+    url: &str,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+    timeout_secs: u64,
+) -> Result<(Vec<(String, String)>, Vec<u8>), String> {
+    let url = url::Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+
+    let response = send_request_await_response(method, url, Some(headers), timeout_secs, body)
+        .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+
+    // Quota exhaustion is also a 429 at OpenAI, but waiting won't fix it
+    let body_text = String::from_utf8_lossy(response.body());
+    if response.status().as_u16() == 429 && !body_text.contains("insufficient_quota") {
+        let retry_after = response
+            .headers()
+            .get("retry-after")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        return Err(provider_rate_limit_error(retry_after));
+    }
+
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}: {}", response.status(), body_text));
+    }
+
+    let response_headers = response
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|value| (name.as_str().to_string(), value.to_string()))
+        })
+        .collect();
+
+    Ok((response_headers, response.into_body()))
+}
+
+// Back-off suggested when a provider rate limits without sending Retry-After
+const DEFAULT_PROVIDER_RETRY_AFTER_SECS: u64 = 5;
+
+// Provider 429s are worded like the per-key limiter's errors, so clients can look for
+// "rate limit exceeded, retry after {n}s" whichever side limited them
+fn provider_rate_limit_error(retry_after_secs: Option<u64>) -> String {
+    format!(
+        "provider rate limit exceeded, retry after {}s",
+        retry_after_secs
+            .unwrap_or(DEFAULT_PROVIDER_RETRY_AFTER_SECS)
+            .max(1)
+    )
+}
+
+// Retry-After holds either a delay in seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(secs);
+    }
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|at| (at.with_timezone(&Utc) - Utc::now()).num_seconds().max(0) as u64)
+}
+
+// The OpenAI client only exposes errors through Debug, without headers, so rate limits
+// are recognized by OpenAI's error code and get the default back-off
+fn openai_client_error(context: &str, debug: String) -> String {
+    if debug.contains("rate_limit_exceeded") {
+        return provider_rate_limit_error(None);
+    }
+    format!("{}: {}", context, debug)
+}
+
+// POST a JSON body to a webhook provider's `{base_url}/{operation}` and parse the reply
+fn webhook_post(
+    config: &ProviderConfig,
+    operation: &str,
+    body: Vec<u8>,
+) -> Result<serde_json::Value, String> {
+    let base_url = config
+        .base_url
+        .as_deref()
+        .ok_or("Webhook requires base_url in the provider config")?;
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), operation);
+
+    let mut headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+    if !config.api_key.is_empty() {
+        headers.insert(
+            "Authorization".to_string(),
+            format!("Bearer {}", config.api_key),
+        );
+    }
+
+    let response = post_bytes(&url, headers, body, 60)
+        .map_err(|e| format!("Webhook {} error: {}", operation, e))?;
+    serde_json::from_slice(&response)
+        .map_err(|e| format!("Failed to parse webhook {} response: {}", operation, e))
+}
+
+// Field of a JSON value by dotted path, e.g. "result.text"
+fn json_field<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
+}
+
+// Cheapest authenticated call for each provider, used to check stored keys on init.
+// None means the provider has no check (AwsPolly, Local).
+fn preflight_check(config: &ProviderConfig) -> Option<Result<(), String>> {
+    let (url, headers) = match config.provider {
+        Provider::OpenAI => (
+            format!(
+                "{}/models",
+                config
+                    .base_url
+                    .as_deref()
+                    .unwrap_or(OPENAI_API_BASE_URL)
+                    .trim_end_matches('/')
+            ),
+            HashMap::from([(
+                "Authorization".to_string(),
+                format!("Bearer {}", config.api_key),
+            )]),
+        ),
+        Provider::PlayAI => (
+            PLAYAI_VOICES_URL.to_string(),
+            HashMap::from([
+                ("Authorization".to_string(), config.api_key.clone()),
+                (
+                    "X-User-Id".to_string(),
+                    config.user_id.clone().unwrap_or_default(),
+                ),
+            ]),
+        ),
+        Provider::Azure => {
+            let Some(region) = &config.region else {
+                return Some(Err("no region configured".to_string()));
+            };
+            (
+                format!(
+                    "https://{}.tts.speech.microsoft.com/cognitiveservices/voices/list",
+                    region
+                ),
+                HashMap::from([(
+                    "Ocp-Apim-Subscription-Key".to_string(),
+                    config.api_key.clone(),
+                )]),
+            )
+        }
+        Provider::AwsPolly | Provider::Local | Provider::Webhook => return None,
+    };
+
+    Some(get_bytes(&url, headers, PREFLIGHT_TIMEOUT_SECS).map(|_| ()))
+}
+
+// Parse the local service's `{"text": ...}` reply; results are always marked Local
+#[cfg_attr(not(feature = "local-stt"), allow(dead_code))]
+fn local_stt_response(body: &[u8]) -> Result<SttRes, String> {
+    #[derive(Deserialize)]
+    struct LocalSttRes {
+        text: String,
+    }
+
+    let response: LocalSttRes = serde_json::from_slice(body)
+        .map_err(|e| format!("Failed to parse local STT response: {}", e))?;
+
+    Ok(SttRes {
+        text: response.text,
+        provider: Provider::Local,
+        audio_info: None,
+        raw: None,
+        detected_language: None,
+    })
+}
+
+// Keep only the OpenAI response headers worth surfacing as raw details
+fn openai_raw_headers(headers: Vec<(String, String)>) -> Vec<(String, String)> {
+    headers
+        .into_iter()
+        .filter(|(name, _)| OPENAI_RAW_HEADERS.contains(&name.as_str()))
+        .collect()
+}
+
+// Helper methods (outside of hyperprocess impl block)
+impl TtsttState {
+    // Configure OpenAI from a key found in the environment, unless one is already set up.
+    // Returns whether a provider was added.
+    fn import_openai_key(&mut self, env_key: Option<String>) -> bool {
+        if self
+            .providers
+            .iter()
+            .any(|p| p.provider == Provider::OpenAI)
+        {
+            return false;
+        }
+        match env_key.filter(|key| !key.trim().is_empty()) {
+            Some(api_key) => {
+                self.configure_openai_from_env(api_key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Add an OpenAI provider using the given key, becoming the default for any
+    // operation that has none yet. The key itself is never logged.
+    fn configure_openai_from_env(&mut self, api_key: String) {
+        let is_default_tts = self.default_tts_provider.is_none();
+        let is_default_stt = self.default_stt_provider.is_none();
+        if is_default_tts {
+            self.default_tts_provider = Some(Provider::OpenAI);
+        }
+        if is_default_stt {
+            self.default_stt_provider = Some(Provider::OpenAI);
+        }
+
+        self.providers.push(ProviderConfig {
+            provider: Provider::OpenAI,
+            profile_name: default_profile_name(),
+            api_key,
+            is_default_tts,
+            is_default_stt,
+            default_voice: None,
+            default_speed: None,
+            user_id: None,
+            region: None,
+            base_url: None,
+            aws_credentials: None,
+            default_tts_model: None,
+            default_stt_model: None,
+            response_audio_field: None,
+            response_text_field: None,
+        });
+    }
+
+    // Id for a new pair: the caller's if given, which must be a safe path component,
+    // otherwise a fresh UUID
+    fn new_pair_id(&self, requested: Option<&str>) -> Result<String, String> {
+        match requested {
+            Some(id) if !is_valid_pair_id(id) => Err(format!("Invalid pair id: {}", id)),
+            Some(id) => Ok(id.to_string()),
+            None => Ok(Uuid::new_v4().to_string()),
+        }
+    }
+
+    // Checked after idempotency lookups, so a retried request may reuse its own id
+    fn ensure_pair_id_unused(&self, id: &str) -> Result<(), String> {
+        if self.pair_index.iter().any(|e| e.id == id) {
+            return Err(format!("Pair id already in use: {}", id));
+        }
+        Ok(())
+    }
+
+    // Request-scoped log line, prefixed with the request id so lines from one tts/stt call
+    // can be correlated. The id is the one its pair is stored under.
+    fn log(&self, level: LogLevel, request_id: &str, message: std::fmt::Arguments) {
+        if level > self.log_level {
+            return;
+        }
+        match level {
+            LogLevel::Error | LogLevel::Warn => eprintln!("[req={}] {}", request_id, message),
+            LogLevel::Info | LogLevel::Debug => println!("[req={}] {}", request_id, message),
+        }
+    }
+
+    // Check each stored key and record which are accepted. Failures are only logged,
+    // so an unreachable provider never blocks init.
+    fn run_preflight(&mut self) {
+        self.provider_health.clear();
+        for config in &self.providers {
+            let Some(result) = preflight_check(config) else {
+                continue;
+            };
+            match &result {
+                Ok(()) => println!(
+                    "Preflight: {} ({}) key is valid",
+                    config.provider.as_str(),
+                    config.profile_name
+                ),
+                Err(e) => eprintln!(
+                    "Preflight: {} ({}) key failed: {}",
+                    config.provider.as_str(),
+                    config.profile_name,
+                    e
+                ),
+            }
+            self.provider_health.push((
+                config.provider.clone(),
+                config.profile_name.clone(),
+                result.is_ok(),
+            ));
+        }
+    }
+
+    fn provider_healthy(&self, provider: &Provider, profile_name: &str) -> Option<bool> {
+        self.provider_health
+            .iter()
+            .find(|(p, name, _)| p == provider && name == profile_name)
+            .map(|(_, _, healthy)| *healthy)
+    }
+
+    // Local STT backs up a failed cloud call only when every other cloud STT config
+    // failed its preflight check; unchecked ones count as healthy. Local STT can't
+    // translate, so translations never fall back.
+    #[cfg_attr(not(feature = "local-stt"), allow(dead_code))]
+    fn should_fall_back_to_local(&self, failed: &Provider, translate: bool) -> bool {
+        *failed != Provider::Local
+            && self.local_stt.is_some()
+            && !translate
+            && self
+                .providers
+                .iter()
+                .filter(|c| {
+                    c.provider.supports_stt()
+                        && c.provider != Provider::Local
+                        && c.provider != *failed
+                })
+                .all(|c| self.provider_healthy(&c.provider, &c.profile_name) == Some(false))
+    }
+
+    fn stt_cost_usd(
+        &self,
+        provider: &Provider,
+        profile: Option<&str>,
+        model: Option<&str>,
+        audio_bytes: &[u8],
+        audio_format: &str,
+    ) -> f64 {
+        let minutes = audio::duration_ms(audio_bytes, audio_format)
+            // Recorded webm can't be parsed cheaply; estimate from size at a typical bitrate
+            .unwrap_or(audio_bytes.len() as u64 * 8 / STT_ASSUMED_BITRATE_KBPS)
+            as f64
+            / 60_000.0;
+        self.stt_price_per_minute(provider, profile, model)
+            .map(|usd_per_minute| minutes * usd_per_minute as f64)
+            .unwrap_or(0.0)
+    }
+
+    fn prune_stt_streams(&mut self) {
+        let cutoff = Utc::now() - chrono::Duration::minutes(STT_STREAM_IDLE_MINUTES);
+        self.stt_streams
+            .retain(|_, stream| stream.last_chunk_at > cutoff);
+    }
+
+    // Whether `key` is the only admin key left, which must never be revoked or demoted
+    fn is_last_admin(&self, key: &str) -> bool {
+        let mut admins = self.api_keys.iter().filter(|k| k.role == ApiKeyRole::Admin);
+        matches!((admins.next(), admins.next()), (Some(k), None) if k.key == key)
+    }
+
+    fn remove_api_key(&mut self, key: &str) -> Result<(), String> {
+        // Don't allow revoking the initial admin key
+        if key == self.admin_key {
+            return Err("Cannot revoke initial admin key".to_string());
+        }
+        if self.is_last_admin(key) {
+            return Err("cannot remove last admin".to_string());
+        }
+
+        self.api_keys.retain(|k| k.key != key);
+        self.rate_buckets.remove(key);
+        Ok(())
+    }
+
+    // Wait until a provider call fits under max_concurrent_calls, then begin it. Waiting
+    // requests are polled rather than woken in order, and give up after QUEUE_TIMEOUT_SECS.
+    async fn acquire_provider_slot(&mut self, provider: &Provider) -> Result<(), String> {
+        let started = Utc::now();
+        while let Some(max) = self.max_concurrent_calls {
+            let in_flight: u32 = self.in_flight.iter().map(|(_, n)| n).sum();
+            if in_flight < max {
+                break;
+            }
+            if Utc::now() - started > chrono::Duration::seconds(QUEUE_TIMEOUT_SECS) {
+                return Err(format!(
+                    "Timed out after {}s waiting for one of {} provider call slots",
+                    QUEUE_TIMEOUT_SECS, max
+                ));
+            }
+            sleep(QUEUE_POLL_MS)
+                .await
+                .map_err(|e| format!("Failed to wait for a provider call slot: {:?}", e))?;
+        }
+
+        self.begin_provider_call(provider);
+        Ok(())
+    }
+
+    // Bracket every provider call so current and peak concurrency stay accurate
+    fn begin_provider_call(&mut self, provider: &Provider) {
+        let current = match self.in_flight.iter_mut().find(|(p, _)| p == provider) {
+            Some((_, n)) => {
+                *n += 1;
+                *n
+            }
+            None => {
+                self.in_flight.push((provider.clone(), 1));
+                1
+            }
+        };
+
+        match self.peak_concurrent.iter_mut().find(|(p, _)| p == provider) {
+            Some((_, peak)) => *peak = (*peak).max(current),
+            None => self.peak_concurrent.push((provider.clone(), current)),
+        }
+    }
+
+    fn end_provider_call(&mut self, provider: &Provider) {
+        if let Some((_, n)) = self.in_flight.iter_mut().find(|(p, _)| p == provider) {
+            *n = n.saturating_sub(1);
+        }
+    }
+
+    // Names without an alias for this provider are passed on as the provider's own
+    fn resolve_voice_alias(&self, provider: &Provider, voice: Option<String>) -> Option<String> {
+        match self
+            .voice_aliases
+            .iter()
+            .find(|a| a.provider == *provider && voice.as_deref() == Some(a.alias.as_str()))
+        {
+            Some(alias) => Some(alias.voice.clone()),
+            None => voice,
+        }
+    }
+
+    async fn synthesize(
+        &self,
+        provider: &Provider,
+        request: TtsReq,
+        request_id: &str,
+    ) -> Result<TtsRes, String> {
+        match provider {
+            Provider::OpenAI => self.handle_openai_tts(request, request_id).await,
+            Provider::PlayAI => self.handle_playai_tts(request).await,
+            Provider::Azure => self.handle_azure_tts(request).await,
+            Provider::AwsPolly => self.handle_polly_tts(request).await,
+            Provider::Webhook => self.handle_webhook_tts(request),
+            Provider::Local => Err("Local provider does not support TTS".to_string()),
+        }
+    }
+
+    // Synthesize segments in order and join the audio. Only mp3 frames and headerless pcm
+    // can be joined byte for byte, so other output formats are refused.
+    async fn synthesize_segments(
+        &self,
+        provider: &Provider,
+        requests: &[TtsReq],
+        request_id: &str,
+    ) -> Result<TtsRes, String> {
+        let mut joined: Option<TtsRes> = None;
+        let mut audio = Vec::new();
+        for (i, request) in requests.iter().enumerate() {
+            let response = self
+                .synthesize(provider, request.clone(), request_id)
+                .await
+                .map_err(|e| format!("segment {}: {}", i, e))?;
+            if !matches!(response.format.as_str(), "mp3" | "pcm") {
+                return Err(format!(
+                    "segments need mp3 or pcm output, but {} returned {}",
+                    provider.as_str(),
+                    response.format
+                ));
+            }
+            audio.extend(
+                BASE64
+                    .decode(&response.audio_data)
+                    .map_err(|e| format!("Failed to decode audio data: {}", e))?,
+            );
+            joined.get_or_insert(response);
+        }
+        let mut response = joined.ok_or_else(|| "segments must not be empty".to_string())?;
+        response.audio_data = BASE64.encode(&audio);
+        response.raw = None;
+        Ok(response)
+    }
+
+    // (model, USD per million characters) for a TTS call, or None when the provider is unpriced
+    fn tts_price(
+        &self,
+        provider: &Provider,
+        profile: Option<&str>,
+        model: Option<&str>,
+    ) -> Option<(String, f32)> {
+        match provider {
+            Provider::OpenAI if self.is_self_hosted_openai(profile) => {
+                Some((model.unwrap_or(DEFAULT_OPENAI_TTS_MODEL).to_string(), 0.0))
+            }
+            Provider::OpenAI => {
+                let model = openai_tts_model_name(model);
+                OPENAI_TTS_PRICING
+                    .iter()
+                    .find(|(name, _)| *name == model)
+                    .map(|(name, price)| (name.to_string(), *price))
+            }
+            Provider::Azure => Some(("neural".to_string(), AZURE_NEURAL_TTS_USD_PER_M_CHARS)),
+            Provider::AwsPolly => {
+                let engine = polly_engine_name(model);
+                POLLY_TTS_PRICING
+                    .iter()
+                    .find(|(name, _)| *name == engine)
+                    .map(|(name, price)| (name.to_string(), *price))
+            }
+            Provider::PlayAI | Provider::Local | Provider::Webhook => None,
+        }
+    }
+
+    // USD per audio minute for an STT call, or None when the provider is unpriced
+    fn stt_price_per_minute(
+        &self,
+        provider: &Provider,
+        profile: Option<&str>,
+        model: Option<&str>,
+    ) -> Option<f32> {
+        match provider {
+            Provider::OpenAI if self.is_self_hosted_openai(profile) => Some(0.0),
+            Provider::OpenAI => {
+                let model = openai_stt_model_name(model);
+                OPENAI_STT_PRICING
+                    .iter()
+                    .find(|(name, _)| *name == model)
+                    .map(|(_, price)| *price)
+            }
+            Provider::Azure => Some(AZURE_STT_USD_PER_MINUTE),
+            Provider::Local => Some(0.0),
+            Provider::PlayAI | Provider::AwsPolly | Provider::Webhook => None,
+        }
+    }
+
+    // Self-hosted OpenAI-compatible endpoints aren't billed
+    fn is_self_hosted_openai(&self, profile: Option<&str>) -> bool {
+        self.get_provider_config(&Provider::OpenAI, profile)
+            .is_ok_and(|c| c.base_url.is_some())
+    }
+
+    // Every tts()/stt() call, including ones rejected before reaching a provider
+    fn count_request(&mut self, request_type: RequestType) {
+        match self
+            .request_counts
+            .iter_mut()
+            .find(|(t, _)| *t == request_type)
+        {
+            Some((_, n)) => *n += 1,
+            None => self.request_counts.push((request_type, 1)),
+        }
+    }
+
+    fn record_provider_error(&mut self, provider: &Provider, request_type: RequestType) {
+        match self
+            .provider_errors
+            .iter_mut()
+            .find(|(p, t, _)| p == provider && *t == request_type)
+        {
+            Some((_, _, n)) => *n += 1,
+            None => self
+                .provider_errors
+                .push((provider.clone(), request_type, 1)),
+        }
+    }
+
+    // Count one completed request. Called exactly once per successful tts/stt, after the
+    // provider call and before the pair is saved, so persisted counters match finished work.
+    fn record_usage(
+        &mut self,
+        provider: &Provider,
+        request_type: RequestType,
+        amount: u64,
+        cost_usd: f64,
+    ) {
+        let counter = match self
+            .usage
+            .iter_mut()
+            .position(|c| c.provider == *provider && c.request_type == request_type)
+        {
+            Some(i) => &mut self.usage[i],
+            None => {
+                self.usage.push(UsageCounter {
+                    provider: provider.clone(),
+                    request_type,
+                    requests: 0,
+                    units: 0,
+                    spend_usd: 0.0,
+                });
+                self.usage.last_mut().unwrap()
+            }
+        };
+        counter.requests = counter.requests.saturating_add(1);
+        counter.units = counter.units.saturating_add(amount);
+        counter.spend_usd += cost_usd;
+    }
+
+    // Estimated USD across TTS and STT since the last reset_spend
+    fn provider_spend(&self, provider: &Provider) -> f64 {
+        self.usage
+            .iter()
+            .filter(|c| c.provider == *provider)
+            .map(|c| c.spend_usd)
+            .sum()
+    }
+
+    // Names identify keys in listings, so they must be unique. `except` is the key being
+    // renamed, which may keep its own name.
+    fn check_key_name_unused(&self, name: &str, except: Option<&str>) -> Result<(), String> {
+        if self
+            .api_keys
+            .iter()
+            .any(|k| k.name == name && Some(k.key.as_str()) != except)
+        {
+            return Err("key name already in use".to_string());
+        }
+        Ok(())
+    }
+
+    // Helper: Validate API key and check permissions
+    // Also stamps the key's last_used_at, which is why this takes &mut self
+    fn validate_api_key(
+        &mut self,
+        api_key: Option<String>,
+        require_admin: bool,
+    ) -> Result<String, String> {
+        let key = api_key.ok_or("API key required")?;
+
+        let api_key_entry = self
+            .api_keys
+            .iter_mut()
+            .find(|k| k.key == key)
+            .ok_or("Invalid API key")?;
+
+        if require_admin && !matches!(api_key_entry.role, ApiKeyRole::Admin) {
+            return Err("Admin permission required".to_string());
+        }
+        api_key_entry.last_used_at = Some(Utc::now().to_rfc3339());
+
+        Ok(api_key_entry.name.clone())
+    }
+
+    // Pair stored for an earlier request with this idempotency key, if still remembered
+    fn idempotent_pair_id(
+        &mut self,
+        key: &str,
+        api_key: Option<&str>,
+        request_type: &RequestType,
+    ) -> Option<String> {
+        self.prune_idempotency_keys();
+        self.idempotency_keys
+            .iter()
+            .find(|e| {
+                e.key == key && e.api_key.as_deref() == api_key && e.request_type == *request_type
+            })
+            .map(|e| e.pair_id.clone())
+    }
+
+    fn remember_idempotency_key(
+        &mut self,
+        key: &str,
+        api_key: Option<&str>,
+        request_type: RequestType,
+        pair_id: &str,
+    ) {
+        self.prune_idempotency_keys();
+        if self.idempotency_keys.len() >= MAX_IDEMPOTENCY_KEYS {
+            self.idempotency_keys.remove(0);
+        }
+        self.idempotency_keys.push(IdempotencyEntry {
+            key: key.to_string(),
+            api_key: api_key.map(str::to_string),
+            request_type,
+            pair_id: pair_id.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+        });
+    }
+
+    fn prune_idempotency_keys(&mut self) {
+        let cutoff = Utc::now() - chrono::Duration::hours(IDEMPOTENCY_TTL_HOURS);
+        self.idempotency_keys.retain(|e| {
+            chrono::DateTime::parse_from_rfc3339(&e.created_at)
+                .is_ok_and(|created_at| created_at >= cutoff)
+        });
+    }
+
+    // Take one token from the key's bucket, or report how long until one is available
+    fn consume_rate_limit_token(&mut self, api_key: &str) -> Result<(), String> {
+        let Some(limit) = self
+            .api_keys
+            .iter()
+            .find(|k| k.key == api_key)
+            .and_then(|k| k.rate_limit_per_min)
+        else {
+            return Ok(());
+        };
+
+        let capacity = limit as f64;
+        let per_second = capacity / 60.0;
+        let now = Utc::now();
+        let bucket = self
+            .rate_buckets
+            .entry(api_key.to_string())
+            .or_insert(RateBucket {
+                tokens: capacity,
+                last_refill: now,
+            });
+
+        let elapsed_secs = (now - bucket.last_refill).num_milliseconds().max(0) as f64 / 1000.0;
+        bucket.tokens = (bucket.tokens + elapsed_secs * per_second).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            let retry_after = ((1.0 - bucket.tokens) / per_second).ceil() as u64;
+            return Err(format!(
+                "rate limit exceeded, retry after {}s",
+                retry_after.max(1)
+            ));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    // Helper: Get provider config. Without a profile, the DEFAULT_PROFILE_NAME profile
+    // is used if configured, otherwise the provider's first profile.
+    fn get_provider_config(
+        &self,
+        provider: &Provider,
+        profile: Option<&str>,
+    ) -> Result<&ProviderConfig, String> {
+        let mut configs = self.providers.iter().filter(|p| p.provider == *provider);
+        match profile {
+            Some(profile) => configs.find(|p| p.profile_name == profile).ok_or_else(|| {
+                format!(
+                    "Provider {:?} profile '{}' not configured",
+                    provider, profile
+                )
+            }),
+            None => {
+                let first = configs.clone().next();
+                configs
+                    .find(|p| p.profile_name == DEFAULT_PROFILE_NAME)
+                    .or(first)
+                    .ok_or_else(|| format!("Provider {:?} not configured", provider))
+            }
+        }
+    }
+
+    // Fail before dispatch when the provider (or requested profile) has no config,
+    // listing what is configured so callers can correct the request
+    fn ensure_provider_configured(
+        &self,
+        provider: &Provider,
+        profile: Option<&str>,
+    ) -> Result<(), String> {
+        let configured = match provider {
+            Provider::Local => self.local_stt.is_some(),
+            _ => self.providers.iter().any(|p| p.provider == *provider),
+        };
+        if !configured {
+            let mut names: Vec<&str> = Vec::new();
+            for p in &self.providers {
+                if !names.contains(&p.provider.as_str()) {
+                    names.push(p.provider.as_str());
+                }
+            }
+            if self.local_stt.is_some() {
+                names.push(Provider::Local.as_str());
+            }
+            return Err(format!(
+                "Provider {} not configured; configured: [{}]",
+                provider.as_str(),
+                names.join(", ")
+            ));
+        }
+
+        if *provider != Provider::Local {
+            self.get_provider_config(provider, profile)?;
+        }
+        Ok(())
+    }
+
+    // Provider for requests that don't name one: the configured default, or else the only
+    // configured provider able to serve the request
+    fn default_provider(&self, request_type: RequestType) -> Result<Provider, String> {
+        let default = match request_type {
+            RequestType::TTS => &self.default_tts_provider,
+            RequestType::STT => &self.default_stt_provider,
+        };
+        if let Some(provider) = default {
+            return Ok(provider.clone());
+        }
+
+        let mut candidates: Vec<&Provider> = Vec::new();
+        for config in &self.providers {
+            let supported = match request_type {
+                RequestType::TTS => config.provider.supports_tts(),
+                RequestType::STT => config.provider.supports_stt(),
+            };
+            if supported && !candidates.contains(&&config.provider) {
+                candidates.push(&config.provider);
+            }
+        }
+        if request_type == RequestType::STT && self.local_stt.is_some() {
+            candidates.push(&Provider::Local);
+        }
+
+        match candidates.as_slice() {
+            [provider] => Ok((*provider).clone()),
+            [] => Err(format!(
+                "No default {:?} provider configured; set one in provider settings",
+                request_type
+            )),
+            _ => Err(format!(
+                "No default {:?} provider configured and several are available; set one in provider settings",
+                request_type
+            )),
+        }
+    }
+
+    // Speed a TTS request is synthesized at: the request's, else the provider's default,
+    // else the app default, else DEFAULT_TTS_SPEED, kept inside the provider's range and the
+    // operator's band. DEFAULT_TTS_SPEED is clamped into the band even under strict_speed,
+    // since nobody asked for it.
+    fn resolve_tts_speed(
+        &self,
+        provider: &Provider,
+        requested: Option<f32>,
+        provider_default: Option<f32>,
+    ) -> Result<f32, String> {
+        let configured = requested.or(provider_default).or(self.tts_defaults.speed);
+        let strict = self.strict_speed && configured.is_some();
+        let speed = configured.unwrap_or(DEFAULT_TTS_SPEED);
+        let speed = check_speed(provider, speed, strict)?;
+        self.tts_defaults.check_speed_band(speed, strict)
+    }
+
+    // A request without a profile uses the profile flagged as default for its operation,
+    // falling back to `get_provider_config`'s choice when none is flagged
+    fn resolve_profile(
+        &self,
+        provider: &Provider,
+        request_type: RequestType,
+        profile: Option<String>,
+    ) -> Option<String> {
+        profile.or_else(|| {
+            self.providers
+                .iter()
+                .find(|p| {
+                    p.provider == *provider
+                        && match request_type {
+                            RequestType::TTS => p.is_default_tts,
+                            RequestType::STT => p.is_default_stt,
+                        }
+                })
+                .map(|p| p.profile_name.clone())
+        })
+    }
+
+    // Run the transcript through the post-processing webhook, if configured.
+    // Returns None when no webhook is set or the call fails, so callers keep the raw text.
+    fn post_process_transcript(&self, text: &str, language: Option<&str>) -> Option<String> {
+        let webhook = self.transcript_webhook.as_ref()?;
+
+        let timeout = webhook.timeout_secs.unwrap_or(DEFAULT_WEBHOOK_TIMEOUT_SECS);
+        let headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+        let result = call_transcript_webhook(text, language, |body| {
+            post_bytes(&webhook.url, headers, body, timeout)
+        });
+
+        match result {
+            Ok(text) => Some(text),
+            Err(e) => {
+                eprintln!("Transcript webhook failed, using raw transcript: {}", e);
+                None
+            }
+        }
+    }
+
+    // OpenAI TTS implementation
+    async fn handle_openai_tts(&self, request: TtsReq, request_id: &str) -> Result<TtsRes, String> {
+        let config = self.get_provider_config(&Provider::OpenAI, request.profile.as_deref())?;
+        if let Some(base_url) = &config.base_url {
+            self.log(
+                LogLevel::Debug,
+                request_id,
+                format_args!("OpenAI-compatible TTS via {}", base_url),
+            );
+            return self.handle_openai_compatible_tts(config, base_url, request);
+        }
+
+        // Create OpenAI TTS client
+        let client = SpeechClient::new(&config.api_key);
+
+        // Map voice string to OpenAI voice enum, use provider default if not specified
+        let voice_str = request
+            .voice
+            .as_deref()
+            .or(config.default_voice.as_deref())
+            .unwrap_or("nova");
+
+        let voice = match voice_str {
+            "alloy" => OpenAIVoice::Alloy,
+            "ash" => OpenAIVoice::Ash,
+            "ballad" => OpenAIVoice::Ballad,
+            "coral" => OpenAIVoice::Coral,
+            "echo" => OpenAIVoice::Echo,
+            "fable" => OpenAIVoice::Fable,
+            "onyx" => OpenAIVoice::Onyx,
+            "nova" => OpenAIVoice::Nova,
+            "sage" => OpenAIVoice::Sage,
+            "shimmer" => OpenAIVoice::Shimmer,
+            "verse" => OpenAIVoice::Verse,
+            _ => OpenAIVoice::Nova, // Default to Nova
+        };
+
+        // Map model string to OpenAI model enum (names shared with the pricing table)
+        let model = match openai_tts_model_name(request.model.as_deref()) {
+            "tts-1" => OpenAITtsModel::Tts1,
+            "tts-1-hd" => OpenAITtsModel::Tts1Hd,
+            _ => OpenAITtsModel::Gpt4oMiniTts, // Default to gpt-4o-mini-tts
+        };
+
+        // Map format string to OpenAI format enum
+        let (format_name, format) = openai_tts_format(request.format.as_deref());
+
+        // Build and execute request
+        let mut builder = client
+            .synthesize()
+            .model(model)
+            .voice(voice)
+            .input(request.text.clone())
+            .response_format(format);
+
+        // Set speed: use request speed, then provider default, then normal speed
+        let speed = request
+            .speed
+            .or(config.default_speed)
+            .unwrap_or(DEFAULT_TTS_SPEED);
+        builder = builder.speed(speed);
+
+        self.log(
+            LogLevel::Debug,
+            request_id,
+            format_args!(
+                "OpenAI TTS model={} voice={} speed={}",
+                openai_tts_model_name(request.model.as_deref()),
+                voice_str,
+                speed
+            ),
+        );
+        let response = builder.execute().await.map_err(|e| {
+            self.log(
+                LogLevel::Debug,
+                request_id,
+                format_args!("OpenAI TTS request failed: {:#?}", e),
+            );
+            openai_client_error("OpenAI TTS error", format!("{:?}", e))
+        })?;
+
+        // The client doesn't expose response headers, so report what was actually sent
+        let raw = (request.include_raw == Some(true)).then(|| {
+            vec![
+                (
+                    "model".to_string(),
+                    openai_tts_model_name(request.model.as_deref()).to_string(),
+                ),
+                ("voice".to_string(), voice_str.to_string()),
+                ("speed".to_string(), speed.to_string()),
+                (
+                    "audio_bytes".to_string(),
+                    response.audio_data.len().to_string(),
+                ),
+            ]
+        });
+
+        let format = openai_output_format(
+            format_name,
+            &response.audio_data,
+            request.web_compatible == Some(true),
+        )?;
+
+        Ok(TtsRes {
+            audio_data: BASE64.encode(&response.audio_data),
+            format,
+            provider: Provider::OpenAI,
+            audio_info: None,
+            raw,
+            pair_id: None,
+        })
+    }
+
+    // Self-hosted OpenAI-compatible TTS (LocalAI, vLLM, ...). These servers use their own
+    // model and voice names, so request values are passed through rather than mapped.
+    fn handle_openai_compatible_tts(
+        &self,
+        config: &ProviderConfig,
+        base_url: &str,
+        request: TtsReq,
+    ) -> Result<TtsRes, String> {
+        #[derive(Serialize)]
+        struct SpeechReq<'a> {
+            model: &'a str,
+            voice: &'a str,
+            input: &'a str,
+            response_format: &'a str,
+            speed: f32,
+        }
+
+        let format = request.format.as_deref().unwrap_or(DEFAULT_TTS_FORMAT);
+        let body = serde_json::to_vec(&SpeechReq {
+            model: request.model.as_deref().unwrap_or("gpt-4o-mini-tts"),
+            voice: request
+                .voice
+                .as_deref()
+                .or(config.default_voice.as_deref())
+                .unwrap_or("nova"),
+            input: &request.text,
+            response_format: format,
+            speed: request
+                .speed
+                .or(config.default_speed)
+                .unwrap_or(DEFAULT_TTS_SPEED),
+        })
+        .map_err(|e| format!("Failed to serialize TTS request: {}", e))?;
+
+        let headers = HashMap::from([
+            (
+                "Authorization".to_string(),
+                format!("Bearer {}", config.api_key),
+            ),
+            ("Content-Type".to_string(), "application/json".to_string()),
+        ]);
+
+        let url = format!("{}/audio/speech", base_url.trim_end_matches('/'));
+        let (response_headers, audio) = post_bytes_with_headers(&url, headers, body, 60)
+            .map_err(|e| format!("OpenAI TTS error: {}", e))?;
+
+        Ok(TtsRes {
+            audio_data: BASE64.encode(&audio),
+            format: openai_output_format(format, &audio, request.web_compatible == Some(true))?,
+            provider: Provider::OpenAI,
+            audio_info: None,
+            raw: (request.include_raw == Some(true)).then(|| openai_raw_headers(response_headers)),
+            pair_id: None,
+        })
+    }
+
+    fn handle_openai_compatible_stt(
+        &self,
+        config: &ProviderConfig,
+        base_url: &str,
+        request: SttReq,
+    ) -> Result<SttRes, String> {
+        let audio_data = BASE64
+            .decode(&request.audio_data)
+            .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+
+        let model = request.model.as_deref().unwrap_or("whisper-1");
+        let filename = stt_upload_filename(request.format.as_deref(), &audio_data);
+        let mut parts: Vec<(&str, Option<&str>, &str, &[u8])> = vec![
+            (
+                "file",
+                Some(&filename),
+                "application/octet-stream",
+                &audio_data,
+            ),
+            ("model", None, "text/plain", model.as_bytes()),
+        ];
+        if let Some(lang) = request.language.as_deref() {
+            parts.push(("language", None, "text/plain", lang.as_bytes()));
+        }
+
+        let boundary = format!("ttstt-{}", Uuid::new_v4());
+        let headers = HashMap::from([
+            (
+                "Authorization".to_string(),
+                format!("Bearer {}", config.api_key),
+            ),
+            (
+                "Content-Type".to_string(),
+                format!("multipart/form-data; boundary={}", boundary),
+            ),
+        ]);
+
+        let url = format!("{}/audio/transcriptions", base_url.trim_end_matches('/'));
+        let (response_headers, body) =
+            post_bytes_with_headers(&url, headers, multipart_body(&boundary, &parts), 120)
+                .map_err(|e| format!("OpenAI STT error: {}", e))?;
+
+        #[derive(Deserialize)]
+        struct TranscriptionRes {
+            text: String,
+            // Everything else (usage, duration, language, ...) is only kept for raw details
+            #[serde(flatten)]
+            extra: serde_json::Map<String, serde_json::Value>,
+        }
+
+        let response: TranscriptionRes = serde_json::from_slice(&body)
+            .map_err(|e| format!("Failed to parse transcription response: {}", e))?;
+
+        let raw = (request.include_raw == Some(true)).then(|| {
+            let mut raw = openai_raw_headers(response_headers);
+            raw.extend(response.extra.into_iter().map(|(key, value)| match value {
+                serde_json::Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            }));
+            raw
+        });
+
+        Ok(SttRes {
+            text: response.text,
+            provider: Provider::OpenAI,
+            audio_info: None,
+            raw,
+            detected_language: None,
+        })
+    }
+
+    // Whisper translation: transcribes speech in any supported language into English text.
+    // The client crate only covers transcriptions, so the endpoint is called directly.
+    fn handle_openai_translation(
+        &self,
+        config: &ProviderConfig,
+        request: SttReq,
+    ) -> Result<SttRes, String> {
+        let audio_data = BASE64
+            .decode(&request.audio_data)
+            .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+
+        let filename = stt_upload_filename(request.format.as_deref(), &audio_data);
+        let parts: [(&str, Option<&str>, &str, &[u8]); 3] = [
+            (
+                "file",
+                Some(&filename),
+                "application/octet-stream",
+                &audio_data,
+            ),
+            (
+                "model",
+                None,
+                "text/plain",
+                OPENAI_TRANSLATION_MODEL.as_bytes(),
+            ),
+            // verbose_json is what reports the detected source language
+            ("response_format", None, "text/plain", b"verbose_json"),
+        ];
+
+        let boundary = format!("ttstt-{}", Uuid::new_v4());
+        let headers = HashMap::from([
+            (
+                "Authorization".to_string(),
+                format!("Bearer {}", config.api_key),
+            ),
+            (
+                "Content-Type".to_string(),
+                format!("multipart/form-data; boundary={}", boundary),
+            ),
+        ]);
+
+        let base_url = config.base_url.as_deref().unwrap_or(OPENAI_API_BASE_URL);
+        let url = format!("{}/audio/translations", base_url.trim_end_matches('/'));
+        let (response_headers, body) =
+            post_bytes_with_headers(&url, headers, multipart_body(&boundary, &parts), 120)
+                .map_err(|e| format!("OpenAI translation error: {}", e))?;
+
+        #[derive(Deserialize)]
+        struct TranslationRes {
+            text: String,
+            #[serde(default)]
+            language: Option<String>,
+        }
+
+        let response: TranslationRes = serde_json::from_slice(&body)
+            .map_err(|e| format!("Failed to parse translation response: {}", e))?;
+
+        Ok(SttRes {
+            text: response.text,
+            provider: Provider::OpenAI,
+            audio_info: None,
+            raw: (request.include_raw == Some(true)).then(|| openai_raw_headers(response_headers)),
+            detected_language: response.language,
+        })
+    }
+
+    // VFS Storage helpers
+    fn storage_drive(&self) -> &str {
+        self.storage_drive
+            .as_deref()
+            .unwrap_or(DEFAULT_STORAGE_DRIVE)
+    }
+
+    fn drive_path(&self) -> String {
+        format!("/{}/{}", our().package_id(), self.storage_drive())
+    }
+
+    fn pair_path(&self, id: &str) -> String {
+        format!("{}/{}", self.drive_path(), id)
+    }
+
+    async fn ensure_storage_initialized(&mut self) -> Result<(), String> {
+        if self.storage_initialized {
+            return Ok(());
+        }
+
+        // Create the drive for pair storage
+        match create_drive(our().package_id(), self.storage_drive(), Some(5)) {
+            Ok(drive_path) => {
+                println!("Created {} drive at: {}", self.storage_drive(), drive_path);
+            }
+            Err(e) => {
+                // Drive might already exist, which is fine
+                println!(
+                    "Note: {} drive may already exist: {:?}",
+                    self.storage_drive(),
+                    e
+                );
+            }
+        }
+
+        self.storage_initialized = true;
+        Ok(())
+    }
+
+    // Check the storage drive can be read, recording the outcome; returns availability
+    async fn probe_storage(&mut self) -> bool {
+        match self.list_pair_dirs().await {
+            Ok(_) => {
+                self.storage_available = true;
+                self.storage_error = None;
+            }
+            Err(e) => {
+                eprintln!("History storage unavailable: {}", e);
+                self.storage_available = false;
+                self.storage_error = Some(e);
+            }
+        }
+        self.storage_available
+    }
+
+    fn require_storage(&self) -> Result<(), String> {
+        if self.storage_available {
+            return Ok(());
+        }
+        Err(format!(
+            "History storage is unavailable ({}); an admin can call retry_storage_init",
+            self.storage_error.as_deref().unwrap_or("not initialized")
+        ))
+    }
+
+    // Housekeeping that needs a readable drive: cleanup after interrupted writes, indexing
+    // and retention
+    async fn prepare_storage(&mut self) {
+        match self.sweep_pair_dirs().await {
+            Ok(without_audio) => self.trash_unwritten_pairs(without_audio).await,
+            Err(e) => eprintln!("Failed to clean up pair directories: {}", e),
+        }
+
+        // Index pairs saved before the index existed or before its current version
+        if self.pair_index.is_empty() || self.pair_index_version != PAIR_INDEX_VERSION {
+            if let Err(e) = self.rebuild_pair_index().await {
+                eprintln!("Failed to index stored pairs: {}", e);
+            }
+        }
+
+        if let Err(e) = self.prune_expired_pairs().await {
+            eprintln!("Failed to prune expired pairs: {}", e);
+        }
+    }
+
+    fn storage_status(&self) -> StorageStatus {
+        StorageStatus {
+            available: self.storage_available,
+            error: self.storage_error.clone(),
+            pair_count: self.pair_index.len() as u32,
+            total_bytes: self.total_stored_bytes(),
+            drive: self.storage_drive().to_string(),
+            max_total_bytes: self.max_total_bytes,
+        }
+    }
+
+    async fn list_pair_dirs(&self) -> Result<Vec<DirEntry>, String> {
+        let base_path = self.drive_path();
+
+        // Open directory
+        let dir = open_dir_async(&base_path, false, Some(5))
+            .await
+            .map_err(|e| format!("Failed to open storage directory: {:?}", e))?;
+
+        // Read directory entries
+        let entries = dir
+            .read()
+            .await
+            .map_err(|e| format!("Failed to read directory: {:?}", e))?;
+
+        Ok(entries
+            .into_iter()
+            .filter(|e| e.file_type == FileType::Directory)
+            .collect())
+    }
+
+    // Remove temp files left behind by writes interrupted before their rename. Returns the
+    // pair directories that hold no audio file.
+    async fn sweep_pair_dirs(&self) -> Result<Vec<String>, String> {
+        let mut without_audio = Vec::new();
+        for pair_dir in self.list_pair_dirs().await? {
+            let dir = open_dir_async(&pair_dir.path, false, Some(5))
+                .await
+                .map_err(|e| format!("Failed to open pair directory: {:?}", e))?;
+            let entries = dir
+                .read()
+                .await
+                .map_err(|e| format!("Failed to read pair directory: {:?}", e))?;
+
+            let mut has_audio = false;
+            for entry in entries {
+                if entry.file_type != FileType::File {
+                    continue;
+                }
+                if entry.path.ends_with(TMP_FILE_SUFFIX) {
+                    if let Err(e) = remove_file_async(&entry.path, Some(5)).await {
+                        eprintln!("Failed to remove stale {}: {:?}", entry.path, e);
+                    }
+                } else if entry
+                    .path
+                    .rsplit('/')
+                    .next()
+                    .is_some_and(|name| name.starts_with("audio."))
+                {
+                    has_audio = true;
+                }
+            }
+            if !has_audio {
+                without_audio.push(pair_dir.path);
+            }
+        }
+
+        Ok(without_audio)
+    }
+
+    // Pairs still flagged pending with no write in flight lost their audio to a failed or
+    // interrupted background write; they go to the trash so history only lists whole pairs
+    async fn trash_unwritten_pairs(&mut self, paths: Vec<String>) {
+        for path in paths {
+            let metadata = match self.read_pair_metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    eprintln!("Skipping {} while checking for lost audio: {}", path, e);
+                    continue;
+                }
+            };
+            if !metadata.pending || self.audio_write_in_flight(&metadata.id) {
+                continue;
+            }
+            match self.move_pair_to_trash(&metadata.id) {
+                Ok(()) => eprintln!(
+                    "Moved pair {} to the trash: its audio was never written",
+                    metadata.id
+                ),
+                Err(e) => eprintln!("Failed to trash pair {} without audio: {}", metadata.id, e),
+            }
+        }
+    }
+
+    // Build an index entry from a pair directory on disk
+    async fn index_entry_for(&self, path: &str) -> Result<PairIndexEntry, String> {
+        let metadata = self.read_pair_metadata(path).await?;
+        let audio_len = self
+            .read_pair_audio_raw(path, &metadata.audio_format)
+            .await
+            .map(|bytes| bytes.len() as u64)
+            .unwrap_or(0);
+        let metadata_len = serde_json::to_vec(&metadata)
+            .map(|json| json.len() as u64)
+            .unwrap_or(0);
+
+        // Keep access times from the previous index where we have them
+        let last_accessed = self
+            .pair_index
+            .iter()
+            .find(|e| e.id == metadata.id)
+            .map(|e| e.last_accessed.clone())
+            .unwrap_or_else(|| metadata.timestamp.clone());
+
+        Ok(PairIndexEntry {
+            tags: tags_from_metadata(&metadata.metadata),
+            content_hash: content_hash_from_metadata(&metadata.metadata),
+            is_test: is_test_pair(&metadata.metadata),
+            metadata_keys: metadata_keys(&metadata.metadata),
+            provider: Some(metadata.provider),
+            request_type: Some(metadata.request_type),
+            id: metadata.id,
+            last_accessed,
+            timestamp: metadata.timestamp,
+            size_bytes: audio_len + metadata_len,
+        })
+    }
+
+    // Rebuild the pair index from what is on disk (used for pairs saved before the index existed)
+    async fn rebuild_pair_index(&mut self) -> Result<(), String> {
+        let mut index = Vec::new();
+        for entry in self.list_pair_dirs().await? {
+            match self.index_entry_for(&entry.path).await {
+                Ok(index_entry) => index.push(index_entry),
+                Err(e) => eprintln!("Skipping {} while indexing: {}", entry.path, e),
+            }
+        }
+
+        println!("Indexed {} stored pairs", index.len());
+        self.pair_index = index;
+        self.pair_index_version = PAIR_INDEX_VERSION;
+        Ok(())
+    }
+
+    // Pairs per tag from the index, most used first and then by name
+    fn tag_counts(&self) -> Vec<TagCount> {
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for entry in &self.pair_index {
+            for tag in &entry.tags {
+                *counts.entry(tag.as_str()).or_default() += 1;
+            }
+        }
+
+        let mut tags: Vec<TagCount> = counts
+            .into_iter()
+            .map(|(tag, count)| TagCount {
+                tag: tag.to_string(),
+                count,
+            })
+            .collect();
+        tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        tags
+    }
+
+    fn total_stored_bytes(&self) -> u64 {
+        self.pair_index.iter().map(|e| e.size_bytes).sum()
+    }
+
+    // Grant one fetch of a pair's audio, pruning tokens that expired unused
+    fn issue_audio_token(&mut self, pair_id: &str, now: chrono::DateTime<Utc>) -> AudioTokenRes {
+        self.audio_tokens.retain(|_, t| t.expires_at > now);
+        let token = Uuid::new_v4().simple().to_string();
+        let expires_at = now + chrono::Duration::minutes(AUDIO_TOKEN_TTL_MINUTES);
+        self.audio_tokens.insert(
+            token.clone(),
+            AudioToken {
+                pair_id: pair_id.to_string(),
+                expires_at,
+            },
+        );
+        AudioTokenRes {
+            token,
+            expires_at: expires_at.to_rfc3339(),
+        }
+    }
+
+    // The pair a token grants; the token is used up either way
+    fn redeem_audio_token(
+        &mut self,
+        token: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<String, String> {
+        match self.audio_tokens.remove(token) {
+            Some(t) if t.expires_at > now => Ok(t.pair_id),
+            Some(_) => Err("Audio token has expired".to_string()),
+            None => Err("Unknown or already used audio token".to_string()),
+        }
+    }
+
+    // An already stored pair with the same content hash, which tts() returns instead of
+    // saving a duplicate. A caller-chosen pair id always gets its own pair.
+    fn identical_pair_id(&self, hash: &str, requested_id: Option<&str>) -> Option<String> {
+        if requested_id.is_some() {
+            return None;
+        }
+        self.pair_index
+            .iter()
+            .find(|e| e.content_hash.as_deref() == Some(hash))
+            .map(|e| e.id.clone())
+    }
+
+    fn touch_pair(&mut self, id: &str) {
+        if let Some(entry) = self.pair_index.iter_mut().find(|e| e.id == id) {
+            entry.last_accessed = Utc::now().to_rfc3339();
+        }
+    }
+
+    // Permanently remove a pair directory and its files
+    async fn remove_pair_dir(&self, path: &str) -> Result<(), String> {
+        let dir = open_dir_async(path, false, Some(5))
+            .await
+            .map_err(|e| format!("Failed to open pair directory: {:?}", e))?;
+        let entries = dir
+            .read()
+            .await
+            .map_err(|e| format!("Failed to read pair directory: {:?}", e))?;
+
+        for entry in entries {
+            remove_file_async(&entry.path, Some(5))
+                .await
+                .map_err(|e| format!("Failed to remove {}: {:?}", entry.path, e))?;
+        }
+
+        remove_dir_async(path, Some(5))
+            .await
+            .map_err(|e| format!("Failed to remove pair directory: {:?}", e))
+    }
+
+    fn audit_log_path(&self) -> String {
+        format!("/{}/{}/{}", our().package_id(), AUDIT_DRIVE, AUDIT_LOG_FILE)
+    }
+
+    // Append an admin action to the audit log. A failed write is reported but doesn't
+    // fail the action, which has already happened.
+    async fn audit(&self, actor: &str, action: &str, target: String) {
+        let entry = AuditEntry {
+            timestamp: Utc::now().to_rfc3339(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target,
+        };
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to serialize audit entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        // Created lazily, like the trash drive
+        if let Err(e) = create_drive(our().package_id(), AUDIT_DRIVE, Some(5)) {
+            println!("Note: {} drive may already exist: {:?}", AUDIT_DRIVE, e);
+        }
+        let path = self.audit_log_path();
+        let result = match open_file_async(&path, true, Some(5)).await {
+            Ok(mut file) => file.append(&line).await.map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(format!("{:?}", e)),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to append to {}: {}", path, e);
+        }
+    }
+
+    fn audio_write_in_flight(&self, id: &str) -> bool {
+        self.audio_writes.borrow().contains(id)
+    }
+
+    // Wait out a pair's background audio write, which rewrites its metadata when done
+    async fn wait_for_audio_write(&self, id: &str) -> Result<(), String> {
+        let started = Utc::now();
+        while self.audio_write_in_flight(id) {
+            if Utc::now() - started > chrono::Duration::seconds(AUDIO_WRITE_TIMEOUT_SECS) {
+                return Err(format!(
+                    "Audio for pair {} is still being written; try again shortly",
+                    id
+                ));
+            }
+            sleep(QUEUE_POLL_MS)
+                .await
+                .map_err(|e| format!("Failed to wait for pair {}: {:?}", id, e))?;
+        }
+        Ok(())
+    }
+
+    // Soft delete: move a pair directory into the trash drive and drop it from the index.
+    // Refused while the pair's audio is still being written.
+    fn move_pair_to_trash(&mut self, id: &str) -> Result<(), String> {
+        if self.audio_write_in_flight(id) {
+            return Err(format!("Audio for pair {} is still being written", id));
+        }
+        // Created lazily since installs that predate the trash never made it
+        if let Err(e) = create_drive(our().package_id(), TRASH_DRIVE, Some(5)) {
+            println!("Note: {} drive may already exist: {:?}", TRASH_DRIVE, e);
+        }
+
+        let package_id = our().package_id();
+        rename_vfs_path(
+            &self.pair_path(id),
+            &format!("/{}/{}/{}", package_id, TRASH_DRIVE, id),
+        )?;
+        self.pair_index.retain(|e| e.id != id);
+        Ok(())
+    }
+
+    fn check_fits_storage(&self, incoming_bytes: u64) -> Result<(), String> {
+        match self.max_total_bytes {
+            Some(max_total_bytes) if incoming_bytes > max_total_bytes => Err(format!(
+                "Audio of {} bytes exceeds max_total_bytes of {}",
+                incoming_bytes, max_total_bytes
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Pairs to evict, in policy order, so `incoming_bytes` more fit under the ceiling.
+    // `keep` is never chosen, e.g. the pair that was just written.
+    fn eviction_victims(&self, incoming_bytes: u64, keep: Option<&str>) -> Vec<String> {
+        let Some(max_total_bytes) = self.max_total_bytes else {
+            return Vec::new();
+        };
+
+        let mut candidates: Vec<&PairIndexEntry> = self
+            .pair_index
+            .iter()
+            .filter(|e| Some(e.id.as_str()) != keep && !self.audio_write_in_flight(&e.id))
+            .collect();
+        match self.eviction_policy {
+            EvictionPolicy::Fifo => candidates.sort_by(|a, b| a.timestamp.cmp(&b.timestamp)),
+            EvictionPolicy::Lru => candidates.sort_by(|a, b| a.last_accessed.cmp(&b.last_accessed)),
+        }
+
+        let mut total = self.total_stored_bytes() + incoming_bytes;
+        let mut victims = Vec::new();
+        for entry in candidates {
+            if total <= max_total_bytes {
+                break;
+            }
+            total -= entry.size_bytes;
+            victims.push(entry.id.clone());
+        }
+        victims
+    }
+
+    // Evict pairs per the configured policy until `incoming_bytes` more fit under the
+    // ceiling. A pair stays indexed, and counted, unless its files were really removed.
+    async fn make_room(&mut self, incoming_bytes: u64, keep: Option<&str>) -> Result<(), String> {
+        self.check_fits_storage(incoming_bytes)?;
+
+        for id in self.eviction_victims(incoming_bytes, keep) {
+            let path = self.pair_path(&id);
+            self.remove_pair_dir(&path)
+                .await
+                .map_err(|e| format!("Failed to evict pair {}: {}", id, e))?;
+            self.pair_index.retain(|e| e.id != id);
+            println!(
+                "Evicted pair {} ({:?}) to stay under max_total_bytes",
+                id, self.eviction_policy
+            );
+        }
+
+        Ok(())
+    }
+
+    // With `background_audio`, only the metadata is written before returning and the audio
+    // follows in a spawned task; otherwise both are written before returning
+    async fn save_audio_text_pair(
+        &mut self,
+        pair: &AudioTextPair,
+        background_audio: bool,
+    ) -> Result<(), String> {
+        self.require_storage()?;
+        let base_path = self.pair_path(&pair.id);
+
+        // Decode base64 up front so audio details can be recorded in metadata
+        let audio_bytes = BASE64
+            .decode(&pair.audio_data)
+            .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+
+        // Metadata is stored without audio data to keep it small
+        let mut metadata = StoredPairMetadata::from_pair(pair);
+        if metadata.duration_ms.is_none() {
+            metadata.duration_ms = audio::duration_ms(&audio_bytes, &pair.audio_format);
+        }
+        if metadata.audio_info.is_none() {
+            metadata.audio_info = audio::audio_info(&audio_bytes, &pair.audio_format);
+        }
+        metadata.audio_sha256 = Some(sha256_hex(&audio_bytes));
+
+        // Compress on write when enabled; reads decompress transparently
+        let stored_audio = if self.compress_audio && audio::is_compressible(&pair.audio_format) {
+            metadata.compression = Some(audio::ZLIB_COMPRESSION.to_string());
+            audio::compress(&audio_bytes)?
+        } else {
+            audio_bytes
+        };
+
+        metadata.pending = background_audio;
+        let metadata_json = serde_json::to_string(&metadata)
+            .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+
+        // Refuse pairs that could never fit; older pairs are only evicted once this one
+        // is safely written, so a failed write costs nothing
+        let size_bytes = (stored_audio.len() + metadata_json.len()) as u64;
+        self.check_fits_storage(size_bytes)?;
+
+        // Create directory for this pair
+        open_dir_async(&base_path, true, Some(5))
+            .await
+            .map_err(|e| format!("Failed to create pair directory: {:?}", e))?;
+
+        let audio_path = format!(
+            "{}/audio.{}",
+            base_path,
+            audio_extension(&pair.audio_format)
+        );
+        if !background_audio {
+            write_file_atomic(&audio_path, &stored_audio).await?;
+        }
+
+        // In the background case metadata goes first so history shows the pair straight
+        // away; the audio follows in a task that clears `pending` once it lands
+        write_file_atomic(
+            &format!("{}/metadata.json", base_path),
+            metadata_json.as_bytes(),
+        )
+        .await?;
+
+        if background_audio {
+            self.audio_writes.borrow_mut().insert(pair.id.clone());
+            spawn(write_pending_audio(
+                pair.id.clone(),
+                base_path,
+                audio_path,
+                stored_audio,
+                self.audio_writes.clone(),
+            ));
+        }
+
+        self.pair_index.push(PairIndexEntry {
+            id: pair.id.clone(),
+            timestamp: pair.timestamp.clone(),
+            size_bytes,
+            last_accessed: pair.timestamp.clone(),
+            tags: tags_from_metadata(&pair.metadata),
+            content_hash: content_hash_from_metadata(&pair.metadata),
+            is_test: is_test_pair(&pair.metadata),
+            metadata_keys: metadata_keys(&pair.metadata),
+            provider: Some(pair.provider.clone()),
+            request_type: Some(pair.request_type.clone()),
+        });
+
+        // The pair is saved either way; a failed eviction is retried on the next save
+        if let Err(e) = self.make_room(0, Some(&pair.id)).await {
+            eprintln!("Storage is over max_total_bytes: {}", e);
+        }
+        if let Err(e) = self.prune_expired_pairs().await {
+            eprintln!("Failed to prune expired pairs: {}", e);
+        }
+
+        Ok(())
+    }
+
+    // Permanently remove pairs older than the retention window; returns how many were removed
+    async fn prune_expired_pairs(&mut self) -> Result<u32, String> {
+        let Some(retention_days) = self.retention_days else {
+            return Ok(0);
+        };
+        let cutoff = Utc::now() - chrono::Duration::days(retention_days as i64);
+
+        let expired: Vec<String> = self
+            .pair_index
+            .iter()
+            .filter(|e| {
+                chrono::DateTime::parse_from_rfc3339(&e.timestamp)
+                    .is_ok_and(|timestamp| timestamp < cutoff)
+            })
+            .map(|e| e.id.clone())
+            .collect();
+
+        let mut removed = 0;
+        for id in expired {
+            let path = self.pair_path(&id);
+            self.remove_pair_dir(&path).await?;
+            self.pair_index.retain(|e| e.id != id);
+            removed += 1;
+        }
+
+        if removed > 0 {
+            println!(
+                "Pruned {} pairs older than {} days",
+                removed, retention_days
+            );
+        }
+        Ok(removed)
+    }
+
+    async fn load_audio_text_pairs(
+        &self,
+        limit: usize,
+        offset: usize,
+        include_audio: bool,
+        sort_key: HistorySortKey,
+        descending: bool,
+        filter: &HistoryFilter,
+    ) -> Result<(Vec<AudioTextPair>, usize), String> {
+        let pair_dirs = self.list_pair_dirs().await?;
+
+        // Read metadata for every pair so sorting reflects stored fields, not directory names
+        let mut stored = Vec::new();
+        for entry in pair_dirs {
+            match self.read_pair_metadata(&entry.path).await {
+                Ok(metadata) => {
+                    if filter.matches(&metadata)? {
+                        stored.push((entry.path, metadata));
+                    }
+                }
+                Err(e) => eprintln!("Failed to load pair from {}: {}", entry.path, e),
+            }
+        }
+
+        stored.sort_by(|(_, a), (_, b)| {
+            let ordering = sort_key.compare(a, b).then_with(|| a.id.cmp(&b.id));
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+
+        // Apply pagination, then load audio only for the requested page
+        let total = stored.len();
+        let mut pairs = Vec::new();
+        for (path, metadata) in stored.into_iter().skip(offset).take(limit) {
+            let audio_data = if include_audio {
+                match self.read_pair_audio(&path, &metadata).await {
+                    Ok(bytes) => BASE64.encode(&bytes),
+                    Err(e) => {
+                        eprintln!("Failed to load pair from {}: {}", path, e);
+                        continue;
+                    }
+                }
+            } else {
+                String::new()
+            };
+            pairs.push(metadata.into_pair(audio_data));
+        }
+
+        Ok((pairs, total))
+    }
+
+    async fn load_history_page(&mut self, request: GetHistoryReq) -> Result<HistoryPage, String> {
+        self.require_storage()?;
+        let limit = request.limit.unwrap_or(50) as usize;
+        let offset = request.offset.unwrap_or(0) as usize;
+        let include_audio = request.include_audio.unwrap_or(false);
+        let filter = HistoryFilter::from_request(&request)?;
+        let sort_key = HistorySortKey::parse(request.sort_by.as_deref())?;
+        let order = match request.order.as_deref() {
+            None => None,
+            Some("desc") => Some(SortOrder::NewestFirst),
+            Some("asc") => Some(SortOrder::OldestFirst),
+            Some(other) => {
+                return Err(format!(
+                    "Invalid order '{}': must be 'asc' or 'desc'",
+                    other
+                ))
+            }
+        };
+        let sort = match (order, request.sort) {
+            (Some(order), Some(sort)) if order != sort => {
+                return Err("order and sort disagree; set only one".to_string())
+            }
+            (order, sort) => sort.or(order).unwrap_or_default(),
+        };
+        let descending = sort == SortOrder::NewestFirst;
+
+        let page = match request.cursor.as_deref() {
+            Some(cursor) => {
+                if !matches!(sort_key, HistorySortKey::Timestamp) {
+                    return Err("cursor is only supported with sort_by 'timestamp'".to_string());
+                }
+                // Cursor pages come from the index, which doesn't hold durations
+                if filter.filters_duration() {
+                    return Err("duration filters are not supported with cursor".to_string());
+                }
+                self.load_history_after_cursor(
+                    decode_history_cursor(cursor)?,
+                    limit,
+                    include_audio,
+                    descending,
+                    filter.exclude_test,
+                )
+                .await
+            }
+            None => {
+                // Load from VFS
+                let (pairs, total) = self
+                    .load_audio_text_pairs(
+                        limit,
+                        offset,
+                        include_audio,
+                        sort_key,
+                        descending,
+                        &filter,
+                    )
+                    .await?;
+
+                let has_more = page_has_more(offset, limit, total);
+                let next_cursor = match (has_more, &sort_key, pairs.last()) {
+                    (true, HistorySortKey::Timestamp, Some(last)) => {
+                        Some(encode_history_cursor(&last.timestamp, &last.id))
+                    }
+                    _ => None,
+                };
+
+                HistoryPage {
+                    pairs,
+                    total: total as u32,
+                    has_more,
+                    next_cursor,
+                }
+            }
+        };
+
+        if include_audio {
+            for pair in &page.pairs {
+                self.touch_pair(&pair.id);
+            }
+        }
+
+        Ok(page)
+    }
+
+    // Cursor paging walks the in-memory index, so only the returned page touches VFS
+    async fn load_history_after_cursor(
+        &self,
+        cursor: (String, String),
+        limit: usize,
+        include_audio: bool,
+        descending: bool,
+        exclude_test: bool,
+    ) -> HistoryPage {
+        let mut keys: Vec<(String, String)> = self
+            .pair_index
+            .iter()
+            .filter(|e| !(exclude_test && e.is_test))
+            .map(|e| (e.timestamp.clone(), e.id.clone()))
+            .collect();
+        keys.sort();
+        if descending {
+            keys.reverse();
+        }
+
+        // Keys are sorted, so the first entry past the cursor can be found by bisection
+        let start = keys.partition_point(|key| {
+            if descending {
+                *key >= cursor
+            } else {
+                *key <= cursor
+            }
+        });
+        let end = keys.len().min(start.saturating_add(limit));
+
+        let mut pairs = Vec::new();
+        for (_, id) in &keys[start..end] {
+            let path = self.pair_path(id);
+            match self
+                .load_audio_text_pair_by_path(&path, include_audio)
+                .await
+            {
+                Ok(pair) => pairs.push(pair),
+                Err(e) => eprintln!("Failed to load pair from {}: {}", path, e),
+            }
+        }
+
+        let has_more = end < keys.len();
+        HistoryPage {
+            pairs,
+            total: keys.len() as u32,
+            has_more,
+            next_cursor: has_more
+                .then(|| encode_history_cursor(&keys[end - 1].0, &keys[end - 1].1)),
+        }
+    }
+
+    async fn load_audio_text_pair_by_id(&self, id: &str) -> Result<AudioTextPair, String> {
+        // Ids become path components, so anything else could escape the drive
+        if !is_valid_pair_id(id) {
+            return Err(format!("Invalid pair id: {}", id));
+        }
+        self.require_storage()?;
+        let path = self.pair_path(id);
+        self.load_audio_text_pair_by_path(&path, true).await
+    }
+
+    // Set metadata entries on a stored pair (or replace them all), keeping the index in step
+    async fn merge_pair_metadata(
+        &mut self,
+        id: &str,
+        metadata: Vec<(String, String)>,
+        replace: bool,
+    ) -> Result<StoredPairMetadata, String> {
+        self.wait_for_audio_write(id).await?;
+        let path = self.pair_path(id);
+        let mut stored = self.read_pair_metadata(&path).await?;
+        let old_len = serde_json::to_vec(&stored)
+            .map(|json| json.len() as u64)
+            .unwrap_or(0);
+
+        if replace {
+            stored.metadata = metadata;
+        } else {
+            for (key, value) in metadata {
+                match stored.metadata.iter_mut().find(|(k, _)| *k == key) {
+                    Some(entry) => entry.1 = value,
+                    None => stored.metadata.push((key, value)),
+                }
+            }
+        }
+
+        let new_len = self.write_pair_metadata(&path, &stored).await?;
+        if let Some(entry) = self.pair_index.iter_mut().find(|e| e.id == id) {
+            entry.size_bytes = (entry.size_bytes + new_len).saturating_sub(old_len);
+            entry.tags = tags_from_metadata(&stored.metadata);
+            entry.metadata_keys = metadata_keys(&stored.metadata);
+        }
+        Ok(stored)
+    }
+
+    // Write metadata.json via a temp file and rename so readers never see a partial file
+    async fn write_pair_metadata(
+        &self,
+        path: &str,
+        metadata: &StoredPairMetadata,
+    ) -> Result<u64, String> {
+        let metadata_json = serde_json::to_string(metadata)
+            .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+
+        write_file_atomic(&format!("{}/metadata.json", path), metadata_json.as_bytes()).await?;
+
+        Ok(metadata_json.len() as u64)
+    }
+
+    async fn read_pair_metadata(&self, path: &str) -> Result<StoredPairMetadata, String> {
+        let metadata_path = format!("{}/metadata.json", path);
+        let metadata_file = open_file_async(&metadata_path, false, Some(5))
+            .await
+            .map_err(|e| format!("Failed to open metadata file: {:?}", e))?;
+
+        let metadata_str = metadata_file
+            .read_to_string()
+            .await
+            .map_err(|e| format!("Failed to read metadata: {:?}", e))?;
+
+        serde_json::from_str(&metadata_str).map_err(|e| format!("Failed to parse metadata: {}", e))
+    }
+
+    // Audio bytes exactly as stored on disk (possibly compressed)
+    async fn read_pair_audio_raw(&self, path: &str, audio_format: &str) -> Result<Vec<u8>, String> {
+        let audio_path = format!("{}/audio.{}", path, audio_extension(audio_format));
+        let audio_file = match open_file_async(&audio_path, false, Some(5)).await {
+            Ok(file) => file,
+            // Pairs saved before per-format extensions used a generic one
+            Err(_) => open_file_async(
+                &format!("{}/audio.{}", path, LEGACY_AUDIO_EXTENSION),
+                false,
+                Some(5),
+            )
+            .await
+            .map_err(|e| format!("Failed to open audio file: {:?}", e))?,
+        };
+
+        audio_file
+            .read()
+            .await
+            .map_err(|e| format!("Failed to read audio: {:?}", e))
+    }
+
+    // A byte range of the audio, reading only that slice when the file is stored uncompressed.
+    // Compressed audio has to be inflated whole first. Checksums can't be checked on a slice.
+    async fn read_pair_audio_range(
+        &self,
+        path: &str,
+        metadata: &StoredPairMetadata,
+        start: u64,
+        length: u64,
+    ) -> Result<(Vec<u8>, u64), String> {
+        if metadata.compression.is_some() {
+            let audio = self.read_pair_audio(path, metadata).await?;
+            let total = audio.len() as u64;
+            let start = start.min(total) as usize;
+            let end = (start as u64 + length).min(total) as usize;
+            return Ok((audio[start..end].to_vec(), total));
+        }
+
+        let audio_path = format!("{}/audio.{}", path, audio_extension(&metadata.audio_format));
+        let mut audio_file = match open_file_async(&audio_path, false, Some(5)).await {
+            Ok(file) => file,
+            // Pairs saved before per-format extensions used a generic one
+            Err(_) => open_file_async(
+                &format!("{}/audio.{}", path, LEGACY_AUDIO_EXTENSION),
+                false,
+                Some(5),
+            )
+            .await
+            .map_err(|e| format!("Failed to open audio file: {:?}", e))?,
+        };
+
+        let total = audio_file
+            .metadata()
+            .await
+            .map_err(|e| format!("Failed to read audio size: {:?}", e))?
+            .len;
+        let length = length.min(total.saturating_sub(start));
+        if length == 0 {
+            return Ok((Vec::new(), total));
+        }
+
+        audio_file
+            .seek(SeekFrom::Start(start))
+            .await
+            .map_err(|e| format!("Failed to seek audio: {:?}", e))?;
+        let mut buffer = vec![0; length as usize];
+        let read = audio_file
+            .read_at(&mut buffer)
+            .await
+            .map_err(|e| format!("Failed to read audio: {:?}", e))?;
+        buffer.truncate(read);
+
+        Ok((buffer, total))
+    }
+
+    // Audio bytes as originally produced, decompressing if the pair was stored compressed
+    async fn read_pair_audio(
+        &self,
+        path: &str,
+        metadata: &StoredPairMetadata,
+    ) -> Result<Vec<u8>, String> {
+        let stored = self
+            .read_pair_audio_raw(path, &metadata.audio_format)
+            .await?;
+        let audio = match &metadata.compression {
+            Some(compression) => audio::decompress(&stored, compression)?,
+            None => stored,
+        };
+
+        if let Some(expected) = &metadata.audio_sha256 {
+            if sha256_hex(&audio) != *expected {
+                return Err(format!("audio checksum mismatch for pair {}", metadata.id));
+            }
+        }
+        Ok(audio)
+    }
+
+    // Load a pair from its directory; audio is only read when `include_audio` is set,
+    // otherwise `audio_data` is left empty. It's also empty while the audio is still being
+    // written; a pending pair whose write has ended without clearing the flag failed.
+    async fn load_audio_text_pair_by_path(
+        &self,
+        path: &str,
+        include_audio: bool,
+    ) -> Result<AudioTextPair, String> {
+        let metadata = self.read_pair_metadata(path).await?;
+
+        let audio_data = if include_audio {
+            match self.read_pair_audio(path, &metadata).await {
+                Ok(audio) => BASE64.encode(audio),
+                // Saved moments ago; the audio hasn't landed yet
+                Err(_) if metadata.pending && self.audio_write_in_flight(&metadata.id) => {
+                    String::new()
+                }
+                Err(e) if metadata.pending => {
+                    return Err(format!(
+                        "Audio for pair {} was never written: {}",
+                        metadata.id, e
+                    ))
+                }
+                Err(e) => return Err(e),
+            }
+        } else {
+            String::new()
+        };
+
+        Ok(metadata.into_pair(audio_data))
+    }
+
+    // PlayAI (PlayHT) TTS implementation
+    async fn handle_playai_tts(&self, request: TtsReq) -> Result<TtsRes, String> {
+        let config = self.get_provider_config(&Provider::PlayAI, request.profile.as_deref())?;
+        let user_id = config
+            .user_id
+            .as_deref()
+            .ok_or("PlayAI requires a user_id in the provider config")?;
+
+        // Voices are PlayHT manifest URLs; accept those directly or map known names
+        let voice_str = request
+            .voice
+            .as_deref()
+            .or(config.default_voice.as_deref())
+            .unwrap_or("jennifer");
+        let voice = if voice_str.starts_with("s3://") || voice_str.starts_with("https://") {
+            voice_str
+        } else {
+            match voice_str {
+                "jennifer" => PLAYAI_VOICE_JENNIFER,
+                _ => PLAYAI_VOICE_JENNIFER, // Default to Jennifer
+            }
+        };
+
+        // Map model string to a PlayHT voice engine
+        let voice_engine = match request.model.as_deref() {
+            Some("PlayDialog") => "PlayDialog",
+            Some("Play3.0-mini") => "Play3.0-mini",
+            _ => "PlayDialog", // Default
+        };
+
+        // PlayHT streams back the requested container; fall back to mp3 for anything else
+        let (output_format, mime) = match request.format.as_deref() {
+            Some("wav") => ("wav", "audio/wav"),
+            Some("ogg") => ("ogg", "audio/ogg"),
+            Some("flac") => ("flac", "audio/flac"),
+            _ => ("mp3", "audio/mpeg"),
+        };
+
+        #[derive(Serialize)]
+        struct PlayAiReq<'a> {
+            text: &'a str,
+            voice: &'a str,
+            voice_engine: &'a str,
+            output_format: &'a str,
+            speed: f32,
+        }
+
+        let speed = request
+            .speed
+            .or(config.default_speed)
+            .unwrap_or(DEFAULT_TTS_SPEED);
+        let body = serde_json::to_vec(&PlayAiReq {
+            text: &request.text,
+            voice,
+            voice_engine,
+            output_format,
+            speed,
+        })
+        .map_err(|e| format!("Failed to serialize PlayAI request: {}", e))?;
+
+        let headers = HashMap::from([
+            ("Authorization".to_string(), config.api_key.clone()),
+            ("X-User-Id".to_string(), user_id.to_string()),
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Accept".to_string(), mime.to_string()),
+        ]);
+
+        let audio = post_bytes(PLAYAI_TTS_STREAM_URL, headers, body, 60)
+            .map_err(|e| format!("PlayAI TTS error: {}", e))?;
+
+        Ok(TtsRes {
+            audio_data: BASE64.encode(&audio),
+            format: output_format.to_string(),
+            provider: Provider::PlayAI,
+            audio_info: None,
+            raw: None,
+            pair_id: None,
+        })
+    }
+
+    // Azure Speech TTS implementation (SSML over REST)
+    async fn handle_azure_tts(&self, request: TtsReq) -> Result<TtsRes, String> {
+        let config = self.get_provider_config(&Provider::Azure, request.profile.as_deref())?;
+        let region = config
+            .region
+            .as_deref()
+            .ok_or("Azure requires a region in the provider config")?;
+
+        let voice = azure_voice_name(
+            request
+                .voice
+                .as_deref()
+                .or(config.default_voice.as_deref())
+                .unwrap_or(AZURE_DEFAULT_VOICE),
+        );
+
+        // Azure picks the container via header; fall back to mp3 for anything else
+        let (format, output_format) = match request.format.as_deref() {
+            Some("wav") => ("wav", "riff-24khz-16bit-mono-pcm"),
+            Some("pcm") => ("pcm", "raw-24khz-16bit-mono-pcm"),
+            Some("opus") | Some("ogg") => ("ogg", "ogg-24khz-16bit-mono-opus"),
+            Some("webm") => ("webm", "webm-24khz-16bit-mono-opus"),
+            _ => ("mp3", "audio-24khz-48kbitrate-mono-mp3"),
+        };
+
+        // Text that is already SSML is sent through untouched
+        let ssml = if request.text.trim_start().starts_with("<speak") {
+            request.text.clone()
+        } else {
+            // Voice names are "<locale>-<Name>Neural"; the locale doubles as xml:lang
+            let lang = voice.rsplit_once('-').map(|(l, _)| l).unwrap_or("en-US");
+            let speed = request
+                .speed
+                .or(config.default_speed)
+                .unwrap_or(DEFAULT_TTS_SPEED);
+            format!(
+                "<speak version='1.0' xmlns='http://www.w3.org/2001/10/synthesis' xml:lang='{}'>\
+                 <voice name='{}'><prosody rate='{}'>{}</prosody></voice></speak>",
+                lang,
+                voice,
+                speed,
+                xml_escape(&request.text)
+            )
+        };
+
+        let headers = HashMap::from([
+            (
+                "Ocp-Apim-Subscription-Key".to_string(),
+                config.api_key.clone(),
+            ),
+            (
+                "Content-Type".to_string(),
+                "application/ssml+xml".to_string(),
+            ),
+            (
+                "X-Microsoft-OutputFormat".to_string(),
+                output_format.to_string(),
+            ),
+            ("User-Agent".to_string(), "ttstt".to_string()),
+        ]);
+
+        let url = format!(
+            "https://{}.tts.speech.microsoft.com/cognitiveservices/v1",
+            region
+        );
+        let audio = post_bytes(&url, headers, ssml.into_bytes(), 60)
+            .map_err(|e| format!("Azure TTS error: {}", e))?;
+
+        Ok(TtsRes {
+            audio_data: BASE64.encode(&audio),
+            format: format.to_string(),
+            provider: Provider::Azure,
+            audio_info: None,
+            raw: None,
+            pair_id: None,
+        })
+    }
+
+    // Amazon Polly TTS implementation (SynthesizeSpeech over SigV4-signed REST)
+    async fn handle_polly_tts(&self, request: TtsReq) -> Result<TtsRes, String> {
+        let config = self.get_provider_config(&Provider::AwsPolly, request.profile.as_deref())?;
+        let region = config
+            .region
+            .as_deref()
+            .ok_or("AwsPolly requires a region in the provider config")?;
+        let credentials = config
+            .aws_credentials
+            .as_ref()
+            .ok_or("AwsPolly requires aws_credentials in the provider config")?;
+
+        let voice = polly_voice_id(
+            request
+                .voice
+                .as_deref()
+                .or(config.default_voice.as_deref())
+                .unwrap_or(POLLY_DEFAULT_VOICE),
+        );
+        let engine = polly_engine_name(request.model.as_deref());
+
+        // Polly pcm is 8/16kHz only, so pcm requests fall back to mp3 like other formats
+        let (format, output_format) = match request.format.as_deref() {
+            Some("ogg") => ("ogg", "ogg_vorbis"),
+            _ => ("mp3", "mp3"),
+        };
+
+        // Polly has no speed parameter; rate is applied through SSML prosody instead
+        let speed = request
+            .speed
+            .or(config.default_speed)
+            .unwrap_or(DEFAULT_TTS_SPEED);
+        let (text, text_type) = if request.text.trim_start().starts_with("<speak") {
+            (request.text.clone(), "ssml")
+        } else if speed != 1.0 {
+            (
+                format!(
+                    "<speak><prosody rate='{}%'>{}</prosody></speak>",
+                    (speed * 100.0).round(),
+                    xml_escape(&request.text)
+                ),
+                "ssml",
+            )
+        } else {
+            (request.text.clone(), "text")
+        };
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct PollyReq<'a> {
+            engine: &'a str,
+            output_format: &'a str,
+            text: &'a str,
+            text_type: &'a str,
+            voice_id: &'a str,
+        }
+
+        let body = serde_json::to_vec(&PollyReq {
+            engine,
+            output_format,
+            text: &text,
+            text_type,
+            voice_id: &voice,
+        })
+        .map_err(|e| format!("Failed to serialize Polly request: {}", e))?;
+
+        let host = format!("polly.{}.amazonaws.com", region);
+        let path = "/v1/speech";
+        let headers = aws::sigv4_json_post_headers(
+            credentials,
+            region,
+            "polly",
+            &host,
+            path,
+            &body,
+            Utc::now(),
+        );
+
+        let url = format!("https://{}{}", host, path);
+        let audio = post_bytes(&url, headers, body, 60)
+            .map_err(|e| format!("AwsPolly TTS error: {}", e))?;
+
+        Ok(TtsRes {
+            audio_data: BASE64.encode(&audio),
+            format: format.to_string(),
+            provider: Provider::AwsPolly,
+            audio_info: None,
+            raw: None,
+            pair_id: None,
+        })
+    }
+
+    // Azure Speech STT implementation (fast transcription API, accepts webm directly)
+    async fn handle_azure_stt(&self, request: SttReq) -> Result<SttRes, String> {
+        let config = self.get_provider_config(&Provider::Azure, request.profile.as_deref())?;
+        let region = config
+            .region
+            .as_deref()
+            .ok_or("Azure requires a region in the provider config")?;
+
+        let audio_data = BASE64
+            .decode(&request.audio_data)
+            .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+
+        // Without locales Azure falls back to language identification
+        let definition = serde_json::json!({
+            "locales": request.language.iter().collect::<Vec<_>>(),
+        })
+        .to_string();
+
+        let filename = stt_upload_filename(request.format.as_deref(), &audio_data);
+        let boundary = format!("ttstt-{}", Uuid::new_v4());
+        let body = multipart_body(
+            &boundary,
+            &[
+                (
+                    "audio",
+                    Some(&filename),
+                    "application/octet-stream",
+                    &audio_data,
+                ),
+                (
+                    "definition",
+                    None,
+                    "application/json",
+                    definition.as_bytes(),
+                ),
+            ],
+        );
+
+        let headers = HashMap::from([
+            (
+                "Ocp-Apim-Subscription-Key".to_string(),
+                config.api_key.clone(),
+            ),
+            (
+                "Content-Type".to_string(),
+                format!("multipart/form-data; boundary={}", boundary),
+            ),
+        ]);
+
+        let url = format!(
+            "https://{}.api.cognitive.microsoft.com/speechtotext/transcriptions:transcribe?api-version={}",
+            region, AZURE_STT_API_VERSION
+        );
+        let response =
+            post_bytes(&url, headers, body, 120).map_err(|e| format!("Azure STT error: {}", e))?;
+
+        #[derive(Deserialize)]
+        struct AzurePhrase {
+            text: String,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct AzureSttRes {
+            combined_phrases: Vec<AzurePhrase>,
+        }
+
+        let response: AzureSttRes = serde_json::from_slice(&response)
+            .map_err(|e| format!("Failed to parse Azure STT response: {}", e))?;
+
+        Ok(SttRes {
+            text: response
+                .combined_phrases
+                .into_iter()
+                .map(|p| p.text)
+                .collect::<Vec<_>>()
+                .join(" "),
+            provider: Provider::Azure,
+            audio_info: None,
+            raw: None,
+            detected_language: None,
+        })
+    }
+
+    // Webhook TTS: POST {text, voice, format, speed} to `{base_url}/tts` and read base64
+    // audio from the JSON response. A non-empty api_key is sent as a bearer token.
+    fn handle_webhook_tts(&self, request: TtsReq) -> Result<TtsRes, String> {
+        let config = self.get_provider_config(&Provider::Webhook, request.profile.as_deref())?;
+
+        #[derive(Serialize)]
+        struct WebhookTtsReq<'a> {
+            text: &'a str,
+            voice: Option<&'a str>,
+            format: &'a str,
+            speed: Option<f32>,
+        }
+
+        let format = request.format.as_deref().unwrap_or(DEFAULT_TTS_FORMAT);
+        let body = serde_json::to_vec(&WebhookTtsReq {
+            text: &request.text,
+            voice: request.voice.as_deref().or(config.default_voice.as_deref()),
+            format,
+            speed: request.speed.or(config.default_speed),
+        })
+        .map_err(|e| format!("Failed to serialize TTS request: {}", e))?;
+
+        let response = webhook_post(config, "tts", body)?;
+        let field = config
+            .response_audio_field
+            .as_deref()
+            .unwrap_or("audio_data");
+        let audio_data = json_field(&response, field)
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| format!("Webhook TTS response has no string field {}", field))?;
+        // Re-encode so malformed base64 fails here rather than when the pair is read
+        let audio = BASE64
+            .decode(audio_data)
+            .map_err(|e| format!("Webhook TTS returned invalid base64 audio: {}", e))?;
+
+        Ok(TtsRes {
+            audio_data: BASE64.encode(&audio),
+            format: format.to_string(),
+            provider: Provider::Webhook,
+            audio_info: None,
+            raw: None,
+            pair_id: None,
+        })
+    }
+
+    // Local STT: POST {audio_data, model, language} to the configured on-node service
+    #[cfg(feature = "local-stt")]
+    fn handle_local_stt(&self, request: SttReq) -> Result<SttRes, String> {
+        let config = self
+            .local_stt
+            .as_ref()
+            .ok_or("Local STT is not configured")?;
+
+        #[derive(Serialize)]
+        struct LocalSttReq<'a> {
+            audio_data: &'a str,
+            model: Option<&'a str>,
+            language: Option<&'a str>,
+        }
+
+        let body = post_json(
+            &config.url,
+            &LocalSttReq {
+                audio_data: &request.audio_data,
+                model: request.model.as_deref(),
+                language: request.language.as_deref(),
+            },
+            config
+                .timeout_secs
+                .unwrap_or(DEFAULT_LOCAL_STT_TIMEOUT_SECS),
+        )
+        .map_err(|e| format!("Local STT error: {}", e))?;
+
+        local_stt_response(&body)
+    }
+
+    // Webhook STT: POST {audio_data, model, language} to `{base_url}/stt`
+    fn handle_webhook_stt(&self, request: SttReq) -> Result<SttRes, String> {
+        let config = self.get_provider_config(&Provider::Webhook, request.profile.as_deref())?;
+
+        #[derive(Serialize)]
+        struct WebhookSttReq<'a> {
+            audio_data: &'a str,
+            model: Option<&'a str>,
+            language: Option<&'a str>,
+        }
+
+        let body = serde_json::to_vec(&WebhookSttReq {
+            audio_data: &request.audio_data,
+            model: request.model.as_deref(),
+            language: request.language.as_deref(),
+        })
+        .map_err(|e| format!("Failed to serialize STT request: {}", e))?;
+
+        let response = webhook_post(config, "stt", body)?;
+        let field = config.response_text_field.as_deref().unwrap_or("text");
+        let text = json_field(&response, field)
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| format!("Webhook STT response has no string field {}", field))?;
+
+        Ok(SttRes {
+            text: text.to_string(),
+            provider: Provider::Webhook,
+            audio_info: None,
+            raw: None,
+            detected_language: None,
+        })
+    }
+
+    async fn dispatch_stt(
+        &self,
+        provider: &Provider,
+        request: &SttReq,
+        request_id: &str,
+    ) -> Result<SttRes, String> {
+        match provider {
+            Provider::OpenAI => self.handle_openai_stt(request.clone(), request_id).await,
+            Provider::PlayAI => Err("PlayAI does not support STT".to_string()),
+            Provider::AwsPolly => Err("AwsPolly does not support STT".to_string()),
+            Provider::Azure => self.handle_azure_stt(request.clone()).await,
+            Provider::Webhook => self.handle_webhook_stt(request.clone()),
+            #[cfg(feature = "local-stt")]
+            Provider::Local => self.handle_local_stt(request.clone()),
+            #[cfg(not(feature = "local-stt"))]
+            Provider::Local => Err("Local STT requires the local-stt feature".to_string()),
+        }
+    }
+
+    // Run STT on the chosen provider. With the `local-stt` feature, a failed cloud call is
+    // retried against the local provider when no other cloud STT provider is healthy; the
+    // provider that failed is returned alongside.
+    async fn transcribe(
+        &self,
+        provider: &Provider,
+        request: &SttReq,
+        request_id: &str,
+    ) -> Result<(SttRes, Option<Provider>), String> {
+        let result = self.dispatch_stt(provider, request, request_id).await;
+
+        #[cfg(feature = "local-stt")]
+        if let Err(e) = &result {
+            if self.should_fall_back_to_local(provider, request.translate == Some(true)) {
+                self.log(
+                    LogLevel::Warn,
+                    request_id,
+                    format_args!(
+                        "{} STT failed, falling back to local STT: {}",
+                        provider.as_str(),
+                        e
+                    ),
+                );
+                return match self.handle_local_stt(request.clone()) {
+                    Ok(response) => Ok((response, Some(provider.clone()))),
+                    Err(local_error) => Err(TtsttError::AllProvidersFailed(vec![
+                        (provider.clone(), e.clone()),
+                        (Provider::Local, local_error),
+                    ])
+                    .into()),
+                };
+            }
+        }
+
+        result.map(|response| (response, None))
+    }
+
+    // Everything stt() does before the provider call: validation, idempotency, decoding
+    // and provider resolution. A replayed idempotency key is answered here.
+    async fn prepare_stt(&mut self, request: SttReq) -> Result<SttPrep, String> {
+        self.count_request(RequestType::STT);
+        // Also the id the pair is stored under
+        let request_id = self.new_pair_id(request.pair_id.as_deref())?;
+        let encoding = AudioEncoding::parse(request.encoding.as_deref())?;
+        // Check size before and after decoding so oversized payloads are never fully decoded
+        let max_audio_bytes = self.max_audio_bytes.unwrap_or(DEFAULT_MAX_AUDIO_BYTES);
+        if encoding.decoded_len(&request.audio_data) > max_audio_bytes {
+            return Err(format!(
+                "audio exceeds maximum size of {} bytes",
+                max_audio_bytes
+            ));
+        }
+        let mut request = SttReq {
+            audio_data: encoding.to_base64(request.audio_data)?,
+            encoding: None,
+            ..request
+        };
+
+        // Validate API key if provided
+        if let Some(api_key) = &request.api_key {
+            self.validate_api_key(Some(api_key.clone()), false)?;
+            self.consume_rate_limit_token(api_key)?;
+        }
+
+        validate_request_metadata(&request.metadata)?;
+        if let Some(format) = request.format.as_deref() {
+            if !STT_AUDIO_FORMATS.contains(&format) {
+                return Err(format!(
+                    "Unsupported format '{}': must be one of {:?}",
+                    format, STT_AUDIO_FORMATS
+                ));
+            }
+        }
+
+        validate_idempotency_key(&request.idempotency_key)?;
+        if let Some(key) = &request.idempotency_key {
+            if let Some(pair_id) =
+                self.idempotent_pair_id(key, request.api_key.as_deref(), &RequestType::STT)
+            {
+                match self.load_audio_text_pair_by_id(&pair_id).await {
+                    Ok(pair) => {
+                        let detected_language = pair
+                            .metadata
+                            .iter()
+                            .find(|(k, _)| k == "detected_language")
+                            .map(|(_, v)| v.clone());
+                        return Ok(SttPrep::Answered(SttRes {
+                            text: pair.text,
+                            provider: pair.provider,
+                            audio_info: pair.audio_info,
+                            raw: None,
+                            detected_language,
+                        }));
+                    }
+                    Err(e) => self.log(
+                        LogLevel::Warn,
+                        &request_id,
+                        format_args!(
+                            "Idempotency key {} refers to unreadable pair {}, processing again: {}",
+                            key, pair_id, e
+                        ),
+                    ),
+                }
+            }
+        }
+
+        self.ensure_pair_id_unused(&request_id)?;
+
+        let mut audio_bytes = BASE64
+            .decode(&request.audio_data)
+            .map_err(|e| format!("audio_data is not valid base64: {}", e))?;
+        if audio_bytes.len() as u64 > max_audio_bytes {
+            return Err(format!(
+                "audio exceeds maximum size of {} bytes",
+                max_audio_bytes
+            ));
+        }
+
+        // Trimmed audio is what gets transcribed, billed and stored
+        let mut trimmed_duration_ms = None;
+        if request.trim_silence == Some(true) {
+            let format = stt_audio_format(request.format.as_deref(), &audio_bytes);
+            match (format == "wav")
+                .then(|| audio::trim_wav_silence(&audio_bytes))
+                .flatten()
+            {
+                Some(trimmed) => {
+                    trimmed_duration_ms = audio::duration_ms(&trimmed, "wav");
+                    request.audio_data = BASE64.encode(&trimmed);
+                    audio_bytes = trimmed;
+                }
+                None => self.log(
+                    LogLevel::Warn,
+                    &request_id,
+                    format_args!("trim_silence skipped: {} audio can't be decoded", format),
+                ),
+            }
+        }
+
+        // Determine provider
+        let provider = request
+            .provider
+            .clone()
+            .map_or_else(|| self.default_provider(RequestType::STT), Ok)?;
+
+        if request.translate == Some(true) {
+            if provider != Provider::OpenAI {
+                return Err(format!(
+                    "Translation is only supported by OpenAI, not {}",
+                    provider.as_str()
+                ));
+            }
+            if let Some(model) = request
+                .model
+                .as_deref()
+                .filter(|m| *m != OPENAI_TRANSLATION_MODEL)
+            {
+                return Err(format!(
+                    "Translation is only supported by {}, not {}",
+                    OPENAI_TRANSLATION_MODEL, model
+                ));
+            }
+        }
+
+        if !provider.supports_stt() {
+            return Err(format!("{} does not support STT", provider.as_str()));
+        }
+
+        request.profile = self.resolve_profile(&provider, RequestType::STT, request.profile);
+        self.ensure_provider_configured(&provider, request.profile.as_deref())?;
+        if request.model.is_none() && request.translate != Some(true) {
+            request.model = self
+                .get_provider_config(&provider, request.profile.as_deref())
+                .ok()
+                .and_then(|c| c.default_stt_model.clone());
+        }
+
+        Ok(SttPrep::Ready(PreparedStt {
+            request_id,
+            request,
+            provider,
+            audio_bytes,
+            trimmed_duration_ms,
+        }))
+    }
+
+    // Everything stt() does after the provider call: usage, metadata and saving the pair
+    async fn finish_stt(
+        &mut self,
+        prepared: PreparedStt,
+        result: Result<(SttRes, Option<Provider>), String>,
+    ) -> Result<SttRes, String> {
+        let PreparedStt {
+            request_id,
+            request,
+            provider,
+            audio_bytes,
+            trimmed_duration_ms,
+        } = prepared;
+        let (mut response, fallback_from) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.log(
+                    LogLevel::Error,
+                    &request_id,
+                    format_args!("STT failed: {}", e),
+                );
+                self.record_provider_error(&provider, RequestType::STT);
+                return Err(e);
+            }
+        };
+
+        let audio_format = stt_audio_format(request.format.as_deref(), &audio_bytes);
+        response.audio_info = audio::audio_info(&audio_bytes, audio_format);
+
+        let cost_usd = self.stt_cost_usd(
+            &response.provider,
+            request.profile.as_deref(),
+            request.model.as_deref(),
+            &audio_bytes,
+            audio_format,
+        );
+        self.record_usage(
+            &response.provider,
+            RequestType::STT,
+            audio_bytes.len() as u64,
+            cost_usd,
+        );
+
+        let mut metadata = request.metadata.clone().unwrap_or_default();
+        if let Some(failed) = fallback_from {
+            metadata.push(("fallback_from".to_string(), failed.as_str().to_string()));
+        }
+        if request.translate == Some(true) {
+            metadata.push(("translated".to_string(), "true".to_string()));
+        }
+        if let Some(duration_ms) = trimmed_duration_ms {
+            metadata.push(("trimmed_duration_ms".to_string(), duration_ms.to_string()));
+        }
+        if let Some(language) = &response.detected_language {
+            metadata.push(("detected_language".to_string(), language.clone()));
+        }
+
+        // Apply post-processing webhook, keeping the raw transcript in metadata
+        let processed = self.post_process_transcript(&response.text, request.language.as_deref());
+        let pair = transcript_pair(
+            &request_id,
+            &request.audio_data,
+            audio_format,
+            &mut response,
+            metadata,
+            processed,
+        );
+
+        // Save to VFS
+        match self.save_audio_text_pair(&pair, true).await {
+            Ok(()) => {
+                if let Some(key) = &request.idempotency_key {
+                    self.remember_idempotency_key(
+                        key,
+                        request.api_key.as_deref(),
+                        RequestType::STT,
+                        &pair.id,
+                    );
+                }
+            }
+            Err(e) => {
+                self.log(
+                    LogLevel::Error,
+                    &request_id,
+                    format_args!("Failed to save audio-text pair: {}", e),
+                );
+                self.probe_storage().await;
+            }
+        }
+
+        Ok(response)
+    }
+
+    // OpenAI STT implementation
+    async fn handle_openai_stt(&self, request: SttReq, request_id: &str) -> Result<SttRes, String> {
+        let config = self.get_provider_config(&Provider::OpenAI, request.profile.as_deref())?;
+        if request.translate == Some(true) {
+            self.log(
+                LogLevel::Debug,
+                request_id,
+                format_args!("OpenAI translation"),
+            );
+            return self.handle_openai_translation(config, request);
+        }
+        if let Some(base_url) = &config.base_url {
+            self.log(
+                LogLevel::Debug,
+                request_id,
+                format_args!("OpenAI-compatible STT via {}", base_url),
+            );
+            return self.handle_openai_compatible_stt(config, base_url, request);
+        }
+
+        // Create OpenAI STT client
+        let client = TranscriptionClient::new(&config.api_key);
+
+        // Decode base64 audio data
+        let audio_data = BASE64
+            .decode(&request.audio_data)
+            .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+
+        // Map model string to OpenAI model enum (names shared with the pricing table)
+        let model = match openai_stt_model_name(request.model.as_deref()) {
+            "gpt-4o-transcribe" => OpenAISttModel::Gpt4oTranscribe,
+            "gpt-4o-mini-transcribe" => OpenAISttModel::Gpt4oMiniTranscribe,
+            _ => OpenAISttModel::Whisper1, // Default
+        };
+
+        // Build and execute request
+        let filename = stt_upload_filename(request.format.as_deref(), &audio_data);
+        let mut builder = client.transcribe().file(audio_data, &filename).model(model);
+
+        if let Some(lang) = request.language.clone() {
+            builder = builder.language(lang);
+        }
+
+        self.log(
+            LogLevel::Debug,
+            request_id,
+            format_args!(
+                "OpenAI STT model={} filename={}",
+                openai_stt_model_name(request.model.as_deref()),
+                filename
+            ),
+        );
+        let response = builder.execute().await.map_err(|e| {
+            self.log(
+                LogLevel::Debug,
+                request_id,
+                format_args!("OpenAI STT request failed: {:#?}", e),
+            );
+            openai_client_error("OpenAI STT error", format!("{:?}", e))
+        })?;
+
+        // As with TTS, the client hides response headers; report the model and upload
+        let raw = (request.include_raw == Some(true)).then(|| {
+            vec![
+                (
+                    "model".to_string(),
+                    openai_stt_model_name(request.model.as_deref()).to_string(),
+                ),
+                ("filename".to_string(), filename.clone()),
+            ]
+        });
+
+        Ok(SttRes {
+            text: response.text,
+            provider: Provider::OpenAI,
+            audio_info: None,
+            raw,
+            detected_language: None,
+        })
+    }
+}
+
+#[hyperprocess(
+    name = "TTSTT",
+    ui = Some(HttpBindingConfig::default()),
+    endpoints = vec![
+        Binding::Http {
+            path: "/api",
+            config: HttpBindingConfig::new(false, false, false, None)
+        },
+        Binding::Http {
+            path: "/audio/*",
+            config: HttpBindingConfig::new(false, false, false, None)
+        }
+    ],
+    save_config = SaveOptions::EveryMessage,
+    wit_world = "ttstt-dot-os-v0"
+)]
+impl TtsttState {
+    #[init]
+    async fn initialize(&mut self) {
+        add_to_homepage("Spider TTSTT", None, Some("/"), None);
+
+        // Generate initial admin key if not exists
+        if self.admin_key.is_empty() {
+            self.admin_key = format!("ttstt-admin-{}", Uuid::new_v4());
+            println!("Generated admin API key: {}", self.admin_key);
+
+            // Add to API keys list
+            self.api_keys.push(ApiKey {
+                key: self.admin_key.clone(),
+                role: ApiKeyRole::Admin,
+                created_at: Utc::now().to_rfc3339(),
+                name: "Initial Admin Key".to_string(),
+                rate_limit_per_min: None,
+                last_used_at: None,
+            });
+        }
+
+        // Zero-config path: pick up an OpenAI key from the environment
+        if self.import_openai_key(std::env::var(OPENAI_API_KEY_ENV).ok()) {
+            println!("Configured OpenAI provider from {}", OPENAI_API_KEY_ENV);
+        }
+
+        // Ensure storage is initialized
+        if let Err(e) = self.ensure_storage_initialized().await {
+            eprintln!("Failed to initialize storage: {}", e);
+        }
+
+        if self.probe_storage().await {
+            self.prepare_storage().await;
+        }
+
+        if self.preflight_on_init {
+            self.run_preflight();
+        }
+
+        let our_node = our().node.clone();
+        println!("TTSTT initialized on node: {}", our_node);
+    }
+
+    #[local]
+    #[http]
+    async fn tts(&mut self, request: TtsReq) -> Result<TtsRes, String> {
+        self.count_request(RequestType::TTS);
+        // Also the id the pair is stored under
+        let request_id = self.new_pair_id(request.pair_id.as_deref())?;
+        let encoding = AudioEncoding::parse(request.encoding.as_deref())?;
+        // A segmented request's text is its segments' text, whatever was sent
+        let mut request = request;
+        if let Some(segments) = &request.segments {
+            if segments.is_empty() || segments.iter().any(|s| s.text.trim().is_empty()) {
+                return Err("segments must not be empty".to_string());
+            }
+            request.text = segments
+                .iter()
+                .map(|s| s.text.as_str())
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+        if request.text.trim().is_empty() {
+            return Err("text must not be empty".to_string());
+        }
+
+        // Validate API key if provided
+        if let Some(api_key) = &request.api_key {
+            self.validate_api_key(Some(api_key.clone()), false)?;
+            self.consume_rate_limit_token(api_key)?;
+        }
+
+        validate_request_metadata(&request.metadata)?;
+        if let Some(url) = &request.callback_url {
+            url::Url::parse(url).map_err(|e| format!("Invalid callback URL: {}", e))?;
+        }
+
+        validate_idempotency_key(&request.idempotency_key)?;
+        if let Some(key) = &request.idempotency_key {
+            if let Some(pair_id) =
+                self.idempotent_pair_id(key, request.api_key.as_deref(), &RequestType::TTS)
+            {
+                match self.load_audio_text_pair_by_id(&pair_id).await {
+                    Ok(pair) => {
+                        return Ok(TtsRes {
+                            audio_data: if request.store_only == Some(true) {
+                                String::new()
+                            } else {
+                                encoding.encode(pair.audio_data)?
+                            },
+                            format: pair.audio_format,
+                            provider: pair.provider,
+                            audio_info: pair.audio_info,
+                            raw: None,
+                            pair_id: Some(pair.id),
+                        })
+                    }
+                    Err(e) => self.log(
+                        LogLevel::Warn,
+                        &request_id,
+                        format_args!(
+                            "Idempotency key {} refers to unreadable pair {}, processing again: {}",
+                            key, pair_id, e
+                        ),
+                    ),
+                }
+            }
+        }
+
+        self.ensure_pair_id_unused(&request_id)?;
+
+        // Determine provider
+        let provider = request
+            .provider
+            .clone()
+            .map_or_else(|| self.default_provider(RequestType::TTS), Ok)?;
+
+        if !provider.supports_tts() {
+            return Err(format!("{} does not support TTS", provider.as_str()));
+        }
+
+        // Don't pay for synthesis that has nowhere to go
+        if request.store_only == Some(true) {
+            self.require_storage()?;
+        }
+
+        request.profile = self.resolve_profile(&provider, RequestType::TTS, request.profile);
+        self.ensure_provider_configured(&provider, request.profile.as_deref())?;
+
+        // Fill unset options: provider config first, then app-level defaults.
+        // Anything still unset falls through to each handler's hardcoded fallback.
+        let provider_config = self
+            .get_provider_config(&provider, request.profile.as_deref())
+            .ok();
+        request.voice = request
+            .voice
+            .or_else(|| provider_config.and_then(|c| c.default_voice.clone()))
+            .or_else(|| self.tts_defaults.voice.clone());
+        request.voice = self.resolve_voice_alias(&provider, request.voice);
+        request.speed = Some(self.resolve_tts_speed(
+            &provider,
+            request.speed,
+            provider_config.and_then(|c| c.default_speed),
+        )?);
+        request.model = request
+            .model
+            .or_else(|| provider_config.and_then(|c| c.default_tts_model.clone()))
+            .or_else(|| self.tts_defaults.model.clone());
+        request.format = request.format.or_else(|| self.tts_defaults.format.clone());
+
+        // Only pcm output can be resampled, and only OpenAI and Azure produce it
+        if let Some(sample_rate) = request.sample_rate {
+            if request.format.as_deref() != Some("pcm")
+                || !matches!(provider, Provider::OpenAI | Provider::Azure)
+            {
+                return Err("sample_rate requires format 'pcm' with OpenAI or Azure".to_string());
+            }
+            if !audio::SUPPORTED_PCM_SAMPLE_RATES.contains(&sample_rate) {
+                return Err(format!(
+                    "Unsupported sample_rate {}: must be one of {:?}",
+                    sample_rate,
+                    audio::SUPPORTED_PCM_SAMPLE_RATES
+                ));
+            }
+        }
+        if let Some(bit_depth) = request.bit_depth {
+            if request.format.as_deref() != Some("pcm") || request.raw_pcm == Some(true) {
+                return Err("bit_depth requires format 'pcm' wrapped as wav".to_string());
+            }
+            if !audio::SUPPORTED_WAV_BIT_DEPTHS.contains(&bit_depth) {
+                return Err(format!(
+                    "Unsupported bit_depth {}: must be one of {:?}",
+                    bit_depth,
+                    audio::SUPPORTED_WAV_BIT_DEPTHS
+                ));
+            }
+        }
+
+        let mut metadata = request.metadata.clone().unwrap_or_default();
+        if let Some(sample_rate) = request.sample_rate {
+            metadata.push(("sample_rate".to_string(), sample_rate.to_string()));
+        }
+        if let Some(bit_depth) = request.bit_depth {
+            metadata.push(("bit_depth".to_string(), bit_depth.to_string()));
+        }
+
+        // Optional text normalization; SSML is left alone so markup isn't rewritten. The
+        // pair keeps the caller's text, with what was spoken in metadata.
+        let text = request.text.clone();
+        // Each segment gets its own voice and expansion locale; the breakdown kept in
+        // metadata has the resolved voices
+        let mut segment_breakdown = None;
+        let segment_requests = match request.segments.take() {
+            Some(segments) => {
+                let mut requests = Vec::with_capacity(segments.len());
+                let mut breakdown = Vec::with_capacity(segments.len());
+                for segment in segments {
+                    let mut segment_request = segment_request(&request, &segment);
+                    segment_request.voice =
+                        self.resolve_voice_alias(&provider, segment_request.voice);
+                    if let Some(spoken) = spoken_text(&segment_request) {
+                        segment_request.text = spoken;
+                    }
+                    breakdown.push(TtsSegment {
+                        voice: segment_request.voice.clone(),
+                        ..segment
+                    });
+                    requests.push(segment_request);
+                }
+                let breakdown = serde_json::to_string(&breakdown)
+                    .map_err(|e| format!("Failed to serialize segments: {}", e))?;
+                metadata.push((TTS_SEGMENTS_METADATA_KEY.to_string(), breakdown.clone()));
+                segment_breakdown = Some(breakdown);
+                Some(requests)
+            }
+            None => None,
+        };
+        let spoken = match &segment_requests {
+            Some(requests) => Some(
+                requests
+                    .iter()
+                    .map(|r| r.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            None => spoken_text(&request),
+        };
+        if let Some(spoken) = spoken {
+            if spoken != text {
+                metadata.push(("spoken_text".to_string(), spoken.clone()));
+                request.text = spoken;
+            }
+        }
+
+        // Checked after expansion since that is what the provider receives
+        if let Some(max_chars) = self.max_text_chars.or(provider.max_tts_chars()) {
+            let chars = request.text.chars().count();
+            if chars > max_chars as usize {
+                return Err(format!(
+                    "text is {} characters, over the {} character limit for {}",
+                    chars,
+                    max_chars,
+                    provider.as_str()
+                ));
+            }
+        }
+
+        // Handle request based on provider
+        self.log(
+            LogLevel::Info,
+            &request_id,
+            format_args!("TTS via {}", provider.as_str()),
+        );
+        self.acquire_provider_slot(&provider).await?;
+        let result = match &segment_requests {
+            Some(requests) => {
+                self.synthesize_segments(&provider, requests, &request_id)
+                    .await
+            }
+            None => {
+                self.synthesize(&provider, request.clone(), &request_id)
+                    .await
+            }
+        };
+        self.end_provider_call(&provider);
+        let mut response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.log(
+                    LogLevel::Error,
+                    &request_id,
+                    format_args!("TTS failed: {}", e),
+                );
+                self.record_provider_error(&provider, RequestType::TTS);
+                return Err(e);
+            }
+        };
+
+        // Resample pcm to the requested rate, then wrap it as wav since browsers can't play
+        // headerless pcm, unless asked not to
+        let sample_rate = request.sample_rate.unwrap_or(audio::OPENAI_PCM_SAMPLE_RATE);
+        if response.format == "pcm" {
+            let mut pcm = BASE64
+                .decode(&response.audio_data)
+                .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+            if sample_rate != audio::OPENAI_PCM_SAMPLE_RATE {
+                pcm = audio::resample_pcm16(&pcm, audio::OPENAI_PCM_SAMPLE_RATE, sample_rate);
+            }
+            if request.raw_pcm == Some(true) {
+                response.audio_data = BASE64.encode(&pcm);
+            } else {
+                response.audio_data = BASE64.encode(audio::wrap_pcm_as_wav(
+                    &pcm,
+                    sample_rate,
+                    request
+                        .bit_depth
+                        .unwrap_or(audio::OPENAI_PCM_BITS_PER_SAMPLE),
+                ));
+                response.format = "wav".to_string();
+            }
+        }
+
+        // Parse audio details so they are returned and stored with the pair;
+        // headerless pcm carries no rate of its own
+        let audio_bytes = BASE64.decode(&response.audio_data).ok();
+        let (audio_info, duration_ms) = match (audio_bytes.as_deref(), response.format.as_str()) {
+            (Some(bytes), "pcm") => (
+                Some(audio::pcm_audio_info(sample_rate)),
+                Some(audio::pcm_duration_ms(bytes, sample_rate)),
+            ),
+            (Some(bytes), format) => (
+                audio::audio_info(bytes, format),
+                audio::duration_ms(bytes, format),
+            ),
+            (None, _) => (None, None),
+        };
+        response.audio_info = audio_info;
+
+        let characters = request.text.chars().count() as u64;
+        let cost_usd = self
+            .tts_price(
+                &response.provider,
+                request.profile.as_deref(),
+                request.model.as_deref(),
+            )
+            .map(|(_, usd_per_m_chars)| characters as f64 * usd_per_m_chars as f64 / 1_000_000.0)
+            .unwrap_or(0.0);
+        self.record_usage(&response.provider, RequestType::TTS, characters, cost_usd);
+
+        // Identical output already stored is reused rather than saved again, unless the
+        // caller named the pair to store it as. Segment voices are hashed via the breakdown,
+        // so re-voiced segments aren't mistaken for a match.
+        let hash = content_hash(&ContentHashInput {
+            provider: &response.provider,
+            audio_format: &response.format,
+            text: &request.text,
+            voice: segment_breakdown.as_deref().or(request.voice.as_deref()),
+            model: request.model.as_deref(),
+            speed: request.speed,
+            profile: request.profile.as_deref(),
+            sample_rate: request.sample_rate,
+            bit_depth: request.bit_depth,
+        });
+        let existing_id = self.identical_pair_id(&hash, request.pair_id.as_deref());
+        metadata.push((CONTENT_HASH_METADATA_KEY.to_string(), hash));
+
+        // Store audio-text pair to VFS
+        // store_only responses carry no audio, so it moves into the pair rather than being copied
+        let pair = AudioTextPair {
+            id: request_id.clone(),
+            text,
+            audio_data: if request.store_only == Some(true) {
+                std::mem::take(&mut response.audio_data)
+            } else {
+                response.audio_data.clone()
+            },
+            audio_format: response.format.clone(),
+            provider: response.provider.clone(),
+            timestamp: Utc::now().to_rfc3339(),
+            request_type: RequestType::TTS,
+            metadata,
+            duration_ms,
+            audio_info: response.audio_info.clone(),
+        };
+
+        // Save to VFS
+        let mut save_error = None;
+        let stored_id = match existing_id {
+            Some(id) => {
+                self.log(
+                    LogLevel::Info,
+                    &request_id,
+                    format_args!(
+                        "Reusing identical pair {} instead of saving a duplicate",
+                        id
+                    ),
+                );
+                self.touch_pair(&id);
+                Some(id)
+            }
+            // store_only callers get nothing but the pair, so its audio must be on disk
+            None => match self
+                .save_audio_text_pair(&pair, request.store_only != Some(true))
+                .await
+            {
+                Ok(()) => Some(pair.id.clone()),
+                Err(e) => {
+                    self.log(
+                        LogLevel::Error,
+                        &request_id,
+                        format_args!("Failed to save audio-text pair: {}", e),
+                    );
+                    self.probe_storage().await;
+                    save_error = Some(e);
+                    None
+                }
+            },
+        };
+        let stored = stored_id.is_some();
+        response.pair_id = stored_id.clone();
+        if let (Some(key), Some(id)) = (&request.idempotency_key, &stored_id) {
+            self.remember_idempotency_key(key, request.api_key.as_deref(), RequestType::TTS, id);
+        }
+
+        if let Some(url) = request.callback_url {
+            spawn(send_completion_callback(
+                url,
+                CompletionCallback {
+                    id: stored_id.unwrap_or(pair.id),
+                    provider: pair.provider,
+                    format: pair.audio_format,
+                    duration_ms: pair.duration_ms,
+                    stored,
+                },
+            ));
+        }
+
+        if let Some(e) = save_error.filter(|_| request.store_only == Some(true)) {
+            return Err(format!("Failed to store audio: {}", e));
+        }
+        response.audio_data = encoding.encode(response.audio_data)?;
+        Ok(response)
+    }
+
+    #[local]
+    #[http]
+    async fn stt(&mut self, request: SttReq) -> Result<SttRes, String> {
+        let prepared = match self.prepare_stt(request).await? {
+            SttPrep::Answered(response) => return Ok(response),
+            SttPrep::Ready(prepared) => prepared,
+        };
+
+        // Handle request based on provider
+        self.log(
+            LogLevel::Info,
+            &prepared.request_id,
+            format_args!("STT via {}", prepared.provider.as_str()),
+        );
+        self.acquire_provider_slot(&prepared.provider).await?;
+        let result = self
+            .transcribe(&prepared.provider, &prepared.request, &prepared.request_id)
+            .await;
+        self.end_provider_call(&prepared.provider);
+        self.finish_stt(prepared, result).await
+    }
+
+    // Items are prepared and saved one at a time through the same steps as stt(), so each
+    // is validated, rate limited and saved on its own and a failing item doesn't affect the
+    // rest. OpenAI calls run up to options.max_concurrency at a time (see BatchOptions for
+    // why other providers don't overlap), and never more than max_concurrent_calls allows.
+    // Each result carries its input index; with preserve_order (the default) results line
+    // up with the inputs, otherwise they come in completion order.
+    #[local]
+    #[http]
+    async fn stt_batch(&mut self, request: SttBatchReq) -> Result<Vec<SttBatchItem>, String> {
+        if request.items.len() > MAX_STT_BATCH_ITEMS {
+            return Err(format!(
+                "Batch has {} items; at most {} are allowed",
+                request.items.len(),
+                MAX_STT_BATCH_ITEMS
+            ));
+        }
+        let options = request.options.unwrap_or_default();
+        if options.max_concurrency == 0 {
+            return Err("max_concurrency must be at least 1".to_string());
+        }
+
+        let mut results = Vec::with_capacity(request.items.len());
+        let mut ready = Vec::new();
+        for (index, mut item) in request.items.into_iter().enumerate() {
+            item.api_key = request.api_key.clone();
+            match self.prepare_stt(item).await {
+                Ok(SttPrep::Answered(response)) => {
+                    results.push(SttBatchItem::new(index, Ok(response)))
+                }
+                Ok(SttPrep::Ready(prepared)) => ready.push((index, prepared)),
+                Err(e) => results.push(SttBatchItem::new(index, Err(e))),
+            }
+        }
+
+        // Our own calls hold their slots until the wave finishes, so a wave never asks
+        // for more slots than exist
+        let wave_size = self
+            .max_concurrent_calls
+            .map_or(options.max_concurrency, |max| {
+                max.clamp(1, options.max_concurrency)
+            }) as usize;
+        while !ready.is_empty() {
+            let mut wave = Vec::new();
+            for (index, prepared) in ready.drain(..wave_size.min(ready.len())) {
+                match self.acquire_provider_slot(&prepared.provider).await {
+                    Ok(()) => {
+                        self.log(
+                            LogLevel::Info,
+                            &prepared.request_id,
+                            format_args!("STT via {}", prepared.provider.as_str()),
+                        );
+                        wave.push((index, prepared));
+                    }
+                    Err(e) => results.push(SttBatchItem::new(index, Err(e))),
+                }
+            }
+
+            let this = &*self;
+            let outputs = join_unordered(
+                wave.iter()
+                    .map(|(_, p)| this.transcribe(&p.provider, &p.request, &p.request_id))
+                    .collect(),
+            )
+            .await;
+
+            let mut wave: Vec<_> = wave.into_iter().map(Some).collect();
+            for (position, result) in outputs {
+                let (index, prepared) = wave[position].take().unwrap();
+                self.end_provider_call(&prepared.provider);
+                let result = self.finish_stt(prepared, result).await;
+                results.push(SttBatchItem::new(index, result));
+            }
+        }
+
+        if options.preserve_order {
+            results.sort_by_key(|item| item.index);
+        }
+        Ok(results)
+    }
+
+    // Both halves run through stt() and tts(), so each is rate limited, billed and saved
+    // as usual. The speech pair links to the transcript's pair from the start; the
+    // transcript only links back once tts() reports the pair it stored.
+    #[local]
+    #[http]
+    async fn stt_then_tts(&mut self, request: SttThenTtsReq) -> Result<SttThenTtsRes, String> {
+        let stt_pair_id = Uuid::new_v4().to_string();
+
+        let transcript = self
+            .stt(SttReq {
+                audio_data: request.audio_data,
+                provider: request.stt_provider,
+                model: None,
+                language: request.language,
+                api_key: request.api_key.clone(),
+                metadata: None,
+                include_raw: None,
+                profile: None,
+                translate: None,
+                idempotency_key: None,
+                pair_id: Some(stt_pair_id.clone()),
+                encoding: None,
+                trim_silence: None,
+                format: None,
+            })
+            .await?;
+
+        let speech = self
+            .tts(TtsReq {
+                text: transcript.text.clone(),
+                provider: request.tts_provider,
+                voice: request.voice,
+                model: request.model,
+                format: request.format,
+                speed: None,
+                api_key: request.api_key,
+                metadata: Some(vec![(
+                    STT_PAIR_METADATA_KEY.to_string(),
+                    stt_pair_id.clone(),
+                )]),
+                expand_numbers: None,
+                normalize: None,
+                locale: None,
+                callback_url: None,
+                raw_pcm: None,
+                web_compatible: None,
+                sample_rate: None,
+                bit_depth: None,
+                store_only: None,
+                idempotency_key: None,
+                pair_id: None,
+                encoding: None,
+                include_raw: None,
+                segments: None,
+                profile: None,
+            })
+            .await;
+
+        if let Some(link) = tts_pair_link(&speech) {
+            if let Err(e) = self
+                .merge_pair_metadata(&stt_pair_id, vec![link], false)
+                .await
+            {
+                self.log(
+                    LogLevel::Warn,
+                    &stt_pair_id,
+                    format_args!("Failed to link transcript to its speech pair: {}", e),
+                );
+            }
+        }
+        let speech = speech
+            .map_err(|e| format!("Transcribed as pair {}, but TTS failed: {}", stt_pair_id, e))?;
+
+        Ok(SttThenTtsRes {
+            transcript,
+            tts_pair_id: speech.pair_id.clone(),
+            speech,
+            stt_pair_id,
+        })
+    }
+
+    // Progressive STT: audio arrives in chunks, `flush` returns an interim transcript of
+    // everything so far, and `is_final` runs a normal stt() over the whole recording.
+    // Interim transcripts are billed like any other call but never stored. If the final
+    // call fails the session is kept, so it can be retried with empty audio_data.
+    #[local]
+    #[http]
+    async fn stt_stream(&mut self, request: SttStreamReq) -> Result<SttStreamRes, String> {
+        if let Some(api_key) = &request.api_key {
+            self.validate_api_key(Some(api_key.clone()), false)?;
+        }
+        self.prune_stt_streams();
+
+        let chunk = BASE64
+            .decode(&request.audio_data)
+            .map_err(|e| format!("audio_data is not valid base64: {}", e))?;
+
+        let session_id = match request.session_id {
+            Some(id) => {
+                let stream = self
+                    .stt_streams
+                    .get(&id)
+                    .ok_or_else(|| format!("Unknown or expired stt_stream session: {}", id))?;
+                if stream.api_key != request.api_key {
+                    return Err(format!("Unknown or expired stt_stream session: {}", id));
+                }
+                id
+            }
+            None => {
+                if self.stt_streams.len() >= MAX_STT_STREAMS {
+                    return Err("Too many open stt_stream sessions".to_string());
+                }
+                validate_request_metadata(&request.metadata)?;
+                let id = Uuid::new_v4().to_string();
+                self.stt_streams.insert(
+                    id.clone(),
+                    SttStream {
+                        api_key: request.api_key.clone(),
+                        audio: Vec::new(),
+                        provider: request.provider,
+                        model: request.model,
+                        language: request.language,
+                        profile: request.profile,
+                        metadata: request.metadata,
+                        last_chunk_at: Utc::now(),
+                    },
+                );
+                id
+            }
+        };
+
+        let max_audio_bytes = self.max_audio_bytes.unwrap_or(DEFAULT_MAX_AUDIO_BYTES);
+        let stream = self
+            .stt_streams
+            .get_mut(&session_id)
+            .ok_or("stt_stream session disappeared")?;
+        if (stream.audio.len() + chunk.len()) as u64 > max_audio_bytes {
+            self.stt_streams.remove(&session_id);
+            return Err(format!(
+                "audio exceeds maximum size of {} bytes",
+                max_audio_bytes
+            ));
+        }
+        stream.audio.extend_from_slice(&chunk);
+        stream.last_chunk_at = Utc::now();
+        let stream = stream.clone();
+        let received_bytes = stream.audio.len() as u64;
+
+        if !request.flush && !request.is_final {
+            return Ok(SttStreamRes {
+                session_id,
+                received_bytes,
+                text: None,
+                provider: None,
+                is_final: false,
+            });
+        }
+
+        let stt_request = SttReq {
+            audio_data: BASE64.encode(&stream.audio),
+            provider: stream.provider.clone(),
+            model: stream.model.clone(),
+            language: stream.language.clone(),
+            api_key: stream.api_key.clone(),
+            metadata: stream.metadata.clone(),
+            include_raw: None,
+            profile: stream.profile.clone(),
+            translate: None,
+            idempotency_key: None,
+            pair_id: None,
+            encoding: None,
+            trim_silence: None,
+            format: None,
+        };
+
+        if request.is_final {
+            let response = self.stt(stt_request).await?;
+            self.stt_streams.remove(&session_id);
+            return Ok(SttStreamRes {
+                session_id,
+                received_bytes,
+                text: Some(response.text),
+                provider: Some(response.provider),
+                is_final: true,
+            });
+        }
+
+        // Interim transcript of the audio so far; same checks as stt(), nothing stored
+        if let Some(api_key) = &stream.api_key {
+            self.consume_rate_limit_token(api_key)?;
+        }
+        let provider = stream
+            .provider
+            .clone()
+            .map_or_else(|| self.default_provider(RequestType::STT), Ok)?;
+        if !provider.supports_stt() {
+            return Err(format!("{} does not support STT", provider.as_str()));
+        }
+        let mut stt_request = stt_request;
+        stt_request.profile =
+            self.resolve_profile(&provider, RequestType::STT, stt_request.profile);
+        self.ensure_provider_configured(&provider, stt_request.profile.as_deref())?;
+
+        self.acquire_provider_slot(&provider).await?;
+        let result = self.transcribe(&provider, &stt_request, &session_id).await;
+        self.end_provider_call(&provider);
+        let (response, _) = result?;
+
+        let audio_format = stt_audio_format(None, &stream.audio);
+        let cost_usd = self.stt_cost_usd(
+            &response.provider,
+            stt_request.profile.as_deref(),
+            stt_request.model.as_deref(),
+            &stream.audio,
+            audio_format,
+        );
+        self.record_usage(
+            &response.provider,
+            RequestType::STT,
+            received_bytes,
+            cost_usd,
+        );
+
+        Ok(SttStreamRes {
+            session_id,
+            received_bytes,
+            text: Some(response.text),
+            provider: Some(response.provider),
+            is_final: false,
+        })
+    }
+
+    #[local]
+    #[http]
+    async fn reset_spend(&mut self, request: ResetSpendReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        for counter in &mut self.usage {
+            if request
+                .provider
+                .as_ref()
+                .is_none_or(|p| *p == counter.provider)
+            {
+                counter.spend_usd = 0.0;
+            }
+        }
+
+        Ok("Spend reset successfully".to_string())
+    }
+
+    // Price a TTS request without synthesizing it
+    #[local]
+    #[http]
+    async fn estimate_tts_cost(&mut self, request: TtsReq) -> Result<CostEstimate, String> {
+        if request.api_key.is_some() {
+            self.validate_api_key(request.api_key.clone(), false)?;
+        }
+
+        let provider = request
+            .provider
+            .clone()
+            .map_or_else(|| self.default_provider(RequestType::TTS), Ok)?;
+
+        // Count what would actually be sent to the provider
+        let characters = match &request.segments {
+            Some(segments) => segments
+                .iter()
+                .map(|s| {
+                    let segment_request = segment_request(&request, s);
+                    spoken_text(&segment_request).unwrap_or(segment_request.text)
+                })
+                .map(|text| text.chars().count() as u32)
+                .sum(),
+            None => spoken_text(&request)
+                .unwrap_or_else(|| request.text.clone())
+                .chars()
+                .count() as u32,
+        };
+
+        let profile = self.resolve_profile(&provider, RequestType::TTS, request.profile.clone());
+        let requested_model = request
+            .model
+            .as_deref()
+            .or(self
+                .get_provider_config(&provider, profile.as_deref())
+                .ok()
+                .and_then(|c| c.default_tts_model.as_deref()))
+            .or(self.tts_defaults.model.as_deref());
+        let (model, usd_per_m_chars) = self
+            .tts_price(&provider, profile.as_deref(), requested_model)
+            .ok_or_else(|| format!("No pricing available for {}", provider.as_str()))?;
+
+        Ok(CostEstimate {
+            characters,
+            estimated_usd: characters as f32 * usd_per_m_chars / 1_000_000.0,
+            model,
+        })
+    }
+
+    #[http]
+    async fn test_tts(&mut self, request: TestTtsReq) -> Result<TtsRes, String> {
+        if request.text.trim().is_empty() {
+            return Err("text must not be empty".to_string());
+        }
+
+        let provider = self.default_provider(RequestType::TTS)?;
+
+        // Create TTS request with defaults; the response names the provider that served it
+        let tts_request = TtsReq {
+            text: request.text,
+            provider: Some(provider),
+            // Left unset so configured defaults apply
+            voice: None,
+            model: None,
+            format: None,
+            speed: None,
+            api_key: None,
+            metadata: Some(vec![(
+                SOURCE_METADATA_KEY.to_string(),
+                TEST_SOURCE.to_string(),
+            )]),
+            expand_numbers: None,
+            normalize: None,
+            locale: None,
+            callback_url: None,
+            raw_pcm: None,
+            web_compatible: None,
+            sample_rate: None,
+            bit_depth: None,
+            store_only: None,
+            idempotency_key: None,
+            pair_id: None,
+            encoding: None,
+            include_raw: None,
+            segments: None,
+            profile: None,
+        };
+
+        // Process request
+        self.tts(tts_request).await
+    }
+
+    // Synthesize a history pair's text again as a new pair that records its source
+    #[local]
+    #[http]
+    async fn regenerate_from_pair(
+        &mut self,
+        request: RegenerateFromPairReq,
+    ) -> Result<TtsRes, String> {
+        let source = self.load_audio_text_pair_by_id(&request.id).await?;
+
+        let tts_request = TtsReq {
+            text: source.text,
+            provider: request.provider,
+            voice: request.voice,
+            model: request.model,
+            format: None,
+            speed: None,
+            api_key: request.api_key,
+            metadata: Some(vec![("regenerated_from".to_string(), request.id)]),
+            expand_numbers: None,
+            normalize: None,
+            locale: None,
+            callback_url: None,
+            raw_pcm: None,
+            web_compatible: None,
+            sample_rate: None,
+            bit_depth: None,
+            store_only: None,
+            idempotency_key: None,
+            pair_id: None,
+            encoding: None,
+            include_raw: None,
+            segments: None,
+            profile: None,
+        };
+
+        self.tts(tts_request).await
+    }
+
+    // Transcribe a history pair's audio again as a new pair that records its source
+    #[local]
+    #[http]
+    async fn retranscribe_pair(&mut self, request: RetranscribePairReq) -> Result<SttRes, String> {
+        let source = self.load_audio_text_pair_by_id(&request.id).await?;
+
+        let stt_request = SttReq {
+            audio_data: source.audio_data,
+            provider: request.provider,
+            model: request.model,
+            language: request.language,
+            api_key: request.api_key,
+            metadata: Some(vec![("retranscribed_from".to_string(), request.id)]),
+            include_raw: None,
+            profile: None,
+            translate: None,
+            idempotency_key: None,
+            pair_id: None,
+            encoding: None,
+            trim_silence: None,
+            // Formats STT can't take by name (pcm, aac, ...) are sniffed instead
+            format: STT_AUDIO_FORMATS
+                .contains(&source.audio_format.as_str())
+                .then_some(source.audio_format),
+        };
+
+        self.stt(stt_request).await
+    }
+
+    #[http]
+    async fn test_stt(&mut self, request: TestSttReq) -> Result<SttRes, String> {
+        let provider = self.default_provider(RequestType::STT)?;
+
+        // Create STT request with defaults; the response names the provider that served it
+        let stt_request = SttReq {
+            audio_data: request.audio_data,
+            provider: Some(provider),
+            model: None,
+            language: None,
+            api_key: None,
+            metadata: Some(vec![(
+                SOURCE_METADATA_KEY.to_string(),
+                TEST_SOURCE.to_string(),
+            )]),
+            include_raw: None,
+            profile: None,
+            translate: None,
+            idempotency_key: None,
+            pair_id: None,
+            encoding: None,
+            trim_silence: None,
+            format: None,
+        };
+
+        // Process request
+        self.stt(stt_request).await
+    }
+
+    #[local]
+    #[http]
+    async fn add_provider(&mut self, request: AddProviderReq) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        let config = request.config;
+
+        if config.profile_name.trim().is_empty() {
+            return Err("profile_name must not be empty".to_string());
+        }
+        if config.is_default_tts && !config.provider.supports_tts() {
+            return Err(format!("{:?} does not support TTS", config.provider));
+        }
+        if config.is_default_stt && !config.provider.supports_stt() {
+            return Err(format!("{:?} does not support STT", config.provider));
+        }
+        if let Some(base_url) = &config.base_url {
+            url::Url::parse(base_url).map_err(|e| format!("Invalid base_url: {}", e))?;
+        }
+        if config.provider == Provider::AwsPolly
+            && (config.region.is_none() || config.aws_credentials.is_none())
+        {
+            return Err("AwsPolly requires region and aws_credentials".to_string());
+        }
+        if config.provider == Provider::Webhook && config.base_url.is_none() {
+            return Err("Webhook requires base_url".to_string());
+        }
+        // Configured defaults are always checked strictly
+        if let Some(speed) = config.default_speed {
+            check_speed(&config.provider, speed, true)?;
+        }
+
+        // Replace any existing config for this provider profile; its last check no longer applies
+        self.providers
+            .retain(|p| !(p.provider == config.provider && p.profile_name == config.profile_name));
+        self.provider_health
+            .retain(|(p, name, _)| !(*p == config.provider && *name == config.profile_name));
+
+        // Update default providers if needed
+        if config.is_default_tts {
+            self.default_tts_provider = Some(config.provider.clone());
+            // Clear other default TTS
+            for provider in &mut self.providers {
+                provider.is_default_tts = false;
+            }
+        }
+
+        if config.is_default_stt {
+            self.default_stt_provider = Some(config.provider.clone());
+            // Clear other default STT
+            for provider in &mut self.providers {
+                provider.is_default_stt = false;
+            }
+        }
+
+        let target = format!("{:?}/{}", config.provider, config.profile_name);
+        self.providers.push(config);
+        self.audit(&actor, "add_provider", target).await;
+
+        Ok("Provider added successfully".to_string())
+    }
+
+    #[local]
+    #[http]
+    async fn remove_provider(&mut self, request: RemoveProviderReq) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        let provider = request.provider;
+
+        self.providers.retain(|p| {
+            p.provider != provider
+                || request
+                    .profile_name
+                    .as_ref()
+                    .is_some_and(|name| *name != p.profile_name)
+        });
+        let providers = &self.providers;
+        self.provider_health.retain(|(p, name, _)| {
+            providers
+                .iter()
+                .any(|c| c.provider == *p && c.profile_name == *name)
+        });
+
+        // Clear defaults once no profile of the provider is left
+        if !self.providers.iter().any(|p| p.provider == provider) {
+            if self.default_tts_provider == Some(provider.clone()) {
+                self.default_tts_provider = None;
+            }
+            if self.default_stt_provider == Some(provider.clone()) {
+                self.default_stt_provider = None;
+            }
+        }
+
+        let target = match &request.profile_name {
+            Some(name) => format!("{:?}/{}", provider, name),
+            None => format!("{:?}", provider),
+        };
+        self.audit(&actor, "remove_provider", target).await;
+
+        Ok("Provider removed successfully".to_string())
+    }
+
+    #[local]
+    #[http]
+    async fn get_providers(&self) -> Result<Vec<ProviderInfo>, String> {
+        // Return providers without API keys
+        let safe_providers: Vec<ProviderInfo> = self
+            .providers
+            .iter()
+            .map(|p| ProviderInfo {
+                provider: p.provider.clone(),
+                profile_name: p.profile_name.clone(),
+                is_default_tts: p.is_default_tts,
+                is_default_stt: p.is_default_stt,
+                default_voice: p.default_voice.clone(),
+                default_speed: p.default_speed,
+                default_tts_model: p.default_tts_model.clone(),
+                default_stt_model: p.default_stt_model.clone(),
+                total_spend: self.provider_spend(&p.provider),
+                healthy: self.provider_healthy(&p.provider, &p.profile_name),
+            })
+            .collect();
+
+        Ok(safe_providers)
+    }
+
+    // Capabilities of each configured provider, plus Local when a local STT service is set
+    #[local]
+    #[http]
+    async fn get_provider_capabilities(&self) -> Result<Vec<ProviderCapabilities>, String> {
+        let mut providers: Vec<Provider> = Vec::new();
+        for config in &self.providers {
+            if !providers.contains(&config.provider) {
+                providers.push(config.provider.clone());
+            }
+        }
+        if self.local_stt.is_some() && !providers.contains(&Provider::Local) {
+            providers.push(Provider::Local);
+        }
+
+        Ok(providers.iter().map(Provider::capabilities).collect())
+    }
+
+    // Everything in each provider config, for auditing; keys are masked
+    #[local]
+    #[http]
+    async fn get_providers_admin(
+        &mut self,
+        request: GetProvidersAdminReq,
+    ) -> Result<Vec<ProviderAdminInfo>, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        Ok(self
+            .providers
+            .iter()
+            .map(|p| ProviderAdminInfo {
+                provider: p.provider.clone(),
+                profile_name: p.profile_name.clone(),
+                api_key_masked: mask_secret(&p.api_key),
+                is_default_tts: p.is_default_tts,
+                is_default_stt: p.is_default_stt,
+                default_voice: p.default_voice.clone(),
+                default_speed: p.default_speed,
+                default_tts_model: p.default_tts_model.clone(),
+                default_stt_model: p.default_stt_model.clone(),
+                user_id: p.user_id.clone(),
+                region: p.region.clone(),
+                base_url: p.base_url.clone(),
+                aws_access_key_id_masked: p
+                    .aws_credentials
+                    .as_ref()
+                    .map(|c| mask_secret(&c.access_key_id)),
+                total_spend: self.provider_spend(&p.provider),
+                healthy: self.provider_healthy(&p.provider, &p.profile_name),
+            })
+            .collect())
+    }
+
+    #[local]
+    #[http]
+    async fn set_default_provider(
+        &mut self,
+        request: SetDefaultProviderReq,
+    ) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        let provider = request.provider;
+        let provider_type = request.provider_type.as_str();
+
+        // Ensure provider exists
+        let profile_name = self
+            .get_provider_config(&provider, request.profile_name.as_deref())
+            .map_err(|_| "Provider not configured".to_string())?
+            .profile_name
+            .clone();
+
+        match provider_type {
+            "tts" => {
+                if !provider.supports_tts() {
+                    return Err(format!("{:?} does not support TTS", provider));
+                }
+
+                // Clear other defaults and set new one
+                for p in &mut self.providers {
+                    p.is_default_tts = p.provider == provider && p.profile_name == profile_name;
+                }
+                self.default_tts_provider = Some(provider.clone());
+            }
+            "stt" => {
+                if !provider.supports_stt() {
+                    return Err(format!("{:?} does not support STT", provider));
+                }
+
+                // Clear other defaults and set new one
+                for p in &mut self.providers {
+                    p.is_default_stt = p.provider == provider && p.profile_name == profile_name;
+                }
+                self.default_stt_provider = Some(provider.clone());
+            }
+            _ => return Err("Invalid type: must be 'tts' or 'stt'".to_string()),
+        }
+
+        let target = format!("{:?}/{} ({})", provider, profile_name, provider_type);
+        self.audit(&actor, "set_default_provider", target).await;
+
+        Ok("Default provider set successfully".to_string())
+    }
+
+    #[local]
+    #[http]
+    async fn generate_api_key(
+        &mut self,
+        request: GenerateApiKeyReq,
+    ) -> Result<GenerateApiKeyRes, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        let name = request.name;
+        let role = request.role;
+        if request.rate_limit_per_min == Some(0) {
+            return Err("rate_limit_per_min must be at least 1".to_string());
+        }
+        self.check_key_name_unused(&name, None)?;
+
+        let new_key = ApiKey {
+            key: format!(
+                "ttstt-{}-{}",
+                if matches!(role, ApiKeyRole::Admin) {
+                    "admin"
+                } else {
+                    "req"
+                },
+                Uuid::new_v4()
+            ),
+            role: role.clone(),
+            created_at: Utc::now().to_rfc3339(),
+            name: name.to_string(),
+            rate_limit_per_min: request.rate_limit_per_min,
+            last_used_at: None,
+        };
+
+        let key_value = new_key.key.clone();
+        let name_clone = name.clone();
+        self.api_keys.push(new_key);
+        self.audit(&actor, "generate_api_key", name).await;
+
+        // Return the newly generated key
+        Ok(GenerateApiKeyRes {
+            key: key_value,
+            name: name_clone,
+            role,
+        })
+    }
+
+    #[local]
+    #[http]
+    async fn revoke_api_key(&mut self, request: RevokeApiKeyReq) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        let name = self
+            .api_keys
+            .iter()
+            .find(|k| k.key == request.key_to_revoke)
+            .map(|k| k.name.clone());
+        self.remove_api_key(&request.key_to_revoke)?;
+        // Unknown keys are a no-op and not worth recording
+        if let Some(name) = name {
+            self.audit(&actor, "revoke_api_key", name).await;
+        }
+
+        Ok("API key revoked successfully".to_string())
+    }
+
+    // Names are unique for keys generated since names were enforced, but older
+    // duplicates may remain, so an ambiguous name is an error rather than a guess
+    #[local]
+    #[http]
+    async fn revoke_api_key_by_name(
+        &mut self,
+        request: RevokeApiKeyByNameReq,
+    ) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        let matches: Vec<String> = self
+            .api_keys
+            .iter()
+            .filter(|k| k.name == request.name)
+            .map(|k| k.key.clone())
+            .collect();
+        match matches.as_slice() {
+            [] => Err(format!("No API key named {}", request.name)),
+            [key] => {
+                self.remove_api_key(key)?;
+                self.audit(&actor, "revoke_api_key", request.name).await;
+                Ok("API key revoked successfully".to_string())
+            }
+            _ => Err(format!(
+                "{} API keys are named {}; revoke by key instead",
+                matches.len(),
+                request.name
+            )),
+        }
+    }
+
+    // The last admin key can't be demoted, or no one could manage the app
+    #[local]
+    #[http]
+    async fn set_api_key_role(&mut self, request: SetApiKeyRoleReq) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        if request.role != ApiKeyRole::Admin && self.is_last_admin(&request.key_to_modify) {
+            return Err("cannot remove last admin".to_string());
+        }
+        let entry = self
+            .api_keys
+            .iter_mut()
+            .find(|k| k.key == request.key_to_modify)
+            .ok_or("API key not found")?;
+        entry.role = request.role;
+        let name = entry.name.clone();
+        self.audit(&actor, "set_api_key_role", name).await;
+
+        Ok("API key role updated successfully".to_string())
+    }
+
+    // Change a key's tts/stt rate limit; its bucket restarts full at the new size
+    #[local]
+    #[http]
+    async fn set_rate_limit(&mut self, request: SetRateLimitReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        if request.rate_limit_per_min == Some(0) {
+            return Err("rate_limit_per_min must be at least 1".to_string());
+        }
+        let entry = self
+            .api_keys
+            .iter_mut()
+            .find(|k| k.key == request.key)
+            .ok_or("API key not found")?;
+        entry.rate_limit_per_min = request.rate_limit_per_min;
+        self.rate_buckets.remove(&request.key);
+
+        Ok("Rate limit updated successfully".to_string())
+    }
+
+    #[local]
+    #[http]
+    async fn list_api_keys(&mut self, request: ListApiKeysReq) -> Result<Vec<ApiKeyInfo>, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        // Return keys without actual key values
+        let safe_keys: Vec<ApiKeyInfo> = self
+            .api_keys
+            .iter()
+            .map(|k| ApiKeyInfo {
+                name: k.name.clone(),
+                role: k.role.clone(),
+                created_at: k.created_at.clone(),
+                key_preview: format!("{}...", &k.key[..20.min(k.key.len())]),
+                rate_limit_per_min: k.rate_limit_per_min,
+                last_used_at: k.last_used_at.clone(),
+            })
+            .collect();
 
-        // Create OpenAI TTS client
-        let client = SpeechClient::new(&config.api_key);
+        Ok(safe_keys)
+    }
 
-        // Map voice string to OpenAI voice enum, use provider default if not specified
-        let voice_str = request
-            .voice
-            .as_deref()
-            .or(config.default_voice.as_deref())
-            .unwrap_or("nova");
+    #[local]
+    #[http]
+    async fn get_audit_log(&mut self, request: GetAuditLogReq) -> Result<AuditLogPage, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        let voice = match voice_str {
-            "alloy" => OpenAIVoice::Alloy,
-            "ash" => OpenAIVoice::Ash,
-            "ballad" => OpenAIVoice::Ballad,
-            "coral" => OpenAIVoice::Coral,
-            "echo" => OpenAIVoice::Echo,
-            "fable" => OpenAIVoice::Fable,
-            "onyx" => OpenAIVoice::Onyx,
-            "nova" => OpenAIVoice::Nova,
-            "sage" => OpenAIVoice::Sage,
-            "shimmer" => OpenAIVoice::Shimmer,
-            "verse" => OpenAIVoice::Verse,
-            _ => OpenAIVoice::Nova, // Default to Nova
+        let limit = request.limit.unwrap_or(100) as usize;
+        let offset = request.offset.unwrap_or(0) as usize;
+
+        let bytes = match open_file_async(&self.audit_log_path(), false, Some(5)).await {
+            Ok(file) => file
+                .read()
+                .await
+                .map_err(|e| format!("Failed to read audit log: {:?}", e))?,
+            // Nothing has been audited yet
+            Err(_) => Vec::new(),
         };
+        let mut entries: Vec<AuditEntry> = Vec::new();
+        for line in bytes.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            match serde_json::from_slice(line) {
+                Ok(entry) => entries.push(entry),
+                // A write cut short leaves a partial last line
+                Err(e) => eprintln!("Skipping unreadable audit entry: {}", e),
+            }
+        }
 
-        // Map model string to OpenAI model enum
-        let model = match request.model.as_deref() {
-            Some("tts-1") => OpenAITtsModel::Tts1,
-            Some("tts-1-hd") => OpenAITtsModel::Tts1Hd,
-            Some("gpt-4o-mini-tts") => OpenAITtsModel::Gpt4oMiniTts,
-            _ => OpenAITtsModel::Gpt4oMiniTts, // Default to gpt-4o-mini-tts
+        let total = entries.len();
+        let entries: Vec<AuditEntry> = entries.into_iter().rev().skip(offset).take(limit).collect();
+        Ok(AuditLogPage {
+            has_more: offset + entries.len() < total,
+            entries,
+            total: total as u32,
+        })
+    }
+
+    #[local]
+    #[http]
+    async fn get_history(&mut self, request: GetHistoryReq) -> Result<Vec<AudioTextPair>, String> {
+        Ok(self.load_history_page(request).await?.pairs)
+    }
+
+    // Same as get_history, plus the total count and whether another page exists
+    #[local]
+    #[http]
+    async fn get_history_page(&mut self, request: GetHistoryReq) -> Result<HistoryPage, String> {
+        self.load_history_page(request).await
+    }
+
+    // Counted from the pair index, so no metadata.json or audio is read
+    #[local]
+    #[http]
+    async fn count_history(&self, request: CountHistoryReq) -> Result<u32, String> {
+        self.require_storage()?;
+        let exclude_test = request.exclude_test.unwrap_or(false);
+
+        let count = self
+            .pair_index
+            .iter()
+            .filter(|e| !(exclude_test && e.is_test))
+            .filter(|e| {
+                request
+                    .provider
+                    .as_ref()
+                    .is_none_or(|p| e.provider.as_ref() == Some(p))
+            })
+            .filter(|e| {
+                request
+                    .request_type
+                    .as_ref()
+                    .is_none_or(|t| e.request_type.as_ref() == Some(t))
+            })
+            .count();
+
+        Ok(count as u32)
+    }
+
+    // Filters on the index, whose timestamps come from each pair's metadata.json, so
+    // only matching pairs are loaded
+    #[local]
+    #[http]
+    async fn get_history_since(
+        &mut self,
+        request: GetHistorySinceReq,
+    ) -> Result<Vec<AudioTextPair>, String> {
+        self.require_storage()?;
+        let since = chrono::DateTime::parse_from_rfc3339(&request.timestamp)
+            .map_err(|e| format!("Invalid timestamp '{}': {}", request.timestamp, e))?;
+        let limit = request.limit.unwrap_or(100) as usize;
+        let include_audio = request.include_audio.unwrap_or(false);
+        let exclude_test = request.exclude_test.unwrap_or(false);
+
+        let mut newer: Vec<(chrono::DateTime<chrono::FixedOffset>, String)> = self
+            .pair_index
+            .iter()
+            .filter(|e| !(exclude_test && e.is_test))
+            .filter_map(|e| {
+                chrono::DateTime::parse_from_rfc3339(&e.timestamp)
+                    .ok()
+                    .filter(|timestamp| *timestamp > since)
+                    .map(|timestamp| (timestamp, e.id.clone()))
+            })
+            .collect();
+        newer.sort();
+
+        let mut pairs = Vec::new();
+        for (_, id) in newer.into_iter().take(limit) {
+            let path = self.pair_path(&id);
+            match self
+                .load_audio_text_pair_by_path(&path, include_audio)
+                .await
+            {
+                Ok(pair) => pairs.push(pair),
+                Err(e) => eprintln!("Failed to load pair from {}: {}", path, e),
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    #[local]
+    #[http]
+    async fn get_audio_text_pair(
+        &mut self,
+        request: GetAudioTextPairReq,
+    ) -> Result<AudioTextPair, String> {
+        // Load from VFS
+        let pair = self.load_audio_text_pair_by_id(&request.id).await?;
+        self.touch_pair(&pair.id);
+
+        Ok(pair)
+    }
+
+    // A slice of a pair's audio, for range requests when scrubbing long recordings
+    #[local]
+    #[http]
+    async fn get_audio_range(
+        &mut self,
+        request: GetAudioRangeReq,
+    ) -> Result<AudioRangeRes, String> {
+        if !is_valid_pair_id(&request.id) {
+            return Err(format!("Invalid pair id: {}", request.id));
+        }
+        if request.length == 0 || request.length > MAX_AUDIO_RANGE_BYTES {
+            return Err(format!(
+                "length must be between 1 and {} bytes",
+                MAX_AUDIO_RANGE_BYTES
+            ));
+        }
+        self.require_storage()?;
+
+        let path = self.pair_path(&request.id);
+        let metadata = self.read_pair_metadata(&path).await?;
+        let (audio, total_bytes) = self
+            .read_pair_audio_range(&path, &metadata, request.start, request.length)
+            .await?;
+        self.touch_pair(&request.id);
+
+        Ok(AudioRangeRes {
+            audio_data: BASE64.encode(&audio),
+            format: metadata.audio_format,
+            start: request.start,
+            total_bytes,
+        })
+    }
+
+    // Like get_audio_text_pair but only reads metadata.json; `audio_data` is left empty.
+    // Doesn't count as an access for LRU eviction.
+    #[local]
+    #[http]
+    async fn get_pair_metadata(
+        &self,
+        request: GetAudioTextPairReq,
+    ) -> Result<AudioTextPair, String> {
+        if !is_valid_pair_id(&request.id) {
+            return Err(format!("Invalid pair id: {}", request.id));
+        }
+        self.require_storage()?;
+
+        let path = self.pair_path(&request.id);
+        self.load_audio_text_pair_by_path(&path, false).await
+    }
+
+    // Soft delete: move the pair into the trash drive
+    #[local]
+    #[http]
+    async fn delete_audio_text_pair(
+        &mut self,
+        request: DeleteAudioTextPairReq,
+    ) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+        if !is_valid_pair_id(&request.id) {
+            return Err(format!("Invalid pair id: {}", request.id));
+        }
+
+        self.wait_for_audio_write(&request.id).await?;
+        self.move_pair_to_trash(&request.id)?;
+        self.audit(&actor, "delete_audio_text_pair", request.id)
+            .await;
+
+        Ok("Pair moved to trash".to_string())
+    }
+
+    #[local]
+    #[http]
+    async fn restore_audio_text_pair(
+        &mut self,
+        request: RestoreAudioTextPairReq,
+    ) -> Result<String, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+        if !is_valid_pair_id(&request.id) {
+            return Err(format!("Invalid pair id: {}", request.id));
+        }
+        if self.pair_index.iter().any(|e| e.id == request.id) {
+            return Err(format!("Pair {} already exists", request.id));
+        }
+
+        // Size the pair, restore it, then evict others to make room for it
+        let package_id = our().package_id();
+        let trash_path = format!("/{}/{}/{}", package_id, TRASH_DRIVE, request.id);
+        let entry = self.index_entry_for(&trash_path).await?;
+        self.check_fits_storage(entry.size_bytes)?;
+
+        rename_vfs_path(&trash_path, &self.pair_path(&request.id))?;
+        self.pair_index.push(entry);
+        self.make_room(0, Some(&request.id)).await?;
+        self.audit(&actor, "restore_audio_text_pair", request.id)
+            .await;
+
+        Ok("Pair restored".to_string())
+    }
+
+    // Permanently remove everything in the trash; returns how many pairs were removed
+    #[local]
+    #[http]
+    async fn empty_trash(&mut self, request: EmptyTrashReq) -> Result<u32, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        let trash_path = format!("/{}/{}", our().package_id(), TRASH_DRIVE);
+        let entries = match open_dir_async(&trash_path, false, Some(5)).await {
+            Ok(dir) => dir
+                .read()
+                .await
+                .map_err(|e| format!("Failed to read trash: {:?}", e))?,
+            // Nothing has ever been deleted
+            Err(_) => return Ok(0),
+        };
+
+        let mut removed = 0;
+        for entry in entries
+            .into_iter()
+            .filter(|e| e.file_type == FileType::Directory)
+        {
+            self.remove_pair_dir(&entry.path).await?;
+            removed += 1;
+        }
+        self.audit(&actor, "empty_trash", format!("{} pairs", removed))
+            .await;
+
+        Ok(removed)
+    }
+
+    // Collapse identical TTS pairs, keeping the oldest of each and trashing the rest.
+    // Pairs saved before content hashes existed are hashed from their stored fields,
+    // so they only match each other. Returns how many pairs were trashed.
+    #[local]
+    #[http]
+    async fn dedupe_history(&mut self, request: DedupeHistoryReq) -> Result<u32, String> {
+        let actor = self.validate_api_key(request.api_key, true)?;
+
+        // (hash, timestamp, id), so sorting puts the oldest of each group first. Pairs
+        // still being written are left for a later run.
+        let mut hashed = Vec::new();
+        for entry in self.pair_index.clone() {
+            if self.audio_write_in_flight(&entry.id) {
+                continue;
+            }
+            let hash = match entry.content_hash {
+                Some(hash) => hash,
+                None => {
+                    let path = self.pair_path(&entry.id);
+                    match self.read_pair_metadata(&path).await {
+                        Ok(metadata) if metadata.request_type == RequestType::TTS => {
+                            content_hash(&ContentHashInput {
+                                provider: &metadata.provider,
+                                audio_format: &metadata.audio_format,
+                                text: &metadata.text,
+                                voice: None,
+                                model: None,
+                                speed: None,
+                                profile: None,
+                                sample_rate: None,
+                                bit_depth: None,
+                            })
+                        }
+                        Ok(_) => continue,
+                        Err(e) => {
+                            eprintln!("Skipping {} while deduplicating: {}", path, e);
+                            continue;
+                        }
+                    }
+                }
+            };
+            hashed.push((hash, entry.timestamp, entry.id));
+        }
+        hashed.sort();
+
+        let mut removed = 0;
+        let mut previous: Option<String> = None;
+        for (hash, _, id) in hashed {
+            if previous.as_deref() == Some(hash.as_str()) {
+                self.move_pair_to_trash(&id)?;
+                removed += 1;
+            } else {
+                previous = Some(hash);
+            }
+        }
+        self.audit(&actor, "dedupe_history", format!("{} pairs", removed))
+            .await;
+
+        Ok(removed)
+    }
+
+    // Bundle stored pairs (metadata and audio) into a JSON-lines archive, a page at a time
+    #[local]
+    #[http]
+    async fn export_history(&mut self, request: ExportHistoryReq) -> Result<ExportRes, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        let limit = request.limit.unwrap_or(100) as usize;
+        let offset = request.offset.unwrap_or(0) as usize;
+
+        // Directory order is stable across calls, so pages don't overlap
+        let mut pair_dirs = self.list_pair_dirs().await?;
+        pair_dirs.sort_by(|a, b| a.path.cmp(&b.path));
+        let has_more = pair_dirs.len() > offset + limit;
+
+        // Lines are compressed as they are produced, so the uncompressed archive is never held
+        let compress = request.compress.unwrap_or(true);
+        let mut encoder = compress.then(|| GzEncoder::new(Vec::new(), Compression::default()));
+        let mut archive = Vec::new();
+        let mut count = 0;
+        for entry in pair_dirs.into_iter().skip(offset).take(limit) {
+            let metadata = match self.read_pair_metadata(&entry.path).await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    eprintln!("Skipping {} in export: {}", entry.path, e);
+                    continue;
+                }
+            };
+            let audio = match self.read_pair_audio(&entry.path, &metadata).await {
+                Ok(audio) => audio,
+                Err(e) => {
+                    eprintln!("Skipping {} in export: {}", entry.path, e);
+                    continue;
+                }
+            };
+            // Archives always carry the original audio bytes
+            let metadata = StoredPairMetadata {
+                compression: None,
+                pending: false,
+                ..metadata
+            };
+
+            let line = serde_json::to_vec(&ExportEntry {
+                metadata,
+                audio_data: BASE64.encode(&audio),
+            })
+            .map_err(|e| format!("Failed to serialize export entry: {}", e))?;
+            match &mut encoder {
+                Some(encoder) => encoder
+                    .write_all(&line)
+                    .and_then(|()| encoder.write_all(b"\n"))
+                    .map_err(|e| format!("Failed to compress archive: {}", e))?,
+                None => {
+                    archive.extend_from_slice(&line);
+                    archive.push(b'\n');
+                }
+            }
+            count += 1;
+        }
+        if let Some(encoder) = encoder {
+            archive = encoder
+                .finish()
+                .map_err(|e| format!("Failed to compress archive: {}", e))?;
+        }
+
+        Ok(ExportRes {
+            archive: BASE64.encode(&archive),
+            count,
+            has_more,
+            compressed: compress,
+        })
+    }
+
+    // Recreate pairs from an export_history archive, skipping ids that already exist
+    #[local]
+    #[http]
+    async fn import_history(&mut self, request: ImportHistoryReq) -> Result<ImportRes, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        let archive = BASE64
+            .decode(&request.archive)
+            .map_err(|e| format!("Failed to decode archive: {}", e))?;
+
+        // Inflate line by line rather than into one buffer
+        let compressed = request
+            .compressed
+            .unwrap_or_else(|| archive.starts_with(&GZIP_MAGIC));
+        let reader: Box<dyn BufRead + Send> = if compressed {
+            Box::new(BufReader::new(GzDecoder::new(archive.as_slice())))
+        } else {
+            Box::new(archive.as_slice())
+        };
+
+        let mut imported = 0;
+        let mut skipped = 0;
+        for (line_number, line) in reader.split(b'\n').enumerate() {
+            let line = line.map_err(|e| format!("Failed to read archive: {}", e))?;
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+
+            // Validate the whole entry before writing anything for it
+            let entry: ExportEntry = match serde_json::from_slice(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("Skipping archive line {}: {}", line_number + 1, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            if !is_valid_pair_id(&entry.metadata.id) {
+                eprintln!(
+                    "Skipping archive line {}: invalid id {:?}",
+                    line_number + 1,
+                    entry.metadata.id
+                );
+                skipped += 1;
+                continue;
+            }
+            if BASE64.decode(&entry.audio_data).is_err() {
+                eprintln!(
+                    "Skipping archive line {}: invalid audio data",
+                    line_number + 1
+                );
+                skipped += 1;
+                continue;
+            }
+            if self.pair_index.iter().any(|e| e.id == entry.metadata.id) {
+                skipped += 1;
+                continue;
+            }
+
+            let pair = entry.metadata.into_pair(entry.audio_data);
+            match self.save_audio_text_pair(&pair, false).await {
+                Ok(()) => imported += 1,
+                Err(e) => {
+                    eprintln!("Failed to import pair {}: {}", pair.id, e);
+                    skipped += 1;
+                }
+            }
+        }
+
+        Ok(ImportRes { imported, skipped })
+    }
+
+    // Rewrite a stored pair's metadata array without touching its audio.
+    // Returns the updated pair without audio data.
+    #[local]
+    #[http]
+    async fn update_pair_metadata(
+        &mut self,
+        request: UpdatePairMetadataReq,
+    ) -> Result<AudioTextPair, String> {
+        self.validate_api_key(request.api_key, true)?;
+        if !is_valid_pair_id(&request.id) {
+            return Err(format!("Invalid pair id: {}", request.id));
+        }
+        validate_request_metadata(&Some(request.metadata.clone()))?;
+
+        let stored = self
+            .merge_pair_metadata(
+                &request.id,
+                request.metadata,
+                request.replace.unwrap_or(false),
+            )
+            .await?;
+        Ok(stored.into_pair(String::new()))
+    }
+
+    #[local]
+    #[http]
+    async fn get_info(&self) -> Result<AppInfo, String> {
+        Ok(AppInfo {
+            node: our().node.clone(),
+            package_id: our().package_id().to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            wit_world: WIT_WORLD.to_string(),
+            provider_count: self.providers.len() as u32,
+        })
+    }
+
+    #[local]
+    #[http]
+    async fn get_storage_status(&self) -> Result<StorageStatus, String> {
+        Ok(self.storage_status())
+    }
+
+    // A one-time URL for a pair's audio, /audio/<token>, so a browser can load it straight
+    // into <audio src> instead of decoding base64 from get_audio_text_pair
+    #[local]
+    #[http]
+    async fn create_audio_token(
+        &mut self,
+        request: CreateAudioTokenReq,
+    ) -> Result<AudioTokenRes, String> {
+        if !is_valid_pair_id(&request.id) {
+            return Err(format!("Invalid pair id: {}", request.id));
+        }
+        self.require_storage()?;
+        let path = self.pair_path(&request.id);
+        self.read_pair_metadata(&path).await?;
+
+        Ok(self.issue_audio_token(&request.id, Utc::now()))
+    }
+
+    // Audio as the response body rather than base64 in JSON, routed by path under /audio:
+    //   POST /audio/tts with a TtsReq body answers with the synthesized audio and its
+    //   Content-Type. JSON from tts() on /api stays the default.
+    //   GET /audio/<token> serves the pair a create_audio_token token grants, once.
+    #[http]
+    async fn serve_audio(&mut self) -> Result<Vec<u8>, String> {
+        let path = get_path().ok_or("No request path provided")?;
+        let route = path
+            .strip_prefix("/audio/")
+            .ok_or_else(|| format!("Invalid audio path: {}", path))?;
+
+        match route {
+            "tts" => {
+                let body = get_blob().ok_or("Request has no body")?.bytes;
+                let request: TtsReq = serde_json::from_slice(&body)
+                    .map_err(|e| format!("Invalid TTS request: {}", e))?;
+                let response = self
+                    .tts(TtsReq {
+                        encoding: None,
+                        store_only: None,
+                        ..request
+                    })
+                    .await?;
+                let audio = BASE64
+                    .decode(&response.audio_data)
+                    .map_err(|e| format!("Failed to decode audio: {}", e))?;
+                add_response_header(
+                    "Content-Type".to_string(),
+                    audio_mime_type(&response.format).to_string(),
+                );
+                Ok(audio)
+            }
+            token => {
+                let pair_id = self.redeem_audio_token(token, Utc::now())?;
+                let pair_path = self.pair_path(&pair_id);
+                let metadata = self.read_pair_metadata(&pair_path).await?;
+                let audio = self.read_pair_audio(&pair_path, &metadata).await?;
+                self.touch_pair(&pair_id);
+                add_response_header(
+                    "Content-Type".to_string(),
+                    audio_mime_type(&metadata.audio_format).to_string(),
+                );
+                Ok(audio)
+            }
+        }
+    }
+
+    // Prometheus text exposition of request, error, spend and storage counters
+    #[http]
+    async fn metrics(&self) -> Result<String, String> {
+        let label = |request_type: &RequestType| match request_type {
+            RequestType::TTS => "tts",
+            RequestType::STT => "stt",
+        };
+        let mut out = String::new();
+
+        out.push_str("# HELP ttstt_requests_total tts/stt requests received since start.\n");
+        out.push_str("# TYPE ttstt_requests_total counter\n");
+        for (request_type, count) in &self.request_counts {
+            out.push_str(&format!(
+                "ttstt_requests_total{{type=\"{}\"}} {}\n",
+                label(request_type),
+                count
+            ));
+        }
+
+        out.push_str("# HELP ttstt_provider_requests_total Requests completed by each provider.\n");
+        out.push_str("# TYPE ttstt_provider_requests_total counter\n");
+        for counter in &self.usage {
+            out.push_str(&format!(
+                "ttstt_provider_requests_total{{provider=\"{}\",type=\"{}\"}} {}\n",
+                counter.provider.as_str(),
+                label(&counter.request_type),
+                counter.requests
+            ));
+        }
+
+        out.push_str("# HELP ttstt_provider_errors_total Failed provider calls since start.\n");
+        out.push_str("# TYPE ttstt_provider_errors_total counter\n");
+        for (provider, request_type, count) in &self.provider_errors {
+            out.push_str(&format!(
+                "ttstt_provider_errors_total{{provider=\"{}\",type=\"{}\"}} {}\n",
+                provider.as_str(),
+                label(request_type),
+                count
+            ));
+        }
+
+        out.push_str(
+            "# HELP ttstt_spend_usd_total Estimated provider spend since the last reset.\n",
+        );
+        out.push_str("# TYPE ttstt_spend_usd_total counter\n");
+        for counter in &self.usage {
+            out.push_str(&format!(
+                "ttstt_spend_usd_total{{provider=\"{}\",type=\"{}\"}} {}\n",
+                counter.provider.as_str(),
+                label(&counter.request_type),
+                counter.spend_usd
+            ));
+        }
+
+        out.push_str("# HELP ttstt_stored_pairs Audio-text pairs in history.\n");
+        out.push_str("# TYPE ttstt_stored_pairs gauge\n");
+        out.push_str(&format!("ttstt_stored_pairs {}\n", self.pair_index.len()));
+        out.push_str("# HELP ttstt_stored_bytes Bytes used by stored pairs.\n");
+        out.push_str("# TYPE ttstt_stored_bytes gauge\n");
+        out.push_str(&format!(
+            "ttstt_stored_bytes {}\n",
+            self.total_stored_bytes()
+        ));
+
+        Ok(out)
+    }
+
+    // Read every stored pair and check its audio against the recorded checksum
+    #[local]
+    #[http]
+    async fn verify_history(
+        &mut self,
+        request: VerifyHistoryReq,
+    ) -> Result<VerifyHistoryRes, String> {
+        self.validate_api_key(request.api_key, true)?;
+        self.require_storage()?;
+
+        let mut result = VerifyHistoryRes {
+            checked: 0,
+            unverified: 0,
+            failures: Vec::new(),
         };
+        for entry in self.list_pair_dirs().await? {
+            result.checked += 1;
+            let metadata = match self.read_pair_metadata(&entry.path).await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    result.failures.push((entry.path, e));
+                    continue;
+                }
+            };
+            if metadata.audio_sha256.is_none() {
+                result.unverified += 1;
+            }
+            if let Err(e) = self.read_pair_audio(&entry.path, &metadata).await {
+                result.failures.push((entry.path, e));
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Recreate the drive and re-probe it, e.g. after storage was unavailable at init
+    #[local]
+    #[http]
+    async fn retry_storage_init(
+        &mut self,
+        request: RetryStorageInitReq,
+    ) -> Result<StorageStatus, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        self.storage_initialized = false;
+        if let Err(e) = self.ensure_storage_initialized().await {
+            eprintln!("Failed to initialize storage: {}", e);
+        }
+        if self.probe_storage().await {
+            self.prepare_storage().await;
+        }
+
+        Ok(self.storage_status())
+    }
+
+    // Move history to another drive of this package. Pairs already stored stay on the old
+    // drive and are no longer listed, so switching to an empty drive comes with a warning.
+    // Drive size isn't set here: the VFS has no per-drive capacity, so the ceiling is
+    // set_storage_limits' max_total_bytes, which get_storage_status reports alongside.
+    #[local]
+    #[http]
+    async fn set_storage_drive(&mut self, request: SetStorageDriveReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        let drive = request
+            .drive
+            .unwrap_or_else(|| DEFAULT_STORAGE_DRIVE.to_string());
+        if drive.is_empty()
+            || !drive
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!("Invalid drive name: {}", drive));
+        }
+        if drive == TRASH_DRIVE {
+            return Err(format!("{} is reserved for the trash", TRASH_DRIVE));
+        }
+        if drive == self.storage_drive() {
+            return Ok(format!("Storage drive is already {}", drive));
+        }
+
+        let old_drive = self.storage_drive().to_string();
+        let old_pair_count = self.pair_index.len();
+        self.storage_drive = (drive != DEFAULT_STORAGE_DRIVE).then(|| drive.clone());
+
+        // The index describes the old drive; rebuild it from the new one
+        self.pair_index.clear();
+        self.storage_initialized = false;
+        if let Err(e) = self.ensure_storage_initialized().await {
+            eprintln!("Failed to initialize storage: {}", e);
+        }
+        if self.probe_storage().await {
+            self.prepare_storage().await;
+        }
 
-        // Map format string to OpenAI format enum
-        let format = match request.format.as_deref() {
-            Some("mp3") => OpenAIAudioFormat::Mp3,
-            Some("opus") => OpenAIAudioFormat::Opus,
-            Some("aac") => OpenAIAudioFormat::Aac,
-            Some("flac") => OpenAIAudioFormat::Flac,
-            Some("wav") => OpenAIAudioFormat::Wav,
-            Some("pcm") => OpenAIAudioFormat::Pcm,
-            _ => OpenAIAudioFormat::Mp3, // Default
-        };
+        if self.pair_index.is_empty() && old_pair_count > 0 {
+            let warning = format!(
+                "{} is empty while {} still holds {} pairs, which are no longer listed",
+                drive, old_drive, old_pair_count
+            );
+            eprintln!("Storage drive changed: {}", warning);
+            return Ok(format!(
+                "Storage drive set to {}; warning: {}",
+                drive, warning
+            ));
+        }
 
-        // Build and execute request
-        let mut builder = client
-            .synthesize()
-            .model(model)
-            .voice(voice)
-            .input(request.text.clone())
-            .response_format(format);
+        Ok(format!("Storage drive set to {}", drive))
+    }
 
-        // Set speed: use request speed, then provider default, then 1.5
-        let speed = request.speed.or(config.default_speed).unwrap_or(1.5);
-        builder = builder.speed(speed);
+    #[local]
+    #[http]
+    async fn get_usage(&mut self, request: GetUsageReq) -> Result<UsageStats, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        let response = builder
-            .execute()
-            .await
-            .map_err(|e| format!("OpenAI TTS error: {:?}", e))?;
+        let concurrency = self
+            .peak_concurrent
+            .iter()
+            .map(|(provider, peak)| ConcurrencyStats {
+                provider: provider.clone(),
+                current_concurrent: self
+                    .in_flight
+                    .iter()
+                    .find(|(p, _)| p == provider)
+                    .map(|(_, n)| *n)
+                    .unwrap_or(0),
+                peak_concurrent: *peak,
+            })
+            .collect();
 
-        Ok(TtsRes {
-            audio_data: BASE64.encode(&response.audio_data),
-            format: request.format.unwrap_or("mp3".to_string()),
-            provider: Provider::OpenAI,
+        Ok(UsageStats {
+            counters: self.usage.clone(),
+            concurrency,
+            max_concurrent_calls: self.max_concurrent_calls,
         })
     }
 
-    // VFS Storage helpers
-    async fn ensure_storage_initialized(&mut self) -> Result<(), String> {
-        if self.storage_initialized {
-            return Ok(());
-        }
+    // Tags (metadata entries keyed "tag") across all pairs, most used first
+    #[local]
+    #[http]
+    async fn list_tags(&self, request: ListTagsReq) -> Result<Vec<TagCount>, String> {
+        let limit = request.limit.unwrap_or(100) as usize;
+        let offset = request.offset.unwrap_or(0) as usize;
 
-        // Create a drive for audio_pairs storage
-        match create_drive(our().package_id(), "audio_pairs", Some(5)) {
-            Ok(drive_path) => {
-                println!("Created audio_pairs drive at: {}", drive_path);
-            }
-            Err(e) => {
-                // Drive might already exist, which is fine
-                println!("Note: audio_pairs drive may already exist: {:?}", e);
+        Ok(self
+            .tag_counts()
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .collect())
+    }
+
+    // Metadata keys across all pairs, most used first. Counted from the pair index, which
+    // is kept current on every save and metadata update, so no metadata.json is read.
+    #[local]
+    #[http]
+    async fn list_metadata_keys(
+        &self,
+        request: ListMetadataKeysReq,
+    ) -> Result<Vec<MetadataKeyCount>, String> {
+        let limit = request.limit.unwrap_or(100) as usize;
+        let offset = request.offset.unwrap_or(0) as usize;
+
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for entry in &self.pair_index {
+            for key in &entry.metadata_keys {
+                *counts.entry(key.as_str()).or_default() += 1;
             }
         }
 
-        self.storage_initialized = true;
-        Ok(())
-    }
+        let mut keys: Vec<MetadataKeyCount> = counts
+            .into_iter()
+            .map(|(key, count)| MetadataKeyCount {
+                key: key.to_string(),
+                count,
+            })
+            .collect();
+        keys.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
 
-    async fn save_audio_text_pair(&self, pair: &AudioTextPair) -> Result<(), String> {
-        let base_path = format!("/{}/audio_pairs/{}", our().package_id(), pair.id);
+        Ok(keys.into_iter().skip(offset).take(limit).collect())
+    }
 
-        // Create directory for this pair
-        open_dir_async(&base_path, true, Some(5))
-            .await
-            .map_err(|e| format!("Failed to create pair directory: {:?}", e))?;
+    #[local]
+    #[http]
+    async fn set_local_stt(&mut self, request: SetLocalSttReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Create metadata struct (without audio data to keep it small)
-        #[derive(Serialize)]
-        struct PairMetadata<'a> {
-            id: &'a str,
-            text: &'a str,
-            audio_format: &'a str,
-            provider: &'a Provider,
-            timestamp: &'a str,
-            request_type: &'a RequestType,
-            metadata: &'a Vec<(String, String)>,
+        if let Some(config) = &request.config {
+            url::Url::parse(&config.url).map_err(|e| format!("Invalid local STT URL: {}", e))?;
         }
 
-        let metadata = PairMetadata {
-            id: &pair.id,
-            text: &pair.text,
-            audio_format: &pair.audio_format,
-            provider: &pair.provider,
-            timestamp: &pair.timestamp,
-            request_type: &pair.request_type,
-            metadata: &pair.metadata,
-        };
-
-        let metadata_path = format!("{}/metadata.json", base_path);
-        let metadata_file = create_file_async(&metadata_path, Some(5))
-            .await
-            .map_err(|e| format!("Failed to create metadata file: {:?}", e))?;
-
-        let metadata_json = serde_json::to_string(&metadata)
-            .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+        self.local_stt = request.config;
 
-        metadata_file
-            .write(metadata_json.as_bytes())
-            .await
-            .map_err(|e| format!("Failed to write metadata: {:?}", e))?;
+        Ok("Local STT updated successfully".to_string())
+    }
 
-        // Save audio data
-        let audio_ext = match pair.audio_format.as_str() {
-            "webm" => "webm",
-            "mp3" => "mp3",
-            _ => "audio",
-        };
-        let audio_path = format!("{}/audio.{}", base_path, audio_ext);
-        let audio_file = create_file_async(&audio_path, Some(5))
-            .await
-            .map_err(|e| format!("Failed to create audio file: {:?}", e))?;
+    #[local]
+    #[http]
+    async fn set_transcript_webhook(
+        &mut self,
+        request: SetTranscriptWebhookReq,
+    ) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Decode base64 and write raw audio
-        let audio_bytes = BASE64
-            .decode(&pair.audio_data)
-            .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+        if let Some(webhook) = &request.webhook {
+            url::Url::parse(&webhook.url).map_err(|e| format!("Invalid webhook URL: {}", e))?;
+        }
 
-        audio_file
-            .write(&audio_bytes)
-            .await
-            .map_err(|e| format!("Failed to write audio: {:?}", e))?;
+        self.transcript_webhook = request.webhook;
 
-        Ok(())
+        Ok("Transcript webhook updated successfully".to_string())
     }
 
-    async fn load_audio_text_pairs(
-        &self,
-        limit: usize,
-        offset: usize,
-    ) -> Result<Vec<AudioTextPair>, String> {
-        let base_path = format!("/{}/audio_pairs", our().package_id());
+    #[local]
+    #[http]
+    async fn set_storage_limits(&mut self, request: SetStorageLimitsReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Open directory
-        let dir = open_dir_async(&base_path, false, Some(5))
-            .await
-            .map_err(|e| format!("Failed to open storage directory: {:?}", e))?;
+        self.max_total_bytes = request.max_total_bytes;
+        if let Some(policy) = request.eviction_policy {
+            self.eviction_policy = policy;
+        }
 
-        // Read directory entries
-        let entries = dir
-            .read()
-            .await
-            .map_err(|e| format!("Failed to read directory: {:?}", e))?;
+        // Apply a lowered ceiling right away rather than on the next save
+        self.make_room(0, None).await?;
 
-        // Sort by name (which includes timestamp) in reverse for most recent first
-        let mut pair_dirs: Vec<_> = entries
-            .into_iter()
-            .filter(|e| e.file_type == hyperware_process_lib::vfs::FileType::Directory)
-            .collect();
-        pair_dirs.sort_by(|a, b| b.path.cmp(&a.path));
+        Ok("Storage limits updated successfully".to_string())
+    }
 
-        // Apply pagination
-        let paginated: Vec<_> = pair_dirs.into_iter().skip(offset).take(limit).collect();
+    #[local]
+    #[http]
+    async fn set_tts_defaults(&mut self, request: SetTtsDefaultsReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Load each pair
-        let mut pairs = Vec::new();
-        for entry in paginated {
-            match self.load_audio_text_pair_by_path(&entry.path).await {
-                Ok(pair) => pairs.push(pair),
-                Err(e) => eprintln!("Failed to load pair from {}: {}", entry.path, e),
+        let defaults = request.defaults;
+        for bound in [defaults.min_speed, defaults.max_speed]
+            .into_iter()
+            .flatten()
+        {
+            if !bound.is_finite() || bound <= 0.0 {
+                return Err("min_speed and max_speed must be positive".to_string());
+            }
+        }
+        if let (Some(min), Some(max)) = (defaults.min_speed, defaults.max_speed) {
+            if min > max {
+                return Err("min_speed must not exceed max_speed".to_string());
             }
         }
+        // The default has to be usable under strict mode too
+        if let Some(speed) = defaults.speed {
+            defaults.check_speed_band(speed, true)?;
+        }
 
-        Ok(pairs)
-    }
+        self.tts_defaults = defaults;
+        if let Some(strict_speed) = request.strict_speed {
+            self.strict_speed = strict_speed;
+        }
 
-    async fn load_audio_text_pair_by_id(&self, id: &str) -> Result<AudioTextPair, String> {
-        let path = format!("/{}/audio_pairs/{}", our().package_id(), id);
-        self.load_audio_text_pair_by_path(&path).await
+        Ok("TTS defaults updated successfully".to_string())
     }
 
-    async fn load_audio_text_pair_by_path(&self, path: &str) -> Result<AudioTextPair, String> {
-        // Define metadata struct for deserialization
-        #[derive(Deserialize)]
-        struct PairMetadata {
-            id: String,
-            text: String,
-            audio_format: String,
-            provider: Provider,
-            timestamp: String,
-            request_type: RequestType,
-            metadata: Vec<(String, String)>,
+    #[local]
+    #[http]
+    async fn set_voice_aliases(&mut self, request: SetVoiceAliasesReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        for (i, alias) in request.aliases.iter().enumerate() {
+            if alias.alias.trim().is_empty() || alias.voice.trim().is_empty() {
+                return Err("alias and voice must not be empty".to_string());
+            }
+            if request.aliases[..i]
+                .iter()
+                .any(|a| a.alias == alias.alias && a.provider == alias.provider)
+            {
+                return Err(format!(
+                    "Alias {} is mapped twice for {}",
+                    alias.alias,
+                    alias.provider.as_str()
+                ));
+            }
         }
 
-        // Load metadata
-        let metadata_path = format!("{}/metadata.json", path);
-        let metadata_file = open_file_async(&metadata_path, false, Some(5))
-            .await
-            .map_err(|e| format!("Failed to open metadata file: {:?}", e))?;
+        self.voice_aliases = request.aliases;
 
-        let metadata_str = metadata_file
-            .read_to_string()
-            .await
-            .map_err(|e| format!("Failed to read metadata: {:?}", e))?;
+        Ok("Voice aliases updated successfully".to_string())
+    }
 
-        let metadata: PairMetadata = serde_json::from_str(&metadata_str)
-            .map_err(|e| format!("Failed to parse metadata: {}", e))?;
+    #[local]
+    #[http]
+    async fn get_voice_aliases(&self) -> Result<Vec<VoiceAlias>, String> {
+        Ok(self.voice_aliases.clone())
+    }
 
-        // Determine audio file extension
-        let audio_ext = match metadata.audio_format.as_str() {
-            "webm" => "webm",
-            "mp3" => "mp3",
-            _ => "audio",
-        };
+    // Returns how many pairs the new window pruned right away
+    #[local]
+    #[http]
+    async fn set_retention(&mut self, request: SetRetentionReq) -> Result<u32, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Load audio data
-        let audio_path = format!("{}/audio.{}", path, audio_ext);
-        let audio_file = open_file_async(&audio_path, false, Some(5))
-            .await
-            .map_err(|e| format!("Failed to open audio file: {:?}", e))?;
+        if request.retention_days == Some(0) {
+            return Err("retention_days must be at least 1".to_string());
+        }
+        self.retention_days = request.retention_days;
 
-        let audio_bytes = audio_file
-            .read()
-            .await
-            .map_err(|e| format!("Failed to read audio: {:?}", e))?;
+        self.prune_expired_pairs().await
+    }
+
+    #[local]
+    #[http]
+    async fn set_log_level(&mut self, request: SetLogLevelReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Encode audio to base64
-        let audio_data = BASE64.encode(&audio_bytes);
+        self.log_level = request.level;
 
-        // Construct AudioTextPair
-        Ok(AudioTextPair {
-            id: metadata.id,
-            text: metadata.text,
-            audio_data,
-            audio_format: metadata.audio_format,
-            provider: metadata.provider,
-            timestamp: metadata.timestamp,
-            request_type: metadata.request_type,
-            metadata: metadata.metadata,
-        })
+        Ok(format!("Log level set to {:?}", request.level))
     }
 
-    // OpenAI STT implementation
-    async fn handle_openai_stt(&self, request: SttReq) -> Result<SttRes, String> {
-        let config = self.get_provider_config(&Provider::OpenAI)?;
-
-        // Create OpenAI STT client
-        let client = TranscriptionClient::new(&config.api_key);
+    // Takes effect on the next init
+    #[local]
+    #[http]
+    async fn set_preflight_on_init(
+        &mut self,
+        request: SetPreflightOnInitReq,
+    ) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Decode base64 audio data
-        let audio_data = BASE64
-            .decode(&request.audio_data)
-            .map_err(|e| format!("Failed to decode audio data: {}", e))?;
+        self.preflight_on_init = request.enabled;
 
-        // Map model string to OpenAI model enum
-        let model = match request.model.as_deref() {
-            Some("whisper-1") => OpenAISttModel::Whisper1,
-            Some("gpt-4o-transcribe") => OpenAISttModel::Gpt4oTranscribe,
-            Some("gpt-4o-mini-transcribe") => OpenAISttModel::Gpt4oMiniTranscribe,
-            _ => OpenAISttModel::Whisper1, // Default
-        };
+        Ok(format!(
+            "Preflight on init {}",
+            if request.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        ))
+    }
 
-        // Build and execute request
-        let mut builder = client
-            .transcribe()
-            .file(audio_data, "audio.webm")
-            .model(model);
+    #[local]
+    #[http]
+    async fn set_max_concurrent_calls(
+        &mut self,
+        request: SetMaxConcurrentCallsReq,
+    ) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        if let Some(lang) = request.language.clone() {
-            builder = builder.language(lang);
+        if request.max_concurrent_calls == Some(0) {
+            return Err("max_concurrent_calls must be at least 1".to_string());
         }
+        self.max_concurrent_calls = request.max_concurrent_calls;
 
-        let response = builder
-            .execute()
-            .await
-            .map_err(|e| format!("OpenAI STT error: {:?}", e))?;
-
-        Ok(SttRes {
-            text: response.text,
-            provider: Provider::OpenAI,
-        })
+        Ok("Max concurrent calls updated successfully".to_string())
     }
-}
 
-#[hyperprocess(
-    name = "TTSTT",
-    ui = Some(HttpBindingConfig::default()),
-    endpoints = vec![
-        Binding::Http {
-            path: "/api",
-            config: HttpBindingConfig::new(false, false, false, None)
+    #[local]
+    #[http]
+    async fn set_max_text_chars(&mut self, request: SetMaxTextCharsReq) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
+
+        if request.max_text_chars == Some(0) {
+            return Err("max_text_chars must be at least 1".to_string());
         }
-    ],
-    save_config = SaveOptions::EveryMessage,
-    wit_world = "ttstt-dot-os-v0"
-)]
-impl TtsttState {
-    #[init]
-    async fn initialize(&mut self) {
-        add_to_homepage("Spider TTSTT", None, Some("/"), None);
+        self.max_text_chars = request.max_text_chars;
 
-        // Generate initial admin key if not exists
-        if self.admin_key.is_empty() {
-            self.admin_key = format!("ttstt-admin-{}", Uuid::new_v4());
-            println!("Generated admin API key: {}", self.admin_key);
+        Ok("Max text length updated successfully".to_string())
+    }
 
-            // Add to API keys list
-            self.api_keys.push(ApiKey {
-                key: self.admin_key.clone(),
-                role: ApiKeyRole::Admin,
-                created_at: Utc::now().to_rfc3339(),
-                name: "Initial Admin Key".to_string(),
-            });
-        }
+    #[local]
+    #[http]
+    async fn set_max_audio_bytes(
+        &mut self,
+        request: SetMaxAudioBytesReq,
+    ) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Ensure storage is initialized
-        if let Err(e) = self.ensure_storage_initialized().await {
-            eprintln!("Failed to initialize storage: {}", e);
-        }
+        self.max_audio_bytes = request.max_audio_bytes;
 
-        let our_node = our().node.clone();
-        println!("TTSTT initialized on node: {}", our_node);
+        Ok("Max audio size updated successfully".to_string())
     }
 
+    // Only affects pairs saved from now on; existing pairs keep their stored form
     #[local]
     #[http]
-    async fn tts(&mut self, request: TtsReq) -> Result<TtsRes, String> {
-        // Validate API key if provided
-        if request.api_key.is_some() {
-            self.validate_api_key(request.api_key.clone(), false)?;
-        }
+    async fn set_audio_compression(
+        &mut self,
+        request: SetAudioCompressionReq,
+    ) -> Result<String, String> {
+        self.validate_api_key(request.api_key, true)?;
 
-        // Determine provider
-        let provider = request
-            .provider
-            .clone()
-            .or(self.default_tts_provider.clone())
-            .ok_or("No provider specified and no default configured")?;
+        self.compress_audio = request.enabled;
 
-        // Handle request based on provider
-        let response = match provider {
-            Provider::OpenAI => self.handle_openai_tts(request.clone()).await?,
-        };
+        Ok(format!(
+            "Audio compression {}",
+            if request.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        ))
+    }
 
-        // Store audio-text pair to VFS
-        let pair = AudioTextPair {
-            id: Uuid::new_v4().to_string(),
-            text: request.text.clone(),
-            audio_data: response.audio_data.clone(),
-            audio_format: response.format.clone(),
-            provider: response.provider.clone(),
-            timestamp: Utc::now().to_rfc3339(),
-            request_type: RequestType::TTS,
-            metadata: vec![],
-        };
+    #[http]
+    async fn get_admin_key(&mut self) -> Result<GetAdminKeyRes, String> {
+        // Hand the key out at most once, and only while no other admin keys exist
+        let admin_count = self
+            .api_keys
+            .iter()
+            .filter(|k| matches!(k.role, ApiKeyRole::Admin))
+            .count();
 
-        // Save to VFS
-        if let Err(e) = self.save_audio_text_pair(&pair).await {
-            eprintln!("Failed to save audio-text pair: {}", e);
+        if self.admin_key_retrieved || admin_count != 1 {
+            return Err("Admin key already retrieved".to_string());
         }
 
-        Ok(response)
+        self.admin_key_retrieved = true;
+
+        Ok(GetAdminKeyRes {
+            admin_key: self.admin_key.clone(),
+            message: "Save this key! It will not be shown again.".to_string(),
+        })
     }
 
+    // Replace the initial admin key; the old one stops validating immediately
     #[local]
     #[http]
-    async fn stt(&mut self, request: SttReq) -> Result<SttRes, String> {
-        // Validate API key if provided
-        if request.api_key.is_some() {
-            self.validate_api_key(request.api_key.clone(), false)?;
+    async fn rotate_admin_key(
+        &mut self,
+        request: RotateAdminKeyReq,
+    ) -> Result<GetAdminKeyRes, String> {
+        if request.api_key.as_deref() != Some(self.admin_key.as_str()) {
+            return Err("Current admin key required".to_string());
         }
+        let actor = self.validate_api_key(request.api_key, true)?;
 
-        // Determine provider
-        let provider = request
-            .provider
-            .clone()
-            .or(self.default_stt_provider.clone())
-            .ok_or("No provider specified and no default configured")?;
+        if let Some(name) = &request.name {
+            self.check_key_name_unused(name, Some(&self.admin_key))?;
+        }
 
-        // Handle request based on provider
-        let response = match provider {
-            Provider::OpenAI => self.handle_openai_stt(request.clone()).await?,
-        };
+        let new_key = format!("ttstt-admin-{}", Uuid::new_v4());
+        let entry = self
+            .api_keys
+            .iter_mut()
+            .find(|k| k.key == self.admin_key)
+            .ok_or("Admin key entry not found")?;
+        entry.key = new_key.clone();
+        if let Some(name) = request.name {
+            entry.name = name;
+        }
+        let name = entry.name.clone();
+        self.admin_key = new_key;
+        self.audit(&actor, "rotate_admin_key", name).await;
 
-        // Store audio-text pair to VFS
-        let pair = AudioTextPair {
-            id: Uuid::new_v4().to_string(),
-            text: response.text.clone(),
-            audio_data: request.audio_data.clone(),
-            audio_format: "webm".to_string(), // Default for recorded audio
-            provider: response.provider.clone(),
-            timestamp: Utc::now().to_rfc3339(),
-            request_type: RequestType::STT,
-            metadata: vec![],
-        };
+        Ok(GetAdminKeyRes {
+            admin_key: self.admin_key.clone(),
+            message: "Save this key! It will not be shown again.".to_string(),
+        })
+    }
+}
 
-        // Save to VFS
-        if let Err(e) = self.save_audio_text_pair(&pair).await {
-            eprintln!("Failed to save audio-text pair: {}", e);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index_entry(
+        id: &str,
+        timestamp: &str,
+        last_accessed: &str,
+        size_bytes: u64,
+    ) -> PairIndexEntry {
+        PairIndexEntry {
+            id: id.to_string(),
+            timestamp: timestamp.to_string(),
+            size_bytes,
+            last_accessed: last_accessed.to_string(),
+            tags: Vec::new(),
+            content_hash: None,
+            is_test: false,
+            metadata_keys: Vec::new(),
+            provider: Some(Provider::OpenAI),
+            request_type: Some(RequestType::TTS),
         }
-
-        Ok(response)
     }
 
-    #[http]
-    async fn test_tts(&mut self, request: TestTtsReq) -> Result<TtsRes, String> {
-        // Create TTS request with defaults
-        let tts_request = TtsReq {
-            text: request.text,
-            provider: self.default_tts_provider.clone(),
-            voice: Some("nova".to_string()), // Default to nova
-            model: Some("gpt-4o-mini-tts".to_string()), // Default to gpt-4o-mini-tts
-            format: Some("mp3".to_string()),
-            speed: Some(1.5), // Default to 1.5x speed
-            api_key: None,
-        };
+    fn state_with_pairs() -> TtsttState {
+        TtsttState {
+            max_total_bytes: Some(300),
+            pair_index: vec![
+                index_entry("a", "2024-01-01T00:00:00Z", "2024-01-05T00:00:00Z", 100),
+                index_entry("b", "2024-01-02T00:00:00Z", "2024-01-03T00:00:00Z", 100),
+                index_entry("c", "2024-01-03T00:00:00Z", "2024-01-04T00:00:00Z", 100),
+            ],
+            ..Default::default()
+        }
+    }
 
-        // Process request
-        self.tts(tts_request).await
+    #[test]
+    fn evicts_oldest_first_under_fifo() {
+        let state = state_with_pairs();
+        assert!(state.eviction_victims(0, None).is_empty());
+        assert_eq!(state.eviction_victims(150, None), vec!["a", "b"]);
     }
 
-    #[http]
-    async fn test_stt(&mut self, request: TestSttReq) -> Result<SttRes, String> {
-        // Create STT request with defaults
-        let stt_request = SttReq {
-            audio_data: request.audio_data,
-            provider: self.default_stt_provider.clone(),
-            model: None,
-            language: None,
-            api_key: None,
+    #[test]
+    fn evicts_least_recently_accessed_under_lru() {
+        let state = TtsttState {
+            eviction_policy: EvictionPolicy::Lru,
+            ..state_with_pairs()
         };
+        assert_eq!(state.eviction_victims(50, None), vec!["b"]);
+    }
 
-        // Process request
-        self.stt(stt_request).await
+    #[test]
+    fn never_evicts_the_kept_pair() {
+        let state = TtsttState {
+            max_total_bytes: Some(150),
+            ..state_with_pairs()
+        };
+        assert_eq!(state.eviction_victims(0, Some("a")), vec!["b", "c"]);
+        assert!(state.check_fits_storage(151).is_err());
     }
 
-    #[local]
-    #[http]
-    async fn add_provider(&mut self, request: AddProviderReq) -> Result<String, String> {
-        self.validate_api_key(request.api_key, true)?;
+    fn hash_input(bit_depth: Option<u16>) -> ContentHashInput<'static> {
+        ContentHashInput {
+            provider: &Provider::OpenAI,
+            audio_format: "wav",
+            text: "hello world",
+            voice: Some("nova"),
+            model: Some("tts-1"),
+            speed: Some(1.0),
+            profile: Some("default"),
+            sample_rate: Some(24000),
+            bit_depth,
+        }
+    }
 
-        let config = request.config;
+    #[test]
+    fn content_hash_differs_by_bit_depth() {
+        assert_ne!(
+            content_hash(&hash_input(Some(16))),
+            content_hash(&hash_input(Some(24)))
+        );
+        assert_eq!(
+            content_hash(&hash_input(Some(24))),
+            content_hash(&hash_input(Some(24)))
+        );
+    }
 
-        // Remove existing config for this provider
-        self.providers.retain(|p| p.provider != config.provider);
+    // test_tts and test_stt surface these when nothing can serve the request
+    #[test]
+    fn missing_default_provider_names_the_request_type() {
+        let state = TtsttState::default();
+        let tts = state.default_provider(RequestType::TTS).unwrap_err();
+        let stt = state.default_provider(RequestType::STT).unwrap_err();
+        assert!(
+            tts.contains("No default TTS provider configured"),
+            "{}",
+            tts
+        );
+        assert!(
+            stt.contains("No default STT provider configured"),
+            "{}",
+            stt
+        );
+    }
 
-        // Update default providers if needed
-        if config.is_default_tts {
-            self.default_tts_provider = Some(config.provider.clone());
-            // Clear other default TTS
-            for provider in &mut self.providers {
-                provider.is_default_tts = false;
-            }
-        }
+    #[test]
+    fn has_more_turns_false_at_the_last_page() {
+        // 25 pairs in pages of 10
+        assert!(page_has_more(0, 10, 25));
+        assert!(page_has_more(10, 10, 25));
+        assert!(!page_has_more(20, 10, 25));
+        assert!(!page_has_more(30, 10, 25));
+        // A page ending exactly on the last pair has nothing after it
+        assert!(!page_has_more(10, 10, 20));
+        assert!(!page_has_more(usize::MAX, 10, 20));
+    }
 
-        if config.is_default_stt {
-            self.default_stt_provider = Some(config.provider.clone());
-            // Clear other default STT
-            for provider in &mut self.providers {
-                provider.is_default_stt = false;
-            }
-        }
+    // Stands in for a webhook that uppercases whatever it is sent
+    fn uppercasing_webhook(body: Vec<u8>) -> Result<Vec<u8>, String> {
+        let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let text = request["text"].as_str().unwrap().to_uppercase();
+        Ok(serde_json::to_vec(&serde_json::json!({ "text": text })).unwrap())
+    }
 
-        self.providers.push(config);
+    #[test]
+    fn transcript_webhook_replaces_the_text() {
+        assert_eq!(
+            call_transcript_webhook("hello there", Some("en"), uppercasing_webhook).unwrap(),
+            "HELLO THERE"
+        );
+    }
 
-        Ok("Provider added successfully".to_string())
+    #[test]
+    fn transcript_webhook_failures_keep_the_raw_text() {
+        let timed_out = call_transcript_webhook("hello", None, |_| {
+            Err("HTTP request failed: Timeout".to_string())
+        });
+        assert!(timed_out.is_err());
+        let malformed = call_transcript_webhook("hello", None, |_| Ok(b"not json".to_vec()));
+        assert!(malformed.is_err());
+
+        // With no webhook configured the transcript is left alone
+        assert_eq!(
+            TtsttState::default().post_process_transcript("hello", None),
+            None
+        );
     }
 
-    #[local]
-    #[http]
-    async fn remove_provider(&mut self, request: RemoveProviderReq) -> Result<String, String> {
-        self.validate_api_key(request.api_key, true)?;
+    fn stored_metadata(
+        timestamp: &str,
+        text: &str,
+        duration_ms: Option<u64>,
+        provider: Provider,
+    ) -> StoredPairMetadata {
+        StoredPairMetadata {
+            id: timestamp.to_string(),
+            text: text.to_string(),
+            audio_format: "mp3".to_string(),
+            provider,
+            timestamp: timestamp.to_string(),
+            request_type: RequestType::TTS,
+            metadata: Vec::new(),
+            duration_ms,
+            audio_info: None,
+            compression: None,
+            audio_sha256: None,
+            pending: false,
+        }
+    }
 
-        let provider = request.provider;
+    #[test]
+    fn sorts_by_timestamp() {
+        let older = stored_metadata("2024-01-01T00:00:00Z", "b", None, Provider::OpenAI);
+        let newer = stored_metadata("2024-02-01T00:00:00Z", "a", None, Provider::OpenAI);
+        let key = HistorySortKey::parse(None).unwrap();
+        assert_eq!(key.compare(&older, &newer), std::cmp::Ordering::Less);
+    }
 
-        self.providers.retain(|p| p.provider != provider);
+    #[test]
+    fn sorts_by_text_length_in_chars() {
+        // Four chars but more bytes than the five-char text
+        let short = stored_metadata("2024-01-01T00:00:00Z", "ññññ", None, Provider::OpenAI);
+        let long = stored_metadata("2024-01-01T00:00:00Z", "hello", None, Provider::OpenAI);
+        let key = HistorySortKey::parse(Some("text_length")).unwrap();
+        assert_eq!(key.compare(&short, &long), std::cmp::Ordering::Less);
+    }
 
-        // Clear defaults if removed
-        if self.default_tts_provider == Some(provider.clone()) {
-            self.default_tts_provider = None;
-        }
-        if self.default_stt_provider == Some(provider) {
-            self.default_stt_provider = None;
-        }
+    #[test]
+    fn sorts_by_duration_with_unknown_first() {
+        let unknown = stored_metadata("2024-01-01T00:00:00Z", "a", None, Provider::OpenAI);
+        let short = stored_metadata("2024-01-01T00:00:00Z", "a", Some(500), Provider::OpenAI);
+        let long = stored_metadata("2024-01-01T00:00:00Z", "a", Some(9000), Provider::OpenAI);
+        let key = HistorySortKey::parse(Some("duration")).unwrap();
+        assert_eq!(key.compare(&unknown, &short), std::cmp::Ordering::Less);
+        assert_eq!(key.compare(&short, &long), std::cmp::Ordering::Less);
+    }
 
-        Ok("Provider removed successfully".to_string())
+    #[test]
+    fn sorts_by_provider_name() {
+        let azure = stored_metadata("2024-01-01T00:00:00Z", "a", None, Provider::Azure);
+        let openai = stored_metadata("2024-01-01T00:00:00Z", "a", None, Provider::OpenAI);
+        let key = HistorySortKey::parse(Some("provider")).unwrap();
+        assert_eq!(key.compare(&azure, &openai), std::cmp::Ordering::Less);
+        assert!(HistorySortKey::parse(Some("size")).is_err());
     }
 
-    #[local]
-    #[http]
-    async fn get_providers(&self) -> Result<Vec<ProviderInfo>, String> {
-        // Return providers without API keys
-        let safe_providers: Vec<ProviderInfo> = self
-            .providers
-            .iter()
-            .map(|p| ProviderInfo {
-                provider: p.provider.clone(),
-                is_default_tts: p.is_default_tts,
-                is_default_stt: p.is_default_stt,
-                default_voice: p.default_voice.clone(),
-                default_speed: p.default_speed,
-            })
+    #[test]
+    fn counts_overlapping_tags() {
+        let tagged = |id: &str, tags: &[&str]| PairIndexEntry {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..index_entry(id, "2024-01-01T00:00:00Z", "2024-01-01T00:00:00Z", 1)
+        };
+        let state = TtsttState {
+            pair_index: vec![
+                tagged("a", &["news", "english"]),
+                tagged("b", &["news"]),
+                tagged("c", &["english", "news", "demo"]),
+                tagged("d", &[]),
+            ],
+            ..Default::default()
+        };
+
+        let counts: Vec<(String, u32)> = state
+            .tag_counts()
+            .into_iter()
+            .map(|t| (t.tag, t.count))
             .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("news".to_string(), 3),
+                ("english".to_string(), 2),
+                ("demo".to_string(), 1),
+            ]
+        );
+    }
 
-        Ok(safe_providers)
+    #[test]
+    fn usage_counts_match_successful_requests() {
+        let mut state = TtsttState::default();
+        let mut last = 0;
+        for characters in [10, 20, 30] {
+            state.record_usage(&Provider::OpenAI, RequestType::TTS, characters, 0.01);
+            let counter = &state.usage[0];
+            assert!(counter.requests > last);
+            last = counter.requests;
+        }
+        state.record_usage(&Provider::OpenAI, RequestType::STT, 4096, 0.0);
+
+        let tts = state
+            .usage
+            .iter()
+            .find(|c| c.request_type == RequestType::TTS)
+            .unwrap();
+        assert_eq!(tts.requests, 3);
+        assert_eq!(tts.units, 60);
+        assert!((tts.spend_usd - 0.03).abs() < 1e-9);
+        assert_eq!(state.usage.len(), 2);
     }
 
-    #[local]
-    #[http]
-    async fn set_default_provider(
-        &mut self,
-        request: SetDefaultProviderReq,
-    ) -> Result<String, String> {
-        self.validate_api_key(request.api_key, true)?;
+    #[test]
+    fn imports_openai_key_when_no_provider_is_configured() {
+        let mut state = TtsttState::default();
+        assert!(!state.import_openai_key(None));
+        assert!(!state.import_openai_key(Some("  ".to_string())));
+        assert!(state.providers.is_empty());
+
+        assert!(state.import_openai_key(Some("sk-env".to_string())));
+        assert_eq!(state.providers.len(), 1);
+        let config = &state.providers[0];
+        assert_eq!(config.provider, Provider::OpenAI);
+        assert_eq!(config.api_key, "sk-env");
+        assert!(config.is_default_tts && config.is_default_stt);
+        assert_eq!(state.default_tts_provider, Some(Provider::OpenAI));
+        assert_eq!(state.default_stt_provider, Some(Provider::OpenAI));
+
+        // An existing OpenAI config is left alone
+        assert!(!state.import_openai_key(Some("sk-other".to_string())));
+        assert_eq!(state.providers.len(), 1);
+        assert_eq!(state.providers[0].api_key, "sk-env");
+    }
 
-        let provider = request.provider;
-        let provider_type = request.provider_type.as_str();
+    #[test]
+    fn tracks_peak_concurrency_per_provider() {
+        let mut state = TtsttState::default();
+        state.begin_provider_call(&Provider::OpenAI);
+        state.begin_provider_call(&Provider::OpenAI);
+        state.begin_provider_call(&Provider::Azure);
+        state.end_provider_call(&Provider::OpenAI);
+        state.begin_provider_call(&Provider::OpenAI);
+        state.end_provider_call(&Provider::OpenAI);
+        state.end_provider_call(&Provider::OpenAI);
+        state.end_provider_call(&Provider::Azure);
+        // Unmatched ends don't underflow
+        state.end_provider_call(&Provider::Azure);
+
+        assert_eq!(
+            state.peak_concurrent,
+            vec![(Provider::OpenAI, 2), (Provider::Azure, 1)]
+        );
+        assert_eq!(
+            state.in_flight,
+            vec![(Provider::OpenAI, 0), (Provider::Azure, 0)]
+        );
+    }
 
-        // Ensure provider exists
-        let exists = self.providers.iter().any(|p| p.provider == provider);
-        if !exists {
-            return Err("Provider not configured".to_string());
-        }
+    #[test]
+    fn reports_every_failed_provider() {
+        let error: String = TtsttError::AllProvidersFailed(vec![
+            (Provider::OpenAI, "HTTP 503".to_string()),
+            (Provider::Local, "connection refused".to_string()),
+        ])
+        .into();
+        assert_eq!(
+            error,
+            "All providers failed: OpenAI: HTTP 503; Local: connection refused"
+        );
+    }
 
-        match provider_type {
-            "tts" => {
-                // Clear other defaults and set new one
-                for p in &mut self.providers {
-                    p.is_default_tts = p.provider == provider;
-                }
-                self.default_tts_provider = Some(provider);
-            }
-            "stt" => {
-                // Clear other defaults and set new one
-                for p in &mut self.providers {
-                    p.is_default_stt = p.provider == provider;
-                }
-                self.default_stt_provider = Some(provider);
-            }
-            _ => return Err("Invalid type: must be 'tts' or 'stt'".to_string()),
+    #[test]
+    fn unknown_openai_formats_are_reported_as_mp3() {
+        for format in OPENAI_TTS_FORMATS {
+            assert_eq!(openai_tts_format_name(Some(format)), *format);
         }
+        assert_eq!(openai_tts_format_name(Some("ogg")), "mp3");
+        assert_eq!(openai_tts_format_name(Some("MP3")), "mp3");
+        assert_eq!(openai_tts_format_name(None), "mp3");
+    }
 
-        Ok("Default provider set successfully".to_string())
+    #[test]
+    fn openai_format_enum_matches_the_reported_format() {
+        assert!(matches!(
+            openai_tts_format(Some("wav")),
+            ("wav", OpenAIAudioFormat::Wav)
+        ));
+        assert!(matches!(
+            openai_tts_format(Some("opus")),
+            ("opus", OpenAIAudioFormat::Opus)
+        ));
+        assert!(matches!(
+            openai_tts_format(Some("aac")),
+            ("aac", OpenAIAudioFormat::Aac)
+        ));
+        assert!(matches!(
+            openai_tts_format(Some("flac")),
+            ("flac", OpenAIAudioFormat::Flac)
+        ));
+        assert!(matches!(
+            openai_tts_format(Some("pcm")),
+            ("pcm", OpenAIAudioFormat::Pcm)
+        ));
+        assert!(matches!(
+            openai_tts_format(Some("ogg")),
+            ("mp3", OpenAIAudioFormat::Mp3)
+        ));
+        assert!(matches!(
+            openai_tts_format(None),
+            ("mp3", OpenAIAudioFormat::Mp3)
+        ));
     }
 
-    #[local]
-    #[http]
-    async fn generate_api_key(
-        &mut self,
-        request: GenerateApiKeyReq,
-    ) -> Result<GenerateApiKeyRes, String> {
-        self.validate_api_key(request.api_key, true)?;
+    #[test]
+    fn falls_back_to_local_only_when_cloud_stt_is_unhealthy() {
+        let mut state = TtsttState::default();
+        state.import_openai_key(Some("sk-test".to_string()));
+        let azure = ProviderConfig {
+            provider: Provider::Azure,
+            ..state.providers[0].clone()
+        };
+        state.providers.push(azure);
 
-        let name = request.name;
-        let role = request.role;
+        // No local provider configured
+        assert!(!state.should_fall_back_to_local(&Provider::OpenAI, false));
 
-        let new_key = ApiKey {
-            key: format!(
-                "ttstt-{}-{}",
-                if matches!(role, ApiKeyRole::Admin) {
-                    "admin"
-                } else {
-                    "req"
-                },
-                Uuid::new_v4()
-            ),
-            role: role.clone(),
-            created_at: Utc::now().to_rfc3339(),
-            name: name.to_string(),
-        };
+        state.local_stt = Some(LocalSttConfig {
+            url: "http://localhost:8080/stt".to_string(),
+            timeout_secs: None,
+        });
+        // Azure hasn't been checked, so it still counts as healthy
+        assert!(!state.should_fall_back_to_local(&Provider::OpenAI, false));
 
-        let key_value = new_key.key.clone();
-        let name_clone = name.clone();
-        self.api_keys.push(new_key);
+        state.provider_health = vec![(Provider::Azure, DEFAULT_PROFILE_NAME.to_string(), false)];
+        assert!(state.should_fall_back_to_local(&Provider::OpenAI, false));
+        assert!(!state.should_fall_back_to_local(&Provider::OpenAI, true));
+        assert!(!state.should_fall_back_to_local(&Provider::Local, false));
 
-        // Return the newly generated key
-        Ok(GenerateApiKeyRes {
-            key: key_value,
-            name: name_clone,
-            role,
-        })
+        state.provider_health = vec![(Provider::Azure, DEFAULT_PROFILE_NAME.to_string(), true)];
+        assert!(!state.should_fall_back_to_local(&Provider::OpenAI, false));
     }
 
-    #[local]
-    #[http]
-    async fn revoke_api_key(&mut self, request: RevokeApiKeyReq) -> Result<String, String> {
-        self.validate_api_key(request.api_key, true)?;
+    #[test]
+    fn local_transcripts_are_marked_local() {
+        let response = local_stt_response(br#"{"text": "hello from the node"}"#).unwrap();
+        assert_eq!(response.text, "hello from the node");
+        assert_eq!(response.provider, Provider::Local);
+        assert!(local_stt_response(b"not json").is_err());
+    }
 
-        let key_to_revoke = request.key_to_revoke.as_str();
+    // Ready after being polled `polls` more times
+    struct Countdown {
+        polls: u32,
+        value: char,
+    }
 
-        // Don't allow revoking the initial admin key
-        if key_to_revoke == self.admin_key {
-            return Err("Cannot revoke initial admin key".to_string());
+    impl Future for Countdown {
+        type Output = char;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<char> {
+            if self.polls == 0 {
+                return Poll::Ready(self.value);
+            }
+            self.polls -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
         }
+    }
 
-        self.api_keys.retain(|k| k.key != key_to_revoke);
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
 
-        Ok("API key revoked successfully".to_string())
+    #[test]
+    fn join_unordered_returns_outputs_as_they_complete() {
+        let futures = vec![
+            Countdown {
+                polls: 3,
+                value: 'a',
+            },
+            Countdown {
+                polls: 0,
+                value: 'b',
+            },
+            Countdown {
+                polls: 1,
+                value: 'c',
+            },
+        ];
+        assert_eq!(
+            block_on(join_unordered(futures)),
+            vec![(1, 'b'), (2, 'c'), (0, 'a')]
+        );
     }
 
-    #[local]
-    #[http]
-    async fn list_api_keys(&self, request: ListApiKeysReq) -> Result<Vec<ApiKeyInfo>, String> {
-        self.validate_api_key(request.api_key, true)?;
+    #[test]
+    fn raw_audio_content_types() {
+        assert_eq!(audio_mime_type("mp3"), "audio/mpeg");
+        assert_eq!(audio_mime_type("opus"), "audio/ogg");
+        assert_eq!(audio_mime_type("m4a"), "audio/mp4");
+        assert_eq!(audio_mime_type("pcm"), "application/octet-stream");
+    }
 
-        // Return keys without actual key values
-        let safe_keys: Vec<ApiKeyInfo> = self
-            .api_keys
-            .iter()
-            .map(|k| ApiKeyInfo {
-                name: k.name.clone(),
-                role: k.role.clone(),
-                created_at: k.created_at.clone(),
-                key_preview: format!("{}...", &k.key[..20.min(k.key.len())]),
-            })
-            .collect();
+    #[test]
+    fn audio_tokens_are_single_use_and_expire() {
+        let mut state = TtsttState::default();
+        let now = Utc::now();
+
+        let issued = state.issue_audio_token("pair-1", now);
+        assert_eq!(
+            state.redeem_audio_token(&issued.token, now),
+            Ok("pair-1".to_string())
+        );
+        assert!(state.redeem_audio_token(&issued.token, now).is_err());
+
+        let issued = state.issue_audio_token("pair-2", now);
+        let later = now + chrono::Duration::minutes(AUDIO_TOKEN_TTL_MINUTES + 1);
+        assert!(state.redeem_audio_token(&issued.token, later).is_err());
+        assert!(state.audio_tokens.is_empty());
+    }
 
-        Ok(safe_keys)
+    #[test]
+    fn metadata_from_before_pending_reads_as_complete() {
+        let json = serde_json::to_string(&stored_metadata(
+            "2024-01-01T00:00:00Z",
+            "hello",
+            None,
+            Provider::OpenAI,
+        ))
+        .unwrap()
+        .replace(",\"pending\":false", "");
+        assert!(!json.contains("pending"));
+
+        let metadata: StoredPairMetadata = serde_json::from_str(&json).unwrap();
+        assert!(!metadata.pending);
     }
 
-    #[local]
-    #[http]
-    async fn get_history(&self, request: GetHistoryReq) -> Result<Vec<AudioTextPair>, String> {
-        let limit = request.limit.unwrap_or(50) as usize;
-        let offset = request.offset.unwrap_or(0) as usize;
+    fn speech(pair_id: Option<&str>) -> TtsRes {
+        TtsRes {
+            audio_data: String::new(),
+            format: "mp3".to_string(),
+            provider: Provider::OpenAI,
+            audio_info: None,
+            raw: None,
+            pair_id: pair_id.map(str::to_string),
+        }
+    }
 
-        // Load from VFS
-        let pairs = self.load_audio_text_pairs(limit, offset).await?;
+    #[test]
+    fn transcript_links_to_the_pair_tts_stored() {
+        // A content-hash hit reports the earlier pair, which is what the link must name
+        assert_eq!(
+            tts_pair_link(&Ok(speech(Some("earlier-pair")))),
+            Some((
+                TTS_PAIR_METADATA_KEY.to_string(),
+                "earlier-pair".to_string()
+            ))
+        );
+    }
 
-        Ok(pairs)
+    #[test]
+    fn transcript_is_not_linked_when_tts_saves_nothing() {
+        assert_eq!(tts_pair_link(&Err("HTTP 503".to_string())), None);
+        assert_eq!(tts_pair_link(&Ok(speech(None))), None);
     }
 
-    #[local]
-    #[http]
-    async fn get_audio_text_pair(
-        &self,
-        request: GetAudioTextPairReq,
-    ) -> Result<AudioTextPair, String> {
-        // Load from VFS
-        let pair = self.load_audio_text_pair_by_id(&request.id).await?;
+    #[test]
+    fn requested_pair_id_skips_content_hash_reuse() {
+        let state = TtsttState {
+            pair_index: vec![PairIndexEntry {
+                content_hash: Some("abc".to_string()),
+                ..index_entry(
+                    "stored",
+                    "2024-01-01T00:00:00Z",
+                    "2024-01-01T00:00:00Z",
+                    100,
+                )
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            state.identical_pair_id("abc", None),
+            Some("stored".to_string())
+        );
+        assert_eq!(state.identical_pair_id("abc", Some("mine")), None);
+        assert_eq!(state.identical_pair_id("other", None), None);
+    }
 
-        Ok(pair)
+    #[test]
+    fn speed_band_applies_when_no_speed_is_given() {
+        let state = TtsttState {
+            tts_defaults: TtsDefaults {
+                min_speed: Some(1.5),
+                ..Default::default()
+            },
+            strict_speed: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            state.resolve_tts_speed(&Provider::OpenAI, None, None),
+            Ok(1.5)
+        );
+        assert_eq!(
+            state.resolve_tts_speed(&Provider::OpenAI, None, Some(2.0)),
+            Ok(2.0)
+        );
+        assert!(state
+            .resolve_tts_speed(&Provider::OpenAI, Some(1.0), None)
+            .is_err());
     }
 
-    #[http]
-    async fn get_admin_key(&self) -> Result<GetAdminKeyRes, String> {
-        // Only return if no other admin keys exist
-        let admin_count = self
-            .api_keys
-            .iter()
-            .filter(|k| matches!(k.role, ApiKeyRole::Admin))
-            .count();
+    #[test]
+    fn pairs_still_being_written_are_not_trashed_or_evicted() {
+        let mut state = state_with_pairs();
+        state.audio_writes.borrow_mut().insert("a".to_string());
 
-        if admin_count == 1 {
-            Ok(GetAdminKeyRes {
-                admin_key: self.admin_key.clone(),
-                message: "Save this key! It will not be shown again.".to_string(),
-            })
-        } else {
-            Err("Admin key already retrieved".to_string())
-        }
+        assert!(state.move_pair_to_trash("a").is_err());
+        assert_eq!(state.pair_index.len(), 3);
+        assert_eq!(state.eviction_victims(150, None), vec!["b", "c"]);
+
+        state.audio_writes.borrow_mut().remove("a");
+        assert_eq!(state.eviction_victims(150, None), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn key_names_stay_unique_when_renaming() {
+        let key = |key: &str, name: &str| ApiKey {
+            key: key.to_string(),
+            role: ApiKeyRole::Admin,
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+            name: name.to_string(),
+            rate_limit_per_min: None,
+            last_used_at: None,
+        };
+        let state = TtsttState {
+            api_keys: vec![key("admin-key", "admin"), key("other-key", "ops")],
+            ..Default::default()
+        };
+        assert!(state.check_key_name_unused("ops", None).is_err());
+        assert!(state
+            .check_key_name_unused("ops", Some("admin-key"))
+            .is_err());
+        assert!(state
+            .check_key_name_unused("admin", Some("admin-key"))
+            .is_ok());
+        assert!(state.check_key_name_unused("new", None).is_ok());
+    }
+
+    #[test]
+    fn processed_transcript_is_stored_and_returned() {
+        let mut response = SttRes {
+            text: "um hello there".to_string(),
+            provider: Provider::OpenAI,
+            audio_info: None,
+            raw: None,
+            detected_language: None,
+        };
+        let processed = call_transcript_webhook(&response.text, None, uppercasing_webhook).ok();
+        let pair = transcript_pair("pair-1", "", "wav", &mut response, Vec::new(), processed);
+
+        assert_eq!(response.text, "UM HELLO THERE");
+        assert_eq!(pair.text, "UM HELLO THERE");
+        assert_eq!(
+            pair.metadata,
+            vec![("raw_transcript".to_string(), "um hello there".to_string())]
+        );
+
+        // Without a processed transcript the raw text is kept as is
+        let pair = transcript_pair("pair-2", "", "wav", &mut response, Vec::new(), None);
+        assert_eq!(pair.text, "UM HELLO THERE");
+        assert!(pair.metadata.is_empty());
     }
 }
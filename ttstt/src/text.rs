@@ -0,0 +1,471 @@
+// Text preprocessing for TTS: spell out numbers, currency and dates so providers read them consistently
+
+const ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+
+const TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+
+const SCALES: [&str; 7] = [
+    "",
+    "thousand",
+    "million",
+    "billion",
+    "trillion",
+    "quadrillion",
+    "quintillion",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// (symbol, major singular, major plural, minor singular, minor plural)
+const CURRENCIES: [(char, &str, &str, &str, &str); 4] = [
+    ('$', "dollar", "dollars", "cent", "cents"),
+    ('€', "euro", "euros", "cent", "cents"),
+    ('£', "pound", "pounds", "penny", "pence"),
+    ('¥', "yen", "yen", "sen", "sen"),
+];
+
+// Full normalization pass behind TtsReq::normalize: typographic punctuation and
+// ampersands become plain text, numbers are expanded, and whitespace runs collapse.
+// Like expand_numbers, only English locales change the words.
+pub fn normalize(text: &str, locale: &str) -> String {
+    let english = locale.to_ascii_lowercase().starts_with("en");
+    let plain: String = text
+        .chars()
+        .map(|c| match c {
+            '\u{2018}' | '\u{2019}' => '\'',
+            '\u{201c}' | '\u{201d}' => '"',
+            '\u{2013}' | '\u{2014}' => '-',
+            '\u{00a0}' => ' ',
+            c => c,
+        })
+        .collect();
+    let plain = if english {
+        plain.replace(" & ", " and ")
+    } else {
+        plain
+    };
+
+    expand_numbers(&plain, locale)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Only English is supported; other locales return the text unchanged.
+// `locale` decides date order: en-US reads 3/4/2024 as March 4, other English locales as 3 April.
+pub fn expand_numbers(text: &str, locale: &str) -> String {
+    let locale = locale.to_ascii_lowercase();
+    if !locale.starts_with("en") {
+        return text.to_string();
+    }
+    let month_first = locale == "en" || locale == "en-us" || locale == "en_us";
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        // Don't split identifiers like "mp3" or "v2"
+        let after_word = i > 0 && (chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+
+        if !after_word {
+            if let Some(currency) = CURRENCIES.iter().find(|cur| cur.0 == c) {
+                if let Some((words, len)) = expand_currency(&chars[i + 1..], currency) {
+                    out.push_str(&words);
+                    i += 1 + len;
+                    continue;
+                }
+            }
+
+            if c.is_ascii_digit() {
+                if let Some((words, len)) = expand_date(&chars[i..], month_first) {
+                    out.push_str(&words);
+                    i += len;
+                    continue;
+                }
+
+                // Hyphen-joined digit runs that aren't dates, like phone numbers "555-1234",
+                // are kept as written rather than read as one number
+                if let Some(len) = hyphenated_digits(&chars[i..]) {
+                    out.extend(&chars[i..i + len]);
+                    i += len;
+                    continue;
+                }
+
+                let negative = out.ends_with('-')
+                    && out[..out.len() - 1]
+                        .chars()
+                        .next_back()
+                        .is_none_or(char::is_whitespace);
+                if let Some((number, len)) = parse_number(&chars[i..]) {
+                    if negative {
+                        out.pop();
+                        out.push_str("minus ");
+                    }
+                    if let Some(suffix_len) = ordinal_suffix(&chars[i + len..], &number) {
+                        out.push_str(&ordinal(&number_to_words(number.whole)));
+                        i += len + suffix_len;
+                        continue;
+                    }
+                    out.push_str(&number.to_words());
+                    i += len;
+                    if chars.get(i) == Some(&'%') {
+                        out.push_str(" percent");
+                        i += 1;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        out.push(c);
+        i += 1;
+    }
+
+    out
+}
+
+struct ParsedNumber {
+    whole: u64,
+    fraction: String, // Digits after the decimal point, read one by one
+}
+
+impl ParsedNumber {
+    fn to_words(&self) -> String {
+        let mut words = number_to_words(self.whole);
+        if !self.fraction.is_empty() {
+            words.push_str(" point");
+            for digit in self.fraction.chars() {
+                words.push(' ');
+                words.push_str(ONES[digit.to_digit(10).unwrap_or(0) as usize]);
+            }
+        }
+        words
+    }
+}
+
+// Digits with optional thousands separators ("1,234") and decimal part ("3.14").
+// Returns the number and the chars consumed; trailing punctuation is left in place.
+fn parse_number(chars: &[char]) -> Option<(ParsedNumber, usize)> {
+    let mut digits = String::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_ascii_digit() {
+            digits.push(c);
+            pos += 1;
+        } else if c == ','
+            && !digits.is_empty()
+            && chars.get(pos + 1..pos + 4).is_some_and(|group| {
+                group.iter().all(char::is_ascii_digit)
+                    && !chars.get(pos + 4).is_some_and(char::is_ascii_digit)
+            })
+        {
+            pos += 1; // Thousands separator
+        } else {
+            break;
+        }
+    }
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut fraction = String::new();
+    if chars.get(pos) == Some(&'.') && chars.get(pos + 1).is_some_and(char::is_ascii_digit) {
+        pos += 1;
+        while let Some(c) = chars.get(pos).filter(|c| c.is_ascii_digit()) {
+            fraction.push(*c);
+            pos += 1;
+        }
+    }
+
+    // Too large to spell out sensibly; leave it for the provider
+    let whole = digits.parse().ok()?;
+    Some((ParsedNumber { whole, fraction }, pos))
+}
+
+// Length of a run like "555-1234": digit groups joined by single hyphens
+fn hyphenated_digits(chars: &[char]) -> Option<usize> {
+    let mut pos = chars.iter().take_while(|c| c.is_ascii_digit()).count();
+    let mut groups = 1;
+    while chars.get(pos) == Some(&'-') && chars.get(pos + 1).is_some_and(char::is_ascii_digit) {
+        pos += 1;
+        pos += chars[pos..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        groups += 1;
+    }
+    (groups > 1).then_some(pos)
+}
+
+// Length of an ordinal suffix ("st", "nd", "rd", "th") ending a whole number, as in "21st"
+fn ordinal_suffix(chars: &[char], number: &ParsedNumber) -> Option<usize> {
+    if !number.fraction.is_empty() {
+        return None;
+    }
+    let suffix: String = chars
+        .iter()
+        .take(2)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let ends_word = !chars.get(2).is_some_and(|c| c.is_alphanumeric());
+    (matches!(suffix.as_str(), "st" | "nd" | "rd" | "th") && ends_word).then_some(2)
+}
+
+fn expand_currency(
+    chars: &[char],
+    currency: &(char, &str, &str, &str, &str),
+) -> Option<(String, usize)> {
+    let (_, major, majors, minor, minors) = *currency;
+    let (number, len) = parse_number(chars)?;
+
+    // Amounts with other than two decimals are read as a plain number of the major unit
+    if !number.fraction.is_empty() && number.fraction.len() != 2 {
+        let words = format!("{} {}", number.to_words(), majors);
+        return Some((words, len));
+    }
+
+    let cents: u64 = number.fraction.parse().unwrap_or(0);
+    let major_words = format!(
+        "{} {}",
+        number_to_words(number.whole),
+        if number.whole == 1 { major } else { majors }
+    );
+    let minor_words = format!(
+        "{} {}",
+        number_to_words(cents),
+        if cents == 1 { minor } else { minors }
+    );
+
+    let words = match (number.whole, cents) {
+        (_, 0) => major_words,
+        (0, _) => minor_words,
+        _ => format!("{} and {}", major_words, minor_words),
+    };
+    Some((words, len))
+}
+
+// Numeric dates like 3/4/2024 or 2024-03-04
+fn expand_date(chars: &[char], month_first: bool) -> Option<(String, usize)> {
+    let take_digits = |start: usize, max: usize| -> Option<(u32, usize)> {
+        let len = chars[start..]
+            .iter()
+            .take(max + 1)
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        if len == 0 || len > max {
+            return None;
+        }
+        let value = chars[start..start + len]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()?;
+        Some((value, start + len))
+    };
+
+    let (first, pos) = take_digits(0, 4)?;
+    let sep = *chars.get(pos)?;
+    if sep != '/' && sep != '-' {
+        return None;
+    }
+    let (second, pos) = take_digits(pos + 1, 2)?;
+    if chars.get(pos) != Some(&sep) {
+        return None;
+    }
+    let (third, end) = take_digits(pos + 1, 4)?;
+    if chars.get(end).is_some_and(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (year, month, day) = if sep == '-' && pos == 7 && first >= 1000 {
+        // ISO 8601
+        (first, second, third)
+    } else if sep == '/' && pos <= 5 && end - pos - 1 == 4 {
+        if month_first {
+            (third, first, second)
+        } else {
+            (third, second, first)
+        }
+    } else {
+        return None;
+    };
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let month_name = MONTHS[month as usize - 1];
+    let day_words = ordinal(&number_to_words(day as u64));
+    let year_words = year_to_words(year);
+    let words = if month_first {
+        format!("{} {}, {}", month_name, day_words, year_words)
+    } else {
+        format!("the {} of {}, {}", day_words, month_name, year_words)
+    };
+    Some((words, end))
+}
+
+// Years are read in pairs ("nineteen ninety-nine", "twenty twenty-four")
+fn year_to_words(year: u32) -> String {
+    let (hi, lo) = (year / 100, year % 100);
+    if !(10..100).contains(&hi) || (year % 1000 < 10 && year >= 2000) {
+        return number_to_words(year as u64);
+    }
+    match lo {
+        0 => format!("{} hundred", number_to_words(hi as u64)),
+        1..=9 => format!("{} oh {}", number_to_words(hi as u64), ONES[lo as usize]),
+        _ => format!(
+            "{} {}",
+            number_to_words(hi as u64),
+            number_to_words(lo as u64)
+        ),
+    }
+}
+
+pub fn number_to_words(n: u64) -> String {
+    if n == 0 {
+        return ONES[0].to_string();
+    }
+
+    let mut groups = Vec::new();
+    let mut rest = n;
+    let mut scale = 0;
+    while rest > 0 {
+        let group = rest % 1000;
+        if group > 0 {
+            let words = below_thousand(group);
+            groups.push(if SCALES[scale].is_empty() {
+                words
+            } else {
+                format!("{} {}", words, SCALES[scale])
+            });
+        }
+        rest /= 1000;
+        scale += 1;
+    }
+
+    groups.reverse();
+    groups.join(" ")
+}
+
+fn below_thousand(n: u64) -> String {
+    let (hundreds, rest) = (n / 100, n % 100);
+    let rest_words = match rest {
+        0 => String::new(),
+        1..=19 => ONES[rest as usize].to_string(),
+        _ if rest % 10 == 0 => TENS[(rest / 10) as usize].to_string(),
+        _ => format!(
+            "{}-{}",
+            TENS[(rest / 10) as usize],
+            ONES[(rest % 10) as usize]
+        ),
+    };
+
+    match (hundreds, rest_words.is_empty()) {
+        (0, _) => rest_words,
+        (_, true) => format!("{} hundred", ONES[hundreds as usize]),
+        (_, false) => format!("{} hundred {}", ONES[hundreds as usize], rest_words),
+    }
+}
+
+// "twenty-one" -> "twenty-first"
+fn ordinal(words: &str) -> String {
+    let split = words.rfind(['-', ' ']).map(|i| i + 1).unwrap_or(0);
+    let (head, last) = words.split_at(split);
+    let last = match last {
+        "one" => "first".to_string(),
+        "two" => "second".to_string(),
+        "three" => "third".to_string(),
+        "five" => "fifth".to_string(),
+        "eight" => "eighth".to_string(),
+        "nine" => "ninth".to_string(),
+        "twelve" => "twelfth".to_string(),
+        w if w.ends_with('y') => format!("{}ieth", &w[..w.len() - 1]),
+        w => format!("{}th", w),
+    };
+    format!("{}{}", head, last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_us_currency() {
+        assert_eq!(
+            expand_numbers("$1,234.56", "en-US"),
+            "one thousand two hundred thirty-four dollars and fifty-six cents"
+        );
+        assert_eq!(expand_numbers("$0.01", "en-US"), "one cent");
+    }
+
+    #[test]
+    fn expands_dates_by_locale() {
+        assert_eq!(
+            expand_numbers("3/4/2024", "en-US"),
+            "March fourth, twenty twenty-four"
+        );
+        assert_eq!(
+            expand_numbers("3/4/2024", "en-GB"),
+            "the third of April, twenty twenty-four"
+        );
+        assert_eq!(
+            expand_numbers("2024-03-04", "en-US"),
+            "March fourth, twenty twenty-four"
+        );
+    }
+
+    #[test]
+    fn expands_ordinals() {
+        assert_eq!(expand_numbers("21st", "en-US"), "twenty-first");
+        assert_eq!(expand_numbers("2nd", "en-US"), "second");
+        assert_eq!(expand_numbers("3rd", "en-US"), "third");
+        assert_eq!(
+            expand_numbers("the 11th floor", "en-US"),
+            "the eleventh floor"
+        );
+    }
+
+    #[test]
+    fn keeps_hyphenated_digit_runs() {
+        assert_eq!(expand_numbers("call 555-1234", "en-US"), "call 555-1234");
+    }
+}
@@ -9,6 +9,8 @@ function TestPage() {
     isRecording,
     audioUrl,
     transcribedText,
+    testTtsProvider,
+    testSttProvider,
     isLoading,
     error,
   } = useTtsttStore();
@@ -53,7 +55,9 @@ function TestPage() {
 
         {audioUrl && (
           <div className="mt-4">
-            <h3 className="mb-2">Generated Audio:</h3>
+            <h3 className="mb-2">
+              Generated Audio{testTtsProvider && ` (${testTtsProvider === 'OpenAi' ? 'OpenAI' : testTtsProvider})`}:
+            </h3>
             <audio controls src={audioUrl} style={{ width: '100%' }} />
           </div>
         )}
@@ -87,7 +91,9 @@ function TestPage() {
 
         {transcribedText && (
           <div className="card" style={{ background: 'var(--background)', marginTop: '1rem' }}>
-            <h3 className="mb-2">Transcribed Text:</h3>
+            <h3 className="mb-2">
+              Transcribed Text{testSttProvider && ` (${testSttProvider === 'OpenAi' ? 'OpenAI' : testSttProvider})`}:
+            </h3>
             <p style={{ fontSize: '1.1rem', lineHeight: '1.6' }}>{transcribedText}</p>
           </div>
         )}
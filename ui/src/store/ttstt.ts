@@ -55,6 +55,8 @@ interface TtsttStore {
   isRecording: boolean;
   audioUrl: string | null;
   transcribedText: string | null;
+  testTtsProvider: ApiProvider | null; // Backend that served the last test
+  testSttProvider: ApiProvider | null;
   adminKey: string | null;
   isLoading: boolean;
   error: string | null;
@@ -96,6 +98,8 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
   isRecording: false,
   audioUrl: null,
   transcribedText: null,
+  testTtsProvider: null,
+  testSttProvider: null,
   adminKey: null,
   isLoading: false,
   error: null,
@@ -143,11 +147,20 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
         api_key: adminKey,
         config: {
           provider: config.provider === 'OpenAI' ? 'OpenAi' as ApiProvider : config.provider as ApiProvider,
+          profile_name: 'default',
           api_key: config.apiKey || '',
           is_default_tts: config.isDefaultTts,
           is_default_stt: config.isDefaultStt,
           default_voice: null,
           default_speed: null,
+          user_id: null,
+          region: null,
+          base_url: null,
+          aws_credentials: null,
+          default_tts_model: null,
+          default_stt_model: null,
+          response_audio_field: null,
+          response_text_field: null,
         },
       });
       
@@ -169,6 +182,7 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
       await api.removeProvider({
         api_key: adminKey,
         provider: provider === 'OpenAI' ? 'OpenAi' as ApiProvider : provider as ApiProvider,
+        profile_name: null,
       });
       
       await get().loadProviders();
@@ -189,6 +203,7 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
         api_key: adminKey,
         provider: provider === 'OpenAI' ? 'OpenAi' as ApiProvider : provider as ApiProvider,
         provider_type: type,
+        profile_name: null,
       });
       
       await get().loadProviders();
@@ -225,6 +240,7 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
         api_key: adminKey,
         name,
         role,
+        rate_limit_per_min: null,
       });
       
       await get().loadApiKeys();
@@ -259,7 +275,7 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
   // TTS testing
   testTts: async (text: string) => {
     try {
-      set({ isLoading: true, error: null, audioUrl: null });
+      set({ isLoading: true, error: null, audioUrl: null, testTtsProvider: null });
       
       const response = await api.testTts({ text });
       
@@ -273,7 +289,7 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
       const blob = new Blob([audioArray], { type: `audio/${response.format}` });
       const url = URL.createObjectURL(blob);
       
-      set({ audioUrl: url });
+      set({ audioUrl: url, testTtsProvider: response.provider });
     } catch (error: any) {
       // Extract the actual error message from the API response
       const errorMessage = error?.details || error?.message || error;
@@ -288,7 +304,13 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
     try {
       const recorder = new AudioRecorder();
       await recorder.startRecording();
-      set({ isRecording: true, recorder, transcribedText: null, error: null });
+      set({
+        isRecording: true,
+        recorder,
+        transcribedText: null,
+        testSttProvider: null,
+        error: null,
+      });
     } catch (error) {
       set({ error: `Failed to start recording: ${error}` });
     }
@@ -318,7 +340,11 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
             audio_data: base64Data,
           });
           
-          set({ transcribedText: response.text, isRecording: false });
+          set({
+            transcribedText: response.text,
+            testSttProvider: response.provider,
+            isRecording: false,
+          });
         } catch (error: any) {
           // Extract the actual error message from the API response
           const errorMessage = error?.details || error?.message || error;
@@ -340,7 +366,19 @@ const useTtsttStore = create<TtsttStore>((set, get) => ({
     try {
       set({ isLoading: true, error: null });
       
-      const history = await api.getHistory({ limit, offset });
+      const history = await api.getHistory({
+        limit,
+        offset,
+        include_audio: false,
+        sort_by: null,
+        order: null,
+        cursor: null,
+        sort: null,
+        exclude_test: true,
+        min_duration_ms: null,
+        max_duration_ms: null,
+        include_unknown_duration: null,
+      });
       set({ history });
     } catch (error) {
       set({ error: `Failed to load history: ${error}` });
@@ -1,6 +1,6 @@
 // TTSTT Type Definitions
 
-export type Provider = 'OpenAI'; // Future: | 'ElevenLabs' | 'PlayAI' | 'Groq'
+export type Provider = 'OpenAI' | 'PlayAI' | 'Azure'; // Future: | 'ElevenLabs' | 'Groq'
 
 export interface ProviderConfig {
   provider: Provider;